@@ -0,0 +1,137 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use crate::models::UserId;
+
+/// the ids a user may block, not to grow without bound
+pub const MAX_BLOCKED: usize = 1024;
+
+/// how long the list of a user of a stable id, a bot, is kept once it disconnects
+pub const KEEP_BLOCKS_FOR: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Default)]
+struct BlockList {
+    ids: HashSet<UserId>,
+    /// while the user is away, forgotten then
+    kept_until: Option<Instant>,
+}
+
+impl BlockList {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.kept_until.is_some_and(|until| until <= now)
+    }
+}
+
+/// whom the users of this node blocked. The list of a browser is forgotten once it
+/// disconnects, as its next connection is a new user with a new id; the list of a bot,
+/// the same id every connection, is kept for `KEEP_BLOCKS_FOR` until it reconnects
+#[derive(Debug, Default)]
+pub struct Blocks(RwLock<HashMap<UserId, BlockList>>);
+
+impl Blocks {
+    /// `false` if `blocker` blocked `MAX_BLOCKED` ids already
+    pub fn block(&self, blocker: &str, blocked: UserId) -> bool {
+        let mut blocks = self.0.write().unwrap_or_else(|e| e.into_inner());
+        let list = &mut blocks.entry(blocker.to_string()).or_default().ids;
+        if list.len() >= MAX_BLOCKED && !list.contains(&blocked) {
+            return false;
+        }
+        list.insert(blocked);
+        true
+    }
+
+    pub fn unblock(&self, blocker: &str, blocked: &str) {
+        let mut blocks = self.0.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(list) = blocks.get_mut(blocker) {
+            list.ids.remove(blocked);
+            if list.ids.is_empty() {
+                blocks.remove(blocker);
+            }
+        }
+    }
+
+    /// whether `receiver` blocked `sender`
+    pub fn is_blocked(&self, receiver: &str, sender: &str) -> bool {
+        let blocks = self.0.read().unwrap_or_else(|e| e.into_inner());
+        blocks
+            .get(receiver)
+            .is_some_and(|list| list.ids.contains(sender))
+    }
+
+    /// a user joined, the list kept of its id is its own again unless expired
+    pub fn connected(&self, blocker: &str, now: Instant) {
+        let mut blocks = self.0.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(list) = blocks.get_mut(blocker) {
+            if list.is_expired(now) {
+                blocks.remove(blocker);
+            } else {
+                list.kept_until = None;
+            }
+        }
+    }
+
+    /// a user left: its list is kept for `KEEP_BLOCKS_FOR` if its id is `stable`,
+    /// else dropped. The lists expired meanwhile are dropped too
+    pub fn disconnected(&self, blocker: &str, stable: bool, now: Instant) {
+        let mut blocks = self.0.write().unwrap_or_else(|e| e.into_inner());
+        blocks.retain(|_, list| !list.is_expired(now));
+        if !stable {
+            blocks.remove(blocker);
+        } else if let Some(list) = blocks.get_mut(blocker) {
+            list.kept_until = Some(now + KEEP_BLOCKS_FOR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_blocks {
+    use super::*;
+
+    #[test]
+    fn block_and_unblock() {
+        let blocks = Blocks::default();
+        assert!(blocks.block("a", "b".to_string()));
+        assert!(blocks.is_blocked("a", "b"));
+        // one way only
+        assert!(!blocks.is_blocked("b", "a"));
+
+        blocks.unblock("a", "b");
+        assert!(!blocks.is_blocked("a", "b"));
+
+        blocks.block("a", "c".to_string());
+        blocks.disconnected("a", false, Instant::now());
+        assert!(!blocks.is_blocked("a", "c"));
+    }
+
+    #[test]
+    fn keep_the_list_of_a_stable_id() {
+        let now = Instant::now();
+        let blocks = Blocks::default();
+        blocks.block("bot-a", "b".to_string());
+
+        blocks.disconnected("bot-a", true, now);
+        blocks.connected("bot-a", now + Duration::from_secs(60));
+        assert!(blocks.is_blocked("bot-a", "b"));
+        // online again, never expires
+        blocks.disconnected("c", false, now + KEEP_BLOCKS_FOR * 2);
+        assert!(blocks.is_blocked("bot-a", "b"));
+
+        blocks.disconnected("bot-a", true, now);
+        blocks.connected("bot-a", now + KEEP_BLOCKS_FOR);
+        assert!(!blocks.is_blocked("bot-a", "b"));
+    }
+
+    #[test]
+    fn cap_the_list() {
+        let blocks = Blocks::default();
+        for n in 0..MAX_BLOCKED {
+            assert!(blocks.block("a", n.to_string()));
+        }
+        assert!(!blocks.block("a", "one more".to_string()));
+        // blocking again is not more
+        assert!(blocks.block("a", "0".to_string()));
+    }
+}
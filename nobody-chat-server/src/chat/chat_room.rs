@@ -1,33 +1,258 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use super::{NewMsg, UserRef};
-use crate::{models::UserId, signal::SignalInfo};
+use super::{NewMsg, NewNotice, Relay, RemoteUser, User, UserOptions, UserRef};
+use crate::{
+    cluster::{self, ClusterBody, ClusterMsg, ClusterOptions, NodeId},
+    handle::{ChatEvent, ChatHandle},
+    health::Status,
+    metrics::{Metrics, SignalOutcome},
+    models::UserId,
+    signal::{CallRecorder, CallTracker, SignalInfo, SignalLimiter, SignalPolicy, SignalType},
+    webhook::WebhooksRef,
+};
+use futures_util::future::join_all;
 use kameo::{
-    actor::{ActorRef, PubSub, Publish, Subscribe},
+    actor::{ActorRef, PubSub, Publish, Subscribe, WeakActorRef},
+    error::{ActorStopReason, BoxError},
+    mailbox::unbounded::UnboundedMailbox,
     message::Message,
     request::MessageSend,
-    Actor,
+    Actor, Reply,
 };
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
 
-#[derive(Actor)]
+/// joins and drops the users, checks the signals, and fans out the presence,
+/// the messages between users go by the `Relay` instead
 pub struct ChatRoom {
-    /// new connection user, have no registered
-    activity_users: HashMap<String, UserRef>,
+    /// the users are written only here, read by everyone
+    relay: Arc<Relay>,
     online_pubsub: ActorRef<PubSub<UserOnline>>,
     offline_pubsub: ActorRef<PubSub<UserDisconnection>>,
     new_name_pubsub: ActorRef<PubSub<SetName>>,
+    signal_policy: SignalPolicy,
+    signal_limiter: SignalLimiter,
+    call_tracker: Option<CallTracker>,
+    /// the incarnation of the other nodes, and when they were heard last
+    nodes: HashMap<NodeId, (String, Instant)>,
+    /// no more users once shutting down
+    shutting_down: bool,
+}
+
+impl Actor for ChatRoom {
+    type Mailbox = UnboundedMailbox<Self>;
+
+    async fn on_stop(
+        self,
+        _actor_ref: WeakActorRef<Self>,
+        _reason: ActorStopReason,
+    ) -> Result<(), BoxError> {
+        self.online_pubsub.kill();
+        self.offline_pubsub.kill();
+        self.new_name_pubsub.kill();
+        if let Some(webhooks) = self.relay.webhooks() {
+            webhooks.actor.kill();
+        }
+
+        Ok(())
+    }
 }
 
 impl ChatRoom {
-    pub fn new() -> ActorRef<Self> {
+    pub fn spawn(
+        signal_policy: SignalPolicy,
+        call_recorder: Option<Arc<dyn CallRecorder>>,
+        events: broadcast::Sender<ChatEvent>,
+        webhooks: Option<WebhooksRef>,
+        cluster: Option<ClusterOptions>,
+        users: UserOptions,
+    ) -> ChatHandle {
+        let mut online_pubsub = PubSub::new();
+        let mut offline_pubsub = PubSub::new();
+        let mut new_name_pubsub = PubSub::new();
+        if let Some(webhooks) = &webhooks {
+            online_pubsub.subscribe(webhooks.actor.clone());
+            offline_pubsub.subscribe(webhooks.actor.clone());
+            new_name_pubsub.subscribe(webhooks.actor.clone());
+        }
+
+        let (cluster, start_cluster) = match cluster.map(cluster::link) {
+            Some((link, start)) => (Some(link), Some(start)),
+            None => (None, None),
+        };
+        let relay = Arc::new(Relay::new(events, webhooks, cluster, users));
+
         let chat_room = kameo::spawn(ChatRoom {
-            activity_users: HashMap::default(),
-            online_pubsub: kameo::spawn(PubSub::new()),
-            offline_pubsub: kameo::spawn(PubSub::new()),
-            new_name_pubsub: kameo::spawn(PubSub::new()),
+            relay: relay.clone(),
+            call_tracker: call_recorder.map(CallTracker::new),
+            signal_limiter: SignalLimiter::new(signal_policy.limits.clone()),
+            signal_policy,
+            online_pubsub: kameo::spawn(online_pubsub),
+            offline_pubsub: kameo::spawn(offline_pubsub),
+            new_name_pubsub: kameo::spawn(new_name_pubsub),
+            nodes: HashMap::default(),
+            shutting_down: false,
         });
+        if let Some(start) = start_cluster {
+            start(chat_room.clone(), relay.metrics().clone());
+        }
+
+        ChatHandle::new(chat_room, relay)
+    }
+
+    /// tell every user the server is going away, give them `timeout` to flush
+    /// the messages in flight, then stop every actor
+    pub async fn shutdown(chat_room: &ActorRef<Self>, metrics: &Metrics, timeout: Duration) {
+        metrics.chat_room_sent();
+        let users = chat_room.ask(Shutdown).send().await.unwrap_or_default();
+        info!("shutting down, {} users connected", users.len());
+
+        let stopped = join_all(users.iter().map(|user| user.wait_for_stop()));
+        if tokio::time::timeout(timeout, stopped).await.is_err() {
+            warn!("users not stopped in {:?}, killing them", timeout);
+            for user in &users {
+                user.kill();
+            }
+        }
+
+        let _ = chat_room.stop_gracefully().await;
+        chat_room.wait_for_stop().await;
+    }
+
+    /// whether the chat room and its pubsubs answer by `deadline`
+    pub async fn probe(
+        chat_room: &ActorRef<Self>,
+        metrics: &Metrics,
+        deadline: Duration,
+    ) -> BTreeMap<&'static str, Status> {
+        let until = tokio::time::Instant::now() + deadline;
+
+        metrics.chat_room_sent();
+        let probed = match tokio::time::timeout_at(until, chat_room.ask(Probe).send()).await {
+            Ok(Ok(probed)) => probed,
+            failed => {
+                let chat_room = match failed {
+                    Err(_) => Status::Timeout,
+                    Ok(_) => Status::Stopped,
+                };
+                return BTreeMap::from([
+                    ("chat_room", chat_room),
+                    ("online_pubsub", Status::Unknown),
+                    ("offline_pubsub", Status::Unknown),
+                    ("new_name_pubsub", Status::Unknown),
+                ]);
+            }
+        };
+
+        let (online, offline, new_name) = tokio::join!(
+            answered(until, probed.online.ask(Probe).send()),
+            answered(until, probed.offline.ask(Probe).send()),
+            answered(until, probed.new_name.ask(Probe).send()),
+        );
+        let chat_room = if probed.shutting_down {
+            Status::ShuttingDown
+        } else {
+            Status::Ok
+        };
+
+        BTreeMap::from([
+            ("chat_room", chat_room),
+            ("online_pubsub", online),
+            ("offline_pubsub", offline),
+            ("new_name_pubsub", new_name),
+        ])
+    }
+
+    /// the pubsubs fan out the presence in their own mailboxes, never waited for
+    async fn fan_out<M>(pubsub: &ActorRef<PubSub<M>>, msg: M)
+    where
+        M: Clone + Send + 'static,
+    {
+        if let Err(e) = pubsub.tell(Publish(msg)).send().await {
+            error!("publishing presence failed: {}", e);
+        }
+    }
+
+    /// forget a disconnected or dead user, and tell everyone it is offline
+    async fn drop_user(&mut self, id: &UserId) {
+        let Some(user) = self.relay.directory.remove(id) else {
+            // already dropped
+            return;
+        };
+        self.signal_limiter.forget(id);
+        // a bot is the same id every connection
+        self.relay.blocks.disconnected(id, user.bot, Instant::now());
+        if let Some(tracker) = &mut self.call_tracker {
+            tracker.forget(id, Instant::now());
+        }
+
+        Self::fan_out(&self.offline_pubsub, UserDisconnection(id.clone())).await;
+        self.relay.publish(ClusterBody::Offline { id: id.clone() });
+        self.relay.emit(ChatEvent::Offline { id: id.clone() });
+    }
+
+    /// a signal of a blocked sender is dropped, a call request is denied the same as
+    /// by the callee, so the caller stops ringing and never learns it is blocked.
+    /// The deny passes the limiter as one of the UI would, starting the cooldown
+    async fn refuse_blocked(&mut self, signal: SignalInfo) {
+        let metrics = self.relay.metrics().clone();
+        if !signal.signal_type.is_call_request() {
+            metrics.signal(signal.signal_type, SignalOutcome::Dropped);
+            return;
+        }
+        metrics.signal(signal.signal_type, SignalOutcome::Denied);
+
+        let caller = signal.from_id;
+        let deny = SignalInfo {
+            from_id: signal.to_id,
+            to_id: caller.clone(),
+            signal_type: SignalType::Deny,
+            // what the UI sends
+            value: String::new().into(),
+            call: None,
+        };
+        let _ = self.signal_limiter.check(&deny, Instant::now());
+        match self.relay.directory.get(&caller) {
+            Some(user) => {
+                if user.tell(ForwordSignal(deny)).send().await.is_err() {
+                    self.drop_user(&caller).await;
+                }
+            }
+            None => self.relay.publish(ClusterBody::Signal(deny)),
+        }
+    }
+
+    /// tell every local user what happened on another node,
+    /// not by the pubsubs, so the webhooks only see the users of this node
+    async fn tell_users<M>(&mut self, msg: M)
+    where
+        User: Message<M>,
+        M: Clone + Send + 'static,
+    {
+        let mut dead = vec![];
+        for user in self.relay.directory.local_users() {
+            if user.actor_ref.tell(msg.clone()).send().await.is_err() {
+                dead.push(user.id);
+            }
+        }
 
-        chat_room
+        for id in dead {
+            warn!("user id: {} is dead", id);
+            self.drop_user(&id).await;
+        }
+    }
+
+    /// the users of a node gone are offline
+    async fn drop_node(&mut self, node: &NodeId) {
+        self.nodes.remove(node);
+
+        for id in self.relay.directory.remove_node(node) {
+            self.tell_users(UserDisconnection(id)).await;
+        }
     }
 }
 
@@ -42,33 +267,52 @@ impl NewUserConnection {
     }
 }
 
+/// replies whether the user joined
 impl Message<NewUserConnection> for ChatRoom {
-    type Reply = ();
+    type Reply = bool;
 
     async fn handle(
         &mut self,
         msg: NewUserConnection,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        if self.activity_users.get(&msg.id).is_some() {
-            return;
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("new_user_connection");
+        if self.relay.directory.contains(&msg.id) {
+            warn!("user id: {} is already connected", msg.id);
+            return false;
+        }
+        if self.shutting_down {
+            let _ = msg.user.actor_ref.tell(GoingAway).send().await;
+            return false;
         }
 
-        macro_rules! user_subscribe {
-            ($user_actor_ref: expr) => {
-                $user_actor_ref
-                    .ask(Subscribe(msg.user.actor_ref.clone()))
-                    .send()
-                    .await
-                    .unwrap();
-            };
+        let actor_ref = msg.user.actor_ref.clone();
+        if !self.relay.directory.insert(msg.user) {
+            warn!("user id: {} is already connected", msg.id);
+            return false;
         }
+        self.relay.blocks.connected(&msg.id, Instant::now());
 
-        user_subscribe!(self.online_pubsub);
-        user_subscribe!(self.offline_pubsub);
-        user_subscribe!(self.new_name_pubsub);
+        // queued before anything is published, so never a round trip
+        let subscribed = async {
+            self.online_pubsub
+                .tell(Subscribe(actor_ref.clone()))
+                .send()
+                .await?;
+            self.offline_pubsub
+                .tell(Subscribe(actor_ref.clone()))
+                .send()
+                .await?;
+            self.new_name_pubsub.tell(Subscribe(actor_ref)).send().await
+        };
+        if let Err(e) = subscribed.await {
+            error!("user id: {} cannot subscribe: {}", msg.id, e);
+            self.relay.directory.remove(&msg.id);
+            return false;
+        }
 
-        self.activity_users.insert(msg.id, msg.user);
+        true
     }
 }
 
@@ -83,30 +327,15 @@ impl Message<UserDisconnection> for ChatRoom {
         msg: UserDisconnection,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        let _ = self.activity_users.remove(&msg.0);
-        self.offline_pubsub.ask(Publish(msg)).send().await.unwrap();
-    }
-}
-
-pub struct AllActivityUsers;
-
-impl Message<AllActivityUsers> for ChatRoom {
-    type Reply = Vec<(UserId, String)>;
-
-    async fn handle(
-        &mut self,
-        _msg: AllActivityUsers,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
-    ) -> Self::Reply {
-        self.activity_users
-            .values()
-            .map(|v| (v.id.clone(), v.name.clone()))
-            .collect()
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("user_disconnection");
+        self.drop_user(&msg.0).await;
     }
 }
 
+/// id, name and whether a bot
 #[derive(Clone)]
-pub struct UserOnline(pub UserId, pub String);
+pub struct UserOnline(pub UserId, pub String, pub bool);
 
 impl Message<UserOnline> for ChatRoom {
     type Reply = ();
@@ -116,7 +345,19 @@ impl Message<UserOnline> for ChatRoom {
         msg: UserOnline,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        self.online_pubsub.ask(Publish(msg)).send().await.unwrap();
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("user_online");
+        self.relay.emit(ChatEvent::Online {
+            id: msg.0.clone(),
+            name: msg.1.clone(),
+            bot: msg.2,
+        });
+        self.relay.publish(ClusterBody::Online {
+            id: msg.0.clone(),
+            name: msg.1.clone(),
+            bot: msg.2,
+        });
+        Self::fan_out(&self.online_pubsub, msg).await;
     }
 }
 
@@ -131,39 +372,50 @@ impl Message<SetName> for ChatRoom {
         msg: SetName,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        self.new_name_pubsub.ask(Publish(msg)).send().await.unwrap();
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("set_name");
+        if self.relay.directory.rename(&msg.0, &msg.1) {
+            self.relay.publish(ClusterBody::Renamed {
+                id: msg.0.clone(),
+                name: msg.1.clone(),
+            });
+        }
+        self.relay.emit(ChatEvent::Renamed {
+            id: msg.0.clone(),
+            name: msg.1.clone(),
+        });
+        Self::fan_out(&self.new_name_pubsub, msg).await;
     }
 }
 
+/// a message to a user, delivered by the `Relay`
 pub struct SendMsg {
     pub from: UserId,
     pub to: UserId,
     pub msg: String,
 }
 
-impl Message<SendMsg> for ChatRoom {
+/// a notice of the server to every user
+pub struct BroadcastNotice(pub String);
+
+impl Message<BroadcastNotice> for ChatRoom {
     type Reply = ();
 
     async fn handle(
         &mut self,
-        msg: SendMsg,
+        msg: BroadcastNotice,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        if let Some(to_user) = self.activity_users.get_mut(&msg.to) {
-            let _ = to_user
-                .actor_ref
-                .tell(NewMsg {
-                    from: msg.from,
-                    msg: msg.msg,
-                })
-                .send()
-                .await;
-        }
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("broadcast_notice");
+        self.relay
+            .publish(ClusterBody::Notice { msg: msg.0.clone() });
+        self.tell_users(NewNotice(msg.0)).await;
     }
 }
 
 /// signal forwork to specify User
-/// do nothing else, just forwork
+/// checked by the `SignalPolicy` first, do nothing else, just forwork
 pub struct ForwordSignal(pub SignalInfo);
 
 impl Message<ForwordSignal> for ChatRoom {
@@ -174,8 +426,424 @@ impl Message<ForwordSignal> for ChatRoom {
         msg: ForwordSignal,
         _ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        if let Some(to_user) = self.activity_users.get(&msg.0.to_id) {
-            to_user.actor_ref.tell(msg).send().await.unwrap();
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("forword_signal");
+        let to_id = msg.0.to_id.clone();
+        let kind = msg.0.signal_type;
+        let metrics = self.relay.metrics().clone();
+        // `None` of a user on another node
+        let to_user = match self.relay.directory.get(&to_id) {
+            Some(actor_ref) => Some(actor_ref),
+            None if self.relay.directory.is_remote(&to_id) => None,
+            None => {
+                metrics.signal(kind, SignalOutcome::Dropped);
+                return;
+            }
+        };
+
+        let from_id = msg.0.from_id.clone();
+        let is_call_request = msg.0.signal_type.is_call_request();
+        let now = Instant::now();
+        // only the signals to be forwarded are charged, a blocked sender too,
+        // so it is refused the same as by anyone else once over the limits
+        let checked = self
+            .signal_policy
+            .check(msg.0)
+            .and_then(|signal| match signal {
+                Some(signal) => self
+                    .signal_limiter
+                    .check(&signal, now)
+                    .map(|_| Some(signal)),
+                None => Ok(None),
+            });
+
+        match checked {
+            Ok(Some(signal)) if self.relay.blocks.is_blocked(&to_id, &from_id) => {
+                self.refuse_blocked(signal).await;
+            }
+            Ok(Some(signal)) => {
+                if let Some(tracker) = &mut self.call_tracker {
+                    tracker.observe(&signal, now);
+                }
+
+                let Some(to_user) = to_user else {
+                    metrics.signal(kind, SignalOutcome::Forwarded);
+                    self.relay.publish(ClusterBody::Signal(signal));
+                    return;
+                };
+                if let Err(e) = to_user.tell(ForwordSignal(signal)).send().await {
+                    warn!("user id: {} is dead: {}", to_id, e);
+                    metrics.signal(kind, SignalOutcome::Dropped);
+                    self.drop_user(&to_id).await;
+                } else {
+                    metrics.signal(kind, SignalOutcome::Forwarded);
+                }
+            }
+            Ok(None) => metrics.signal(kind, SignalOutcome::Dropped),
+            Err(e) if is_call_request => {
+                metrics.signal(kind, SignalOutcome::Denied);
+                warn!("reject call from user id: {}, {}", from_id, e);
+
+                // so the caller stops ringing
+                if let Some(from_user) = self.relay.directory.get(&from_id) {
+                    let deny = SignalInfo {
+                        from_id: to_id,
+                        to_id: from_id.clone(),
+                        signal_type: SignalType::Deny,
+                        value: e.to_string().into(),
+                        call: None,
+                    };
+                    if from_user.tell(ForwordSignal(deny)).send().await.is_err() {
+                        self.drop_user(&from_id).await;
+                    }
+                }
+            }
+            Err(e) => {
+                metrics.signal(kind, SignalOutcome::Denied);
+                warn!("reject signal from user id: {}, {}", from_id, e)
+            }
+        }
+    }
+}
+
+/// the server is shutting down, the user closes its socket and stops
+pub struct GoingAway;
+
+/// replies the users told going away
+struct Shutdown;
+
+impl Message<Shutdown> for ChatRoom {
+    type Reply = Vec<ActorRef<User>>;
+
+    async fn handle(
+        &mut self,
+        _msg: Shutdown,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.relay.metrics().chat_room_took();
+        self.shutting_down = true;
+        self.relay.publish(ClusterBody::Leave);
+
+        let mut users = vec![];
+        for user in self.relay.directory.drain() {
+            // queued after the messages in flight
+            if user.actor_ref.tell(GoingAway).send().await.is_ok() {
+                users.push(user.actor_ref);
+            }
+        }
+
+        users
+    }
+}
+
+/// `Ok` if `asked` answers by `until`
+async fn answered<T, E>(
+    until: tokio::time::Instant,
+    asked: impl std::future::Future<Output = Result<T, E>>,
+) -> Status {
+    match tokio::time::timeout_at(until, asked).await {
+        Ok(Ok(_)) => Status::Ok,
+        Ok(Err(_)) => Status::Stopped,
+        Err(_) => Status::Timeout,
+    }
+}
+
+/// answered by every actor of the chat room, for the readiness
+#[derive(Clone, Copy)]
+pub struct Probe;
+
+/// the pubsubs to probe next
+#[derive(Reply)]
+pub struct Probed {
+    shutting_down: bool,
+    online: ActorRef<PubSub<UserOnline>>,
+    offline: ActorRef<PubSub<UserDisconnection>>,
+    new_name: ActorRef<PubSub<SetName>>,
+}
+
+impl Message<Probe> for ChatRoom {
+    type Reply = Probed;
+
+    async fn handle(
+        &mut self,
+        _msg: Probe,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.relay.metrics().chat_room_took();
+        Probed {
+            shutting_down: self.shutting_down,
+            online: self.online_pubsub.clone(),
+            offline: self.offline_pubsub.clone(),
+            new_name: self.new_name_pubsub.clone(),
+        }
+    }
+}
+
+impl<M: Send + 'static> Message<Probe> for PubSub<M> {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: Probe,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+    }
+}
+
+/// a message of another node of the cluster
+impl Message<ClusterMsg> for ChatRoom {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: ClusterMsg,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.relay.metrics().chat_room_took();
+        let _timer = self.relay.metrics().time("cluster_msg");
+        let ClusterMsg {
+            node,
+            incarnation,
+            body,
+        } = msg;
+
+        let heard = (incarnation.clone(), Instant::now());
+        let restarted = match self.nodes.insert(node.clone(), heard) {
+            None => {
+                info!("cluster node: {} joined", node);
+                true
+            }
+            Some((known, _)) if known != incarnation => {
+                info!(
+                    "cluster node: {} restarted, its users before are offline",
+                    node
+                );
+                for id in self.relay.directory.remove_node(&node) {
+                    self.tell_users(UserDisconnection(id)).await;
+                }
+                true
+            }
+            Some(_) => false,
+        };
+        if restarted {
+            // so the new node knows the users of this one
+            for user in self.relay.directory.local_users() {
+                self.relay.publish(ClusterBody::Online {
+                    id: user.id.clone(),
+                    name: user.name.clone(),
+                    bot: user.bot,
+                });
+            }
+        }
+
+        match body {
+            ClusterBody::Heartbeat => {}
+            ClusterBody::Leave => {
+                info!("cluster node: {} left", node);
+                self.drop_node(&node).await;
+            }
+            ClusterBody::Online { id, name, bot } => {
+                if self.relay.directory.get(&id).is_some() {
+                    warn!("user id: {} is connected to node: {} too", id, node);
+                    return;
+                }
+                let user = RemoteUser {
+                    node,
+                    name: name.clone(),
+                    bot,
+                };
+                if self.relay.directory.insert_remote(id.clone(), user) {
+                    self.tell_users(UserOnline(id, name, bot)).await;
+                }
+            }
+            ClusterBody::Offline { id } => {
+                if self.relay.directory.remove_remote(&id, &node) {
+                    self.tell_users(UserDisconnection(id)).await;
+                }
+            }
+            ClusterBody::Renamed { id, name } => {
+                if self.relay.directory.rename_remote(&id, &name) {
+                    self.tell_users(SetName(id, name)).await;
+                }
+            }
+            ClusterBody::Msg { from, to, msg } => {
+                let Some(to_user) = self.relay.directory.get(&to) else {
+                    return;
+                };
+                if self.relay.blocks.is_blocked(&to, &from) {
+                    self.relay.metrics().message_dropped();
+                    return;
+                }
+                let new_msg = NewMsg {
+                    from: from.clone(),
+                    msg: msg.clone().into_inner(),
+                };
+                if let Err(e) = to_user.tell(new_msg).send().await {
+                    warn!("user id: {} is dead: {}", to, e);
+                    self.drop_user(&to).await;
+                    return;
+                }
+                self.relay.emit(ChatEvent::Message { from, to, msg });
+            }
+            ClusterBody::Signal(signal) => {
+                let Some(to_user) = self.relay.directory.get(&signal.to_id) else {
+                    return;
+                };
+                if self.relay.blocks.is_blocked(&signal.to_id, &signal.from_id) {
+                    self.refuse_blocked(signal).await;
+                    return;
+                }
+                let to_id = signal.to_id.clone();
+                if let Err(e) = to_user.tell(ForwordSignal(signal)).send().await {
+                    warn!("user id: {} is dead: {}", to_id, e);
+                    self.drop_user(&to_id).await;
+                }
+            }
+            ClusterBody::Notice { msg } => self.tell_users(NewNotice(msg)).await,
+        }
+    }
+}
+
+/// heartbeat to the other nodes, and forget the silent ones
+pub struct ClusterTick;
+
+impl Message<ClusterTick> for ChatRoom {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: ClusterTick,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.relay.metrics().chat_room_took();
+        let Some(cluster) = self.relay.cluster() else {
+            return;
+        };
+        cluster.publish(ClusterBody::Heartbeat);
+
+        let deadline = cluster.heartbeat * 3;
+        let silent: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|(_, (_, heard))| heard.elapsed() > deadline)
+            .map(|(node, _)| node.clone())
+            .collect();
+        for node in silent {
+            warn!("cluster node: {} is silent, its users are offline", node);
+            self.drop_node(&node).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_chat_room {
+    use std::collections::HashSet;
+
+    use serde_json::json;
+
+    use crate::{
+        limit::Rate,
+        signal::SignalLimits,
+        test_client::{online_users, serve, TestClient},
+        App,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn survive_dead_sockets_mid_broadcast() {
+        let addr = serve(App::new("127.0.0.1:0", vec!["*".to_string()])).await;
+
+        let mut alive = TestClient::connect(addr).await;
+        let mut doomed = vec![];
+        for _ in 0..8 {
+            doomed.push(TestClient::connect(addr).await);
         }
+        let doomed_ids: HashSet<_> = doomed.iter().map(|client| client.id.clone()).collect();
+
+        // the sockets die while the users joining are broadcast to them
+        let joining = tokio::spawn(async move {
+            let mut joined = vec![];
+            for _ in 0..4 {
+                joined.push(TestClient::connect(addr).await);
+            }
+            joined
+        });
+        drop(doomed);
+        let mut joined = joining.await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut offline = HashSet::new();
+            while offline != doomed_ids {
+                let data = alive
+                    .recv_until(|data| data["msg_type"]["userOffline"].is_object())
+                    .await
+                    .expect("the room is alive");
+                offline.insert(
+                    data["msg_type"]["userOffline"]["id"]
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                );
+            }
+
+            // messages still flow
+            let last = joined.last_mut().unwrap();
+            alive.talk_to(&last.id, "still here").await;
+            let msg = last
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "still here");
+        })
+        .await
+        .expect("every dead user went offline");
+
+        let online: HashSet<_> = online_users(addr).await.into_iter().collect();
+        assert!(online.is_disjoint(&doomed_ids));
+        assert!(online.contains(&alive.id));
+        assert!(joined.iter().all(|client| online.contains(&client.id)));
+    }
+
+    #[tokio::test]
+    async fn charge_only_forwarded_signals() {
+        let app = App::new("", vec!["*".to_string()]).with_signal_policy(SignalPolicy {
+            limits: SignalLimits {
+                signals_per_sender: Rate::new(2, Duration::from_secs(60)),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            let (from_id, to_id) = (a.id.clone(), b.id.clone());
+            let signal = |signal_type: &str, value: &str| {
+                json!({ "msg_type": { "signal": {
+                    "from_id": from_id,
+                    "to_id": to_id,
+                    "signal_type": signal_type,
+                    "value": value,
+                } } })
+            };
+
+            // refused by the policy
+            for _ in 0..3 {
+                a.send(signal("offer", "not a description")).await;
+            }
+            a.send(signal("stop", "")).await;
+            a.send(signal("stop", "")).await;
+            for _ in 0..2 {
+                let stop = b
+                    .recv_until(|data| data["msg_type"]["signal"].is_object())
+                    .await
+                    .unwrap();
+                assert_eq!(stop["msg_type"]["signal"]["signal_type"], "stop");
+            }
+        })
+        .await
+        .unwrap();
     }
 }
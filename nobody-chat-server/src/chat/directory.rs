@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use kameo::actor::ActorRef;
+
+use super::{User, UserRef};
+use crate::{cluster::NodeId, models::UserId, routes::home::OnlineUser};
+
+/// enough shards that the users rarely wait for each other
+const SHARDS: usize = 32;
+
+/// a user of another node of the cluster
+pub struct RemoteUser {
+    pub node: NodeId,
+    pub name: String,
+    pub bot: bool,
+}
+
+/// who is online, sharded by user id, so the users find each other
+/// without waiting in the chat room mailbox, only the chat room writes it
+pub struct Directory {
+    hasher: RandomState,
+    shards: Vec<RwLock<HashMap<UserId, UserRef>>>,
+    /// the users of the other nodes, if a node of a cluster
+    remote: RwLock<HashMap<UserId, RemoteUser>>,
+}
+
+impl Default for Directory {
+    fn default() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            remote: RwLock::default(),
+        }
+    }
+}
+
+/// a panic never leaves a map half written, so a poisoned lock is still usable
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Directory {
+    fn shard(&self, id: &str) -> &RwLock<HashMap<UserId, UserRef>> {
+        let hash = self.hasher.hash_one(id) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    /// `false` if the id is online already, here or on another node
+    pub fn insert(&self, user: UserRef) -> bool {
+        if read(&self.remote).contains_key(&user.id) {
+            return false;
+        }
+
+        let mut shard = write(self.shard(&user.id));
+        if shard.contains_key(&user.id) {
+            return false;
+        }
+        shard.insert(user.id.clone(), user);
+        true
+    }
+
+    pub fn remove(&self, id: &str) -> Option<UserRef> {
+        write(self.shard(id)).remove(id)
+    }
+
+    /// the actor of a user of this node
+    pub fn get(&self, id: &str) -> Option<ActorRef<User>> {
+        read(self.shard(id))
+            .get(id)
+            .map(|user| user.actor_ref.clone())
+    }
+
+    /// a user of this node
+    pub fn user(&self, id: &str) -> Option<UserRef> {
+        read(self.shard(id)).get(id).cloned()
+    }
+
+    /// `false` if not a user of this node
+    pub fn rename(&self, id: &str, name: &str) -> bool {
+        match write(self.shard(id)).get_mut(id) {
+            Some(user) => {
+                user.name = name.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// here or on another node
+    pub fn contains(&self, id: &str) -> bool {
+        read(self.shard(id)).contains_key(id) || self.is_remote(id)
+    }
+
+    pub fn is_remote(&self, id: &str) -> bool {
+        read(&self.remote).contains_key(id)
+    }
+
+    /// a snapshot of the users of this node, for telling them all
+    pub fn local_users(&self) -> Vec<UserRef> {
+        self.shards
+            .iter()
+            .flat_map(|shard| read(shard).values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn local_len(&self) -> usize {
+        self.shards.iter().map(|shard| read(shard).len()).sum()
+    }
+
+    pub fn remote_len(&self) -> usize {
+        read(&self.remote).len()
+    }
+
+    /// every user of the cluster
+    pub fn users(&self) -> Vec<OnlineUser> {
+        let mut users: Vec<_> = self
+            .local_users()
+            .into_iter()
+            .map(|user| OnlineUser {
+                id: user.id,
+                name: user.name,
+                bot: user.bot,
+            })
+            .collect();
+        users.extend(read(&self.remote).iter().map(|(id, user)| OnlineUser {
+            id: id.clone(),
+            name: user.name.clone(),
+            bot: user.bot,
+        }));
+
+        users
+    }
+
+    /// forget every user of this node
+    pub fn drain(&self) -> Vec<UserRef> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                write(shard)
+                    .drain()
+                    .map(|(_, user)| user)
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// `false` if the user is known already
+    pub fn insert_remote(&self, id: UserId, user: RemoteUser) -> bool {
+        write(&self.remote).insert(id, user).is_none()
+    }
+
+    /// only by the node of the user
+    pub fn remove_remote(&self, id: &str, node: &str) -> bool {
+        let mut remote = write(&self.remote);
+        if remote.get(id).is_some_and(|user| user.node == node) {
+            remote.remove(id);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn rename_remote(&self, id: &str, name: &str) -> bool {
+        match write(&self.remote).get_mut(id) {
+            Some(user) => {
+                user.name = name.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// forget the users of the node, returning their ids
+    pub fn remove_node(&self, node: &str) -> Vec<UserId> {
+        let mut remote = write(&self.remote);
+        let ids: Vec<_> = remote
+            .iter()
+            .filter(|(_, user)| user.node == node)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &ids {
+            remote.remove(id);
+        }
+
+        ids
+    }
+}
+
+#[cfg(test)]
+mod test_directory {
+    use super::*;
+
+    fn remote(node: &str) -> RemoteUser {
+        RemoteUser {
+            node: node.to_string(),
+            name: "名字".to_string(),
+            bot: false,
+        }
+    }
+
+    #[test]
+    fn track_remote_users() {
+        let directory = Directory::default();
+        assert!(directory.insert_remote("a".to_string(), remote("one")));
+        assert!(!directory.insert_remote("a".to_string(), remote("one")));
+        assert!(directory.insert_remote("b".to_string(), remote("two")));
+        assert!(directory.contains("a") && directory.is_remote("b"));
+        assert!(directory.get("a").is_none());
+
+        // only the node of the user tells it is offline
+        assert!(!directory.remove_remote("a", "two"));
+        assert!(directory.rename_remote("a", "new name"));
+        assert_eq!(directory.users().len(), 2);
+
+        assert_eq!(directory.remove_node("two"), vec!["b".to_string()]);
+        assert!(!directory.contains("b"));
+        assert!(directory.remove_remote("a", "one"));
+        assert!(directory.users().is_empty());
+    }
+}
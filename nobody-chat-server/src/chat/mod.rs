@@ -1,8 +1,14 @@
+mod blocks;
 mod chat_room;
+mod directory;
 mod models;
 mod plain_user;
+mod relay;
 mod user;
 
+pub use blocks::*;
 pub use chat_room::*;
+pub use directory::*;
 pub use plain_user::*;
+pub use relay::*;
 pub use user::*;
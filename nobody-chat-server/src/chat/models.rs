@@ -6,16 +6,39 @@ use crate::models::UserId;
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
+#[rustfmt::skip]
 enum MsgType {
     SetUser { id: UserId, name: String },
     Msg { from: UserId, msg: String },
-    UserOnline { id: UserId, name: String },
+    UserOnline {
+        id: UserId,
+        name: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        bot: bool,
+    },
     UserOffline { id: UserId },
     SetName { id: UserId, name: String },
+    /// from the server, not a user
+    Notice { msg: String },
+    /// a frame of the user refused
+    Error { code: ErrorCode, msg: String },
 
     Signal(SignalInfo),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// over the message limits, the frame is dropped
+    RateLimited,
+    /// every frame is dropped for a while
+    Muted,
+    /// `MAX_BLOCKED` ids blocked already
+    BlockListFull,
+    /// by a message filter, never delivered
+    Rejected,
+}
+
 #[derive(Serialize)]
 pub struct SendData {
     msg_type: MsgType,
@@ -34,9 +57,9 @@ impl SendData {
         }
     }
 
-    pub fn new_user_online(id: UserId, name: String) -> Self {
+    pub fn new_user_online(id: UserId, name: String, bot: bool) -> Self {
         Self {
-            msg_type: MsgType::UserOnline { id, name },
+            msg_type: MsgType::UserOnline { id, name, bot },
         }
     }
 
@@ -52,6 +75,18 @@ impl SendData {
         }
     }
 
+    pub fn new_notice(msg: String) -> Self {
+        Self {
+            msg_type: MsgType::Notice { msg },
+        }
+    }
+
+    pub fn new_error(code: ErrorCode, msg: String) -> Self {
+        Self {
+            msg_type: MsgType::Error { code, msg },
+        }
+    }
+
     pub fn new_signal_forword(signal: SignalInfo) -> Self {
         Self {
             msg_type: MsgType::Signal(signal),
@@ -62,8 +97,18 @@ impl SendData {
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum RecvDataType {
-    TalkTo { to: UserId, msg: String },
+    TalkTo {
+        to: UserId,
+        msg: String,
+    },
     Signal(SignalInfo),
+    /// stop receiving the messages and calls of `id`
+    Block {
+        id: UserId,
+    },
+    Unblock {
+        id: UserId,
+    },
 }
 
 #[derive(Deserialize)]
@@ -0,0 +1,269 @@
+use std::sync::Arc;
+
+use kameo::{actor::ActorRef, request::MessageSend};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use super::{
+    Blocks, ChatRoom, Directory, HeartbeatOptions, NewMsg, SendMsg, UserDisconnection, UserOptions,
+};
+use crate::{
+    cluster::{ClusterBody, ClusterLink},
+    filter::MessageFilters,
+    handle::ChatEvent,
+    limit::MessageLimits,
+    metrics::Metrics,
+    socket::{Outbox, OutboxMetrics, SendMsg as SendSocketMsg, SendSocket},
+    webhook::{WebhookFlagged, WebhookMessage, WebhooksRef},
+};
+
+/// delivers the messages between the users, shared by the chat room and every user,
+/// so a message never waits in the chat room mailbox
+pub struct Relay {
+    pub directory: Directory,
+    /// whom the users of this node blocked
+    pub blocks: Blocks,
+    /// observed by the `ChatHandle`s
+    events: broadcast::Sender<ChatEvent>,
+    /// subscribed to the presence, and opted in to the messages of some ids
+    webhooks: Option<WebhooksRef>,
+    /// the other nodes, if a node of a cluster
+    cluster: Option<ClusterLink>,
+    users: UserOptions,
+    outbox_metrics: Arc<OutboxMetrics>,
+    metrics: Arc<Metrics>,
+}
+
+impl Relay {
+    pub fn new(
+        events: broadcast::Sender<ChatEvent>,
+        webhooks: Option<WebhooksRef>,
+        cluster: Option<ClusterLink>,
+        users: UserOptions,
+    ) -> Self {
+        Self {
+            directory: Directory::default(),
+            blocks: Blocks::default(),
+            events,
+            webhooks,
+            cluster,
+            users,
+            outbox_metrics: Arc::default(),
+            metrics: Arc::default(),
+        }
+    }
+
+    /// the outbound queue of a new user
+    pub fn outbox<S: SendSocketMsg + 'static>(&self, socket: SendSocket<S>) -> Outbox {
+        Outbox::spawn(
+            socket,
+            self.users.outbox.clone(),
+            self.outbox_metrics.clone(),
+        )
+    }
+
+    pub fn outbox_metrics(&self) -> &OutboxMetrics {
+        &self.outbox_metrics
+    }
+
+    pub fn heartbeat(&self) -> &HeartbeatOptions {
+        &self.users.heartbeat
+    }
+
+    pub fn message_limits(&self) -> Option<MessageLimits> {
+        self.users.message_limits
+    }
+
+    pub fn message_filters(&self) -> &MessageFilters {
+        &self.users.message_filters
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// the Prometheus text format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(
+            self.directory.local_len(),
+            self.directory.remote_len(),
+            self.outbox_metrics.stats(),
+        )
+    }
+
+    pub fn events(&self) -> &broadcast::Sender<ChatEvent> {
+        &self.events
+    }
+
+    pub fn webhooks(&self) -> Option<&WebhooksRef> {
+        self.webhooks.as_ref()
+    }
+
+    pub fn cluster(&self) -> Option<&ClusterLink> {
+        self.cluster.as_ref()
+    }
+
+    pub fn emit(&self, event: ChatEvent) {
+        // no clone of the messages when nobody observes
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// tell the subscribers and the webhooks of a message a filter flagged
+    pub async fn flag(&self, msg: &SendMsg, reasons: Vec<String>) {
+        if let Some(webhooks) = &self.webhooks {
+            let flagged = WebhookFlagged {
+                from: msg.from.clone(),
+                to: msg.to.clone(),
+                msg: msg.msg.clone(),
+                reasons: reasons.clone(),
+            };
+            let _ = webhooks.actor.tell(flagged).send().await;
+        }
+        self.emit(ChatEvent::Flagged {
+            from: msg.from.clone(),
+            to: msg.to.clone(),
+            msg: msg.msg.clone().into(),
+            reasons,
+        });
+    }
+
+    /// tell the other nodes, if any
+    pub fn publish(&self, body: ClusterBody) {
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(body);
+        }
+    }
+
+    /// whether the receiver is online, or a webhook takes the message,
+    /// a dead receiver is told to the chat room; `false` if the receiver blocked the sender,
+    /// the sender is never told
+    pub async fn send_msg(&self, msg: SendMsg, chat_room: &ActorRef<ChatRoom>) -> bool {
+        let _timer = self.metrics.time("send_msg");
+        if self.blocks.is_blocked(&msg.to, &msg.from) {
+            self.metrics.message_dropped();
+            return false;
+        }
+
+        let mut hooked = false;
+        if let Some(webhooks) = self.webhooks.as_ref().filter(|w| w.takes(&msg.to)) {
+            let hook_msg = WebhookMessage {
+                from: msg.from.clone(),
+                to: msg.to.clone(),
+                msg: msg.msg.clone(),
+            };
+            hooked = webhooks.actor.tell(hook_msg).send().await.is_ok();
+        }
+
+        let Some(to_user) = self.directory.get(&msg.to) else {
+            if self.directory.is_remote(&msg.to) {
+                self.metrics.message_routed();
+                self.publish(ClusterBody::Msg {
+                    from: msg.from,
+                    to: msg.to,
+                    msg: msg.msg.into(),
+                });
+                return true;
+            }
+            self.metrics.message_dropped();
+            return hooked;
+        };
+
+        let sent = to_user
+            .tell(NewMsg {
+                from: msg.from.clone(),
+                msg: msg.msg.clone(),
+            })
+            .send()
+            .await;
+        if let Err(e) = sent {
+            warn!("user id: {} is dead: {}", msg.to, e);
+            self.metrics.message_dropped();
+            self.metrics.chat_room_sent();
+            let _ = chat_room.tell(UserDisconnection(msg.to)).send().await;
+            return hooked;
+        }
+
+        self.metrics.message_routed();
+        self.emit(ChatEvent::Message {
+            from: msg.from,
+            to: msg.to,
+            msg: msg.msg.into(),
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod test_load {
+    use std::time::{Duration, Instant};
+
+    use futures_util::future::join_all;
+
+    use crate::{
+        test_client::{serve_routes, TestClient},
+        App,
+    };
+
+    /// `users` each sending `msgs` messages to the next one, the messages per second
+    async fn talk_in_a_ring(users: usize, msgs: usize) -> f64 {
+        let (routes, chat) = App::new("", vec!["*".to_string()]).build().unwrap();
+        let addr = serve_routes(routes).await;
+
+        let mut clients = vec![];
+        for batch in (0..users).collect::<Vec<_>>().chunks(100) {
+            clients.extend(join_all(batch.iter().map(|_| TestClient::connect(addr))).await);
+        }
+        while chat.online_users().await.unwrap().len() < users {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let ids: Vec<_> = clients.iter().map(|client| client.id.clone()).collect();
+        let started = Instant::now();
+        let tasks = clients.into_iter().enumerate().map(|(i, mut client)| {
+            let to = ids[(i + 1) % users].clone();
+            tokio::spawn(async move {
+                for n in 0..msgs {
+                    client.talk_to(&to, &format!("message {n}")).await;
+                }
+                for _ in 0..msgs {
+                    client
+                        .recv_until(|data| data["msg_type"]["msg"].is_object())
+                        .await
+                        .expect("every message delivered");
+                }
+                client
+            })
+        });
+        let clients = join_all(tasks).await;
+        let elapsed = started.elapsed();
+        assert!(clients.iter().all(|client| client.is_ok()));
+
+        (users * msgs) as f64 / elapsed.as_secs_f64()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn talk_with_many_users() {
+        tokio::time::timeout(Duration::from_secs(60), talk_in_a_ring(100, 10))
+            .await
+            .unwrap();
+    }
+
+    /// `cargo test --release -- --ignored --nocapture talk_with_thousands_of_users`,
+    /// `LOAD_USERS` and `LOAD_MSGS` for more
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn talk_with_thousands_of_users() {
+        let var = |name, default| {
+            std::env::var(name)
+                .ok()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(default)
+        };
+        let (users, msgs) = (var("LOAD_USERS", 2000), var("LOAD_MSGS", 50));
+
+        let rate = talk_in_a_ring(users, msgs).await;
+        println!("{users} users, {msgs} messages each: {rate:.0} messages/s");
+    }
+}
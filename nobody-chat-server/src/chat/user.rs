@@ -1,41 +1,165 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
 use axum::extract::ws::{Message as WsMessage, WebSocket};
-use futures_util::StreamExt;
+use futures_util::{Stream, StreamExt};
 use kameo::{
     actor::ActorRef,
-    error::SendError,
     mailbox::unbounded::UnboundedMailbox,
     message::{Message, StreamMessage},
     request::MessageSend,
     Actor,
 };
-use log::{debug, error, info, warn};
 use serde_json::json;
+use tokio::sync::oneshot;
+use tracing::{debug, error, field, info, warn, Span};
 use uuid::Uuid;
 
 use crate::{
-    chat::{models::SendData, PlainUser, SendMsg, UserDisconnection},
+    chat::{
+        models::{ErrorCode, SendData},
+        PlainUser, SendMsg, UserDisconnection, MAX_BLOCKED,
+    },
     cipher::{chacha::ChaCha, EncryptDecrypt},
+    error::{Error, Result},
+    filter::{Checked, MessageFilters},
+    handle::ChatHandle,
+    limit::{ConnectionSlot, MessageLimiter, MessageLimits, Verdict},
     models::UserId,
     signal::SignalInfo,
-    socket::{RecvSocket, SendSocket, SinkSendMsg},
+    socket::{Outbox, OutboxOptions, Outgoing, RecvSocket, SendSocket},
 };
 
 use super::{
     models::{RecvData, RecvDataType},
-    ChatRoom, ForwordSignal, NewUserConnection, SetName, UserOnline,
+    ChatRoom, ForwordSignal, GoingAway, NewUserConnection, Relay, SetName, UserOnline,
 };
 
+#[derive(Clone)]
 pub struct UserRef {
     pub id: UserId,
     pub name: String,
+    pub bot: bool,
+    pub connection: Connection,
     pub actor_ref: ActorRef<User>,
 }
 
+/// where and when a user connected from, for the admins
+#[derive(Debug, Clone)]
+pub struct Connection {
+    /// the peer, unknown if the embedding application serves without connect info
+    pub remote: Option<SocketAddr>,
+    /// the client, behind the trusted proxies
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub connected_at: SystemTime,
+    /// of the connection limits, released once the user is gone
+    slot: Option<Arc<ConnectionSlot>>,
+}
+
+impl Connection {
+    pub fn new(remote: Option<SocketAddr>, ip: Option<IpAddr>, user_agent: Option<String>) -> Self {
+        Self {
+            remote,
+            ip,
+            user_agent,
+            connected_at: SystemTime::now(),
+            slot: None,
+        }
+    }
+
+    pub fn with_slot(mut self, slot: ConnectionSlot) -> Self {
+        self.slot = Some(Arc::new(slot));
+        self
+    }
+}
+
+/// the id of the bot `name`, the same every connection
+pub fn bot_id(name: &str) -> UserId {
+    format!("bot-{name}")
+}
+
+/// like `attach_stream`, but stops once the user is gone, not reading a dead user's socket
+fn forward<S>(mut recv: S, user: ActorRef<User>, mut stopped: oneshot::Receiver<()>)
+where
+    S: Stream<Item = Result<WsMessage, axum::Error>> + Send + Unpin + 'static,
+{
+    tokio::spawn(async move {
+        if user.tell(StreamMessage::Started(())).send().await.is_err() {
+            return;
+        }
+        loop {
+            let next = tokio::select! {
+                next = recv.next() => next,
+                _ = &mut stopped => return,
+            };
+            let Some(next) = next else {
+                break;
+            };
+            if user.tell(StreamMessage::Next(next)).send().await.is_err() {
+                return;
+            }
+        }
+        let _ = user.tell(StreamMessage::Finished(())).send().await;
+    });
+}
+
+/// how the users of half-open connections are noticed
+#[derive(Debug, Clone)]
+pub struct HeartbeatOptions {
+    /// a ping every interval
+    pub ping_interval: Duration,
+    /// without a pong in time the connection is dead
+    pub pong_timeout: Duration,
+    /// without a message from the client this long it is disconnected, `None` never
+    pub idle_timeout: Option<Duration>,
+    /// a browser not sending its public key in time is disconnected before it joins
+    pub handshake_timeout: Duration,
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(10),
+            idle_timeout: None,
+            handshake_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// of every user
+#[derive(Debug, Clone)]
+pub struct UserOptions {
+    pub outbox: OutboxOptions,
+    pub heartbeat: HeartbeatOptions,
+    /// `None` for no limit
+    pub message_limits: Option<MessageLimits>,
+    /// the messages to other users pass them before they are routed
+    pub message_filters: MessageFilters,
+}
+
 pub struct User {
     id: UserId,
     name: String,
-    sender: SendSocket<SinkSendMsg>,
+    bot: bool,
+    outbox: Outbox,
     chat_room: ActorRef<ChatRoom>,
+    /// the messages to other users go by it
+    relay: Arc<Relay>,
+    /// the ping not answered yet
+    awaiting_pong: Option<u64>,
+    pings: u64,
+    last_active: Instant,
+    /// dropped with the user, so a half-open connection is not read forever
+    stop_recv: Option<oneshot::Sender<()>>,
+    /// of the connection, the parent of every log of the user
+    span: Span,
+    /// `None` of a bot, or without limits
+    limiter: Option<MessageLimiter>,
 }
 
 impl Actor for User {
@@ -45,9 +169,7 @@ impl Actor for User {
         &mut self,
         _actor_ref: kameo::actor::ActorRef<Self>,
     ) -> Result<(), kameo::error::BoxError> {
-        use log::debug;
-
-        debug!("user, id: {} started", self.get_id());
+        debug!(parent: &self.span, "started");
 
         Ok(())
     }
@@ -57,10 +179,10 @@ impl Actor for User {
         _actor_ref: kameo::actor::WeakActorRef<Self>,
         reason: kameo::error::ActorStopReason,
     ) -> Result<(), kameo::error::BoxError> {
-        warn!("user id: {} stopped, error: {:?}", self.id, reason);
+        warn!(parent: &self.span, "stopped: {:?}", reason);
 
-        // close websocket
-        let _ = self.sender.close().await;
+        // close websocket, after the frames queued
+        self.outbox.close();
 
         Ok(())
     }
@@ -74,45 +196,51 @@ impl Message<StreamMessage<Result<WsMessage, axum::Error>, (), ()>> for User {
         msg: StreamMessage<Result<WsMessage, axum::Error>, (), ()>,
         ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
-        debug!("user id: {}", self.get_id());
-
         match msg {
             StreamMessage::Started(()) => {
-                info!("user id: {} started", self.id);
+                info!(parent: &self.span, "online");
                 self.connection_started().await;
+                self.start_heartbeat(ctx.actor_ref());
             }
             StreamMessage::Finished(()) => {
-                info!("user id: {}, Finish", self.get_id());
-
-                self.chat_room
-                    .tell(UserDisconnection(self.get_id()))
-                    .send()
-                    .await
-                    .unwrap();
+                info!(parent: &self.span, "connection finished");
 
-                ctx.actor_ref().kill();
+                self.disconnect(ctx.actor_ref()).await;
             }
-            StreamMessage::Next(Ok(message)) => {
-                debug!("{:?}", message);
-                match message {
-                    WsMessage::Text(raw_msg) => self.handle_recv_msg(raw_msg).await,
-                    _ => {}
+            // never logged, a frame is a message body
+            StreamMessage::Next(Ok(message)) => match message {
+                WsMessage::Text(raw_msg) => {
+                    self.last_active = Instant::now();
+                    self.handle_recv_msg(raw_msg, ctx.actor_ref()).await
                 }
-            }
+                WsMessage::Pong(_) => self.awaiting_pong = None,
+                _ => {}
+            },
             StreamMessage::Next(Err(e)) => {
-                error!("Unready User occured: {:?}", e);
+                self.relay.metrics().decrypt_failed();
+                warn!(parent: &self.span, "received a broken frame: {}", e);
             }
         }
     }
 }
 
 impl User {
+    /// a browser, after the key exchange every message is encrypted
     pub async fn new_actor(
         socket: WebSocket,
-        chat_room: ActorRef<ChatRoom>,
-    ) -> Result<(), SendError<NewUserConnection>> {
+        chat: ChatHandle,
+        connection: Connection,
+    ) -> Result<()> {
         let plain_user = PlainUser::new(socket);
-        let (socket, key) = plain_user.exchange_key().await.unwrap();
+        let metrics = chat.relay.metrics();
+        metrics.handshake_started();
+        let handshake_timeout = chat.relay.heartbeat().handshake_timeout;
+        let (socket, key) = tokio::time::timeout(handshake_timeout, plain_user.exchange_key())
+            .await
+            .unwrap_or(Err(Error::Timeout))
+            .inspect_err(|_| {
+                metrics.handshake_failed();
+            })?;
 
         let (sender, recv) = socket.split();
 
@@ -122,36 +250,107 @@ impl User {
         let recv_socket = RecvSocket::new(recv, decrypt);
 
         let id = Uuid::new_v4().simple().to_string();
-        debug!("User new id: {id}");
+        let span = Span::current();
+        span.record("user", field::display(&id));
         let name = id[..5].to_string();
-        let actor = kameo::spawn(Self {
-            id: id.clone(),
-            name: name.clone(),
-            sender: send_socket,
-            chat_room: chat_room.clone(),
-            // pri_key: None,
-        });
+        let limiter = chat
+            .relay
+            .message_limits()
+            .map(|limits| MessageLimiter::new(limits, Instant::now()));
+        let user = Self {
+            id,
+            name,
+            bot: false,
+            outbox: chat.relay.outbox(send_socket),
+            chat_room: chat.chat_room,
+            relay: chat.relay,
+            awaiting_pong: None,
+            pings: 0,
+            last_active: Instant::now(),
+            stop_recv: None,
+            span,
+            limiter,
+        };
 
-        actor.attach_stream(recv_socket, (), ());
-        let tem_actor = actor.clone();
+        user.join(recv_socket, connection).await
+    }
+
+    /// a bot authenticated by its API key, messages are plain JSON
+    pub async fn new_bot(
+        socket: WebSocket,
+        name: String,
+        chat: ChatHandle,
+        connection: Connection,
+    ) -> Result<()> {
+        let (sender, recv) = socket.split();
+        let span = Span::current();
+        span.record("user", field::display(bot_id(&name)));
 
-        chat_room
-            .tell(NewUserConnection::new(
+        let user = Self {
+            id: bot_id(&name),
+            name,
+            bot: true,
+            outbox: chat.relay.outbox(SendSocket::plain(sender)),
+            chat_room: chat.chat_room,
+            relay: chat.relay,
+            awaiting_pong: None,
+            pings: 0,
+            last_active: Instant::now(),
+            stop_recv: None,
+            span,
+            // trusted by its key, it may answer many users at once
+            limiter: None,
+        };
+
+        user.join(recv, connection).await
+    }
+
+    /// spawn and join the chat room, then receive from `recv`
+    async fn join<S>(mut self, recv: S, connection: Connection) -> Result<()>
+    where
+        S: Stream<Item = Result<WsMessage, axum::Error>> + Send + Unpin + 'static,
+    {
+        let (chat_room, relay) = (self.chat_room.clone(), self.relay.clone());
+        let (id, name, bot) = (self.get_id(), self.get_name(), self.bot);
+
+        // before anything the others do, which may come once joined
+        let set_user = SendData::new_set_user(id.clone(), name.clone());
+        self.outbox
+            .push(Outgoing::Data(json!(set_user).to_string()));
+        let (stop_recv, stopped) = oneshot::channel();
+        self.stop_recv = Some(stop_recv);
+        let actor = kameo::spawn(self);
+
+        relay.metrics().chat_room_sent();
+        let joined = chat_room
+            .ask(NewUserConnection::new(
                 id.clone(),
                 UserRef {
-                    id,
+                    id: id.clone(),
                     name,
-                    actor_ref: actor,
+                    bot,
+                    connection,
+                    actor_ref: actor.clone(),
                 },
             ))
             .send()
-            .await
-            .map_err(|e| {
-                tem_actor.kill();
-                e
-            })?;
+            .await;
 
-        Ok(())
+        match joined {
+            Ok(true) => {
+                forward(recv, actor, stopped);
+                Ok(())
+            }
+            Ok(false) => {
+                // after a going away, if shutting down
+                let _ = actor.stop_gracefully().await;
+                Err(Error::Protocol(format!("user id: {id} cannot join")))
+            }
+            Err(e) => {
+                actor.kill();
+                Err(e.into())
+            }
+        }
     }
 
     pub fn get_id(&self) -> String {
@@ -162,56 +361,197 @@ impl User {
         self.name.clone()
     }
 
-    async fn connection_started(&mut self) {
-        self.send_user_info_to_client().await;
-
-        self.chat_room
-            .tell(UserOnline(self.get_id(), self.get_name()))
+    async fn connection_started(&self) {
+        self.relay.metrics().chat_room_sent();
+        if let Err(e) = self
+            .chat_room
+            .tell(UserOnline(self.get_id(), self.get_name(), self.bot))
             .send()
             .await
-            .unwrap();
+        {
+            error!(parent: &self.span, "cannot go online: {}", e);
+        }
+    }
+
+    async fn send_data(&mut self, data: SendData, actor_ref: ActorRef<Self>) {
+        self.send(Outgoing::Data(json!(data).to_string()), actor_ref)
+            .await;
+    }
+
+    /// dropped first once the client is too slow
+    async fn send_presence(&mut self, data: SendData, actor_ref: ActorRef<Self>) {
+        self.send(Outgoing::Presence(json!(data).to_string()), actor_ref)
+            .await;
+    }
+
+    /// a dead socket or a client too slow disconnects the user, never waiting for it
+    async fn send(&mut self, frame: Outgoing, actor_ref: ActorRef<Self>) {
+        if !self.outbox.push(frame) {
+            warn!(parent: &self.span, "too slow or the socket is dead");
+            self.outbox.abort();
+            self.disconnect(actor_ref).await;
+        }
     }
 
-    async fn send_user_info_to_client(&mut self) {
-        let data = SendData::new_set_user(self.get_id(), self.get_name());
-        let data = json!(data).to_string();
+    /// a heartbeat every ping interval, until the user is stopped
+    fn start_heartbeat(&self, actor_ref: ActorRef<Self>) {
+        let interval = self.relay.heartbeat().ping_interval;
+        let user = actor_ref.downgrade();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = tokio::time::interval_at(start, interval);
+            loop {
+                ticks.tick().await;
+                let Some(user) = user.upgrade() else {
+                    break;
+                };
+                if user.tell(Heartbeat).send().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-        self.sender.send(data).await.unwrap();
+    async fn disconnect(&self, actor_ref: ActorRef<Self>) {
+        // the chat room is gone once shutting down
+        self.relay.metrics().chat_room_sent();
+        if let Err(e) = self
+            .chat_room
+            .tell(UserDisconnection(self.get_id()))
+            .send()
+            .await
+        {
+            debug!(parent: &self.span, "disconnection not told: {}", e);
+        }
+
+        actor_ref.kill();
     }
 
-    async fn handle_recv_msg(&self, raw_msg: String) {
-        if let Ok(data) = serde_json::from_str::<'_, RecvData>(&raw_msg) {
+    async fn handle_recv_msg(&mut self, raw_msg: String, actor_ref: ActorRef<Self>) {
+        let data = serde_json::from_str::<'_, RecvData>(&raw_msg);
+        let message = matches!(
+            data,
+            Ok(RecvData {
+                msg_type: RecvDataType::TalkTo { .. }
+            })
+        );
+        if !self
+            .within_limits(raw_msg.len(), message, actor_ref.clone())
+            .await
+        {
+            return;
+        }
+
+        if let Ok(data) = data {
             match data.msg_type {
-                RecvDataType::TalkTo { to, msg } => self.handle_talk_to_user(to, msg).await,
+                RecvDataType::TalkTo { to, msg } => {
+                    self.handle_talk_to_user(to, msg, actor_ref).await
+                }
                 RecvDataType::Signal(signal) => self.handle_signal(signal).await,
+                RecvDataType::Block { id } => self.handle_block(id, actor_ref).await,
+                RecvDataType::Unblock { id } => self.relay.blocks.unblock(&self.id, &id),
             }
         } else {
-            error!("received unknow data: {raw_msg}");
+            error!(
+                parent: &self.span,
+                "received unknown data of {} bytes",
+                raw_msg.len()
+            );
         }
     }
 
-    async fn handle_talk_to_user(&self, to: UserId, msg: String) {
-        debug!("rece: to: {to}, msg: {msg}");
+    /// `false` if the frame is dropped, the user is warned, muted or disconnected
+    async fn within_limits(
+        &mut self,
+        len: usize,
+        message: bool,
+        actor_ref: ActorRef<Self>,
+    ) -> bool {
+        let Some(limiter) = &mut self.limiter else {
+            return true;
+        };
 
-        self.chat_room
-            .tell(SendMsg {
-                from: self.get_id(),
-                to,
-                msg,
-            })
-            .send()
-            .await
-            .unwrap();
+        let now = Instant::now();
+        match limiter.check(len, message, now) {
+            Verdict::Pass => return true,
+            Verdict::Warn => {
+                info!(parent: &self.span, "over the message limits, warned");
+                let data = SendData::new_error(
+                    ErrorCode::RateLimited,
+                    "too many messages, slow down or be muted".to_string(),
+                );
+                self.send_data(data, actor_ref).await;
+            }
+            Verdict::Drop => {}
+            Verdict::Mute(until) => {
+                let secs = until.saturating_duration_since(now).as_secs();
+                info!(parent: &self.span, "over the message limits, muted for {}s", secs);
+                let data = SendData::new_error(
+                    ErrorCode::Muted,
+                    format!("muted for {secs} seconds, more messages disconnect"),
+                );
+                self.send_data(data, actor_ref).await;
+            }
+            Verdict::Disconnect => {
+                warn!(parent: &self.span, "over the message limits, disconnected");
+                self.outbox.kick();
+                self.disconnect(actor_ref).await;
+            }
+        }
+
+        false
     }
 
-    async fn handle_signal(&self, signal: SignalInfo) {
-        debug!("recv: signal message: {:?}", signal);
+    async fn handle_talk_to_user(&mut self, to: UserId, msg: String, actor_ref: ActorRef<Self>) {
+        let (msg, flags) = match self.relay.message_filters().check(&self.id, &to, msg) {
+            Checked::Deliver { msg, flags } => (msg, flags),
+            Checked::Reject(reason) => {
+                info!(parent: &self.span, "message to: {} rejected, {}", to, reason);
+                self.relay.metrics().message_dropped();
+                let data = SendData::new_error(ErrorCode::Rejected, reason);
+                self.send_data(data, actor_ref).await;
+                return;
+            }
+        };
 
-        self.chat_room
-            .tell(ForwordSignal(signal))
-            .send()
-            .await
-            .unwrap();
+        let msg = SendMsg {
+            from: self.get_id(),
+            to,
+            msg,
+        };
+        if !flags.is_empty() {
+            info!(parent: &self.span, "message to: {} flagged, {}", msg.to, flags.join(", "));
+            self.relay.flag(&msg, flags).await;
+        }
+        let to = msg.to.clone();
+        if !self.relay.send_msg(msg, &self.chat_room).await {
+            debug!(parent: &self.span, "message to: {} not delivered", to);
+        }
+    }
+
+    /// the blocked user is never told
+    async fn handle_block(&mut self, id: UserId, actor_ref: ActorRef<Self>) {
+        if self.relay.blocks.block(&self.id, id) {
+            return;
+        }
+
+        let data = SendData::new_error(
+            ErrorCode::BlockListFull,
+            format!("at most {MAX_BLOCKED} users could be blocked"),
+        );
+        self.send_data(data, actor_ref).await;
+    }
+
+    async fn handle_signal(&self, mut signal: SignalInfo) {
+        debug!(parent: &self.span, "received signal: {:?}", signal);
+
+        // the sender cannot pretend to be someone else
+        signal.from_id = self.get_id();
+
+        self.relay.metrics().chat_room_sent();
+        if let Err(e) = self.chat_room.tell(ForwordSignal(signal)).send().await {
+            error!(parent: &self.span, "signal not sent: {}", e);
+        }
     }
 }
 
@@ -221,16 +561,14 @@ impl Message<UserOnline> for User {
     async fn handle(
         &mut self,
         msg: UserOnline,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         if msg.0 == self.id {
             return;
         }
 
-        let data = SendData::new_user_online(msg.0, msg.1);
-        let data = json!(data).to_string();
-
-        self.sender.send(data).await.unwrap();
+        let data = SendData::new_user_online(msg.0, msg.1, msg.2);
+        self.send_presence(data, ctx.actor_ref()).await;
     }
 }
 
@@ -240,16 +578,14 @@ impl Message<UserDisconnection> for User {
     async fn handle(
         &mut self,
         msg: UserDisconnection,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         if msg.0 == self.id {
             return;
         }
 
         let data = SendData::new_user_offline(msg.0);
-        let data = json!(data).to_string();
-
-        self.sender.send(data).await.unwrap();
+        self.send_presence(data, ctx.actor_ref()).await;
     }
 }
 
@@ -259,16 +595,14 @@ impl Message<SetName> for User {
     async fn handle(
         &mut self,
         msg: SetName,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         if msg.0 == self.id {
             return;
         }
 
         let data = SendData::new_set_name(msg.0, msg.1);
-        let data = json!(data).to_string();
-
-        self.sender.send(data).await.unwrap();
+        self.send_presence(data, ctx.actor_ref()).await;
     }
 }
 
@@ -283,12 +617,26 @@ impl Message<NewMsg> for User {
     async fn handle(
         &mut self,
         msg: NewMsg,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         let data = SendData::new_msg(msg.msg, msg.from);
-        let data = json!(data).to_string();
+        self.send_data(data, ctx.actor_ref()).await;
+    }
+}
+
+#[derive(Clone)]
+pub struct NewNotice(pub String);
+
+impl Message<NewNotice> for User {
+    type Reply = ();
 
-        self.sender.send(data).await.unwrap();
+    async fn handle(
+        &mut self,
+        msg: NewNotice,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let data = SendData::new_notice(msg.0);
+        self.send_data(data, ctx.actor_ref()).await;
     }
 }
 
@@ -298,12 +646,498 @@ impl Message<ForwordSignal> for User {
     async fn handle(
         &mut self,
         msg: ForwordSignal,
-        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
     ) -> Self::Reply {
         if msg.0.to_id == self.id {
-            let data = json!(SendData::new_signal_forword(msg.0)).to_string();
+            let data = SendData::new_signal_forword(msg.0);
+            self.send_data(data, ctx.actor_ref()).await;
+        }
+    }
+}
+
+/// ping the client, and disconnect it once idle too long
+struct Heartbeat;
+
+impl Message<Heartbeat> for User {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: Heartbeat,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let heartbeat = self.relay.heartbeat().clone();
+        if let Some(idle_timeout) = heartbeat.idle_timeout {
+            if self.last_active.elapsed() >= idle_timeout {
+                info!(
+                    parent: &self.span,
+                    "idle for {:?}, disconnecting", idle_timeout
+                );
+                self.disconnect(ctx.actor_ref()).await;
+                return;
+            }
+        }
+
+        // the last one is still waiting for its deadline
+        if self.awaiting_pong.is_some() {
+            return;
+        }
+        self.pings += 1;
+        self.awaiting_pong = Some(self.pings);
+        self.send(Outgoing::Ping, ctx.actor_ref()).await;
 
-            self.sender.send(data).await.unwrap();
+        let (user, ping) = (ctx.actor_ref().downgrade(), self.pings);
+        tokio::spawn(async move {
+            tokio::time::sleep(heartbeat.pong_timeout).await;
+            if let Some(user) = user.upgrade() {
+                let _ = user.tell(PongDeadline(ping)).send().await;
+            }
+        });
+    }
+}
+
+struct PongDeadline(u64);
+
+impl Message<PongDeadline> for User {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: PongDeadline,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        if self.awaiting_pong == Some(msg.0) {
+            warn!(parent: &self.span, "did not answer the ping, disconnecting");
+            // a half-open connection may never finish sending
+            self.outbox.abort();
+            self.disconnect(ctx.actor_ref()).await;
         }
     }
 }
+
+impl Message<GoingAway> for User {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: GoingAway,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        debug!(parent: &self.span, "going away");
+
+        self.outbox.going_away().await;
+        let _ = ctx.actor_ref().stop_gracefully().await;
+    }
+}
+
+/// removed by an admin, the others are told it is offline
+pub struct Kick;
+
+impl Message<Kick> for User {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: Kick,
+        ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        info!(parent: &self.span, "kicked");
+
+        self.outbox.kick();
+        self.disconnect(ctx.actor_ref()).await;
+    }
+}
+
+#[cfg(test)]
+mod test_heartbeat {
+    use super::*;
+    use crate::{
+        limit::ConnectionLimits,
+        test_client::{http_get, refused, serve, upgrade, TestClient},
+        App,
+    };
+
+    fn app(idle_timeout: Option<Duration>) -> App {
+        App::new("", vec!["*".to_string()]).with_heartbeat(HeartbeatOptions {
+            ping_interval: Duration::from_millis(50),
+            pong_timeout: Duration::from_millis(100),
+            idle_timeout,
+            handshake_timeout: Duration::from_millis(200),
+        })
+    }
+
+    #[tokio::test]
+    async fn drop_half_open_connections() {
+        let addr = serve(app(None)).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            // never read again, so never answering a ping
+            let ghost = TestClient::connect(addr).await;
+
+            let offline = a
+                .recv_until(|data| data["msg_type"]["userOffline"]["id"] == ghost.id.as_str())
+                .await;
+            assert!(offline.is_some());
+
+            // reading, the pings are answered
+            let reading = a.recv_until(|_| false);
+            assert!(tokio::time::timeout(Duration::from_millis(300), reading)
+                .await
+                .is_err());
+            a.talk_to(&a.id.clone(), "still here").await;
+            assert!(a
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .is_some());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_silent_handshakes() {
+        let app = app(None)
+            .with_connection_limits(ConnectionLimits {
+                per_ip: 1,
+                new_per_ip: None,
+            })
+            .with_metrics(None);
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            // never sends its public key
+            let mut silent = upgrade(addr, "/ws").await;
+            assert_eq!(refused(addr, "/ws", &[]).await, Some(429));
+
+            while let Some(Ok(_)) = silent.next().await {}
+            // the slot is released
+            TestClient::connect(addr).await;
+            let (_, body) = http_get(addr, "/metrics", &[]).await;
+            assert!(
+                body.contains("nobody_chat_handshakes_failed_total 1"),
+                "{body}"
+            );
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnect_idle_users() {
+        let addr = serve(app(Some(Duration::from_millis(200)))).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut idle = TestClient::connect(addr).await;
+            // answering the pings is not enough
+            assert!(idle.recv_until(|_| false).await.is_none());
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_message_limits {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    use super::*;
+    use crate::{
+        limit::Rate,
+        test_client::{serve, Received, TestClient},
+        App,
+    };
+
+    #[tokio::test]
+    async fn warn_mute_then_disconnect() {
+        let app = App::new("", vec!["*".to_string()]).with_message_limits(Some(MessageLimits {
+            messages: Rate::new(3, Duration::from_secs(60)),
+            mute_after: 2,
+            disconnect_after: 4,
+            ..Default::default()
+        }));
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            for n in 0..7 {
+                a.talk_to(&b.id, &format!("message {n}")).await;
+            }
+
+            let mut errors = vec![];
+            let close = loop {
+                match a.recv().await {
+                    Received::Data(data) if data["msg_type"]["error"].is_object() => {
+                        errors.push(data["msg_type"]["error"]["code"].clone());
+                    }
+                    Received::Data(_) => continue,
+                    Received::Close(frame) => break frame,
+                }
+            };
+            assert_eq!(errors, ["rate_limited", "muted"]);
+            assert_eq!(close.unwrap().code, CloseCode::Policy);
+
+            let mut received = vec![];
+            loop {
+                let Received::Data(data) = b.recv().await else {
+                    panic!("b is closed");
+                };
+                if let Some(msg) = data["msg_type"]["msg"]["msg"].as_str() {
+                    received.push(msg.to_string());
+                }
+                if data["msg_type"]["userOffline"]["id"] == a.id.as_str() {
+                    break;
+                }
+            }
+            assert_eq!(received, ["message 0", "message 1", "message 2"]);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn signal_while_muted() {
+        let app = App::new("", vec!["*".to_string()]).with_message_limits(Some(MessageLimits {
+            messages: Rate::new(1, Duration::from_secs(60)),
+            mute_after: 1,
+            ..Default::default()
+        }));
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            a.talk_to(&b.id, "hi").await;
+            a.talk_to(&b.id, "hi again").await;
+            a.recv_until(|data| data["msg_type"]["error"]["code"] == "muted")
+                .await
+                .unwrap();
+
+            a.send(json!({ "msg_type": { "signal": {
+                "from_id": a.id,
+                "to_id": b.id,
+                "signal_type": "stop",
+                "value": "",
+            } } }))
+            .await;
+            let signal = b
+                .recv_until(|data| data["msg_type"]["signal"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(signal["msg_type"]["signal"]["signal_type"], "stop");
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_message_filter {
+    use super::*;
+    use crate::{
+        filter::{FilterAction, LinkFilter, WordList},
+        handle::ChatEvent,
+        test_client::{serve_routes, TestClient},
+        App,
+    };
+
+    #[tokio::test]
+    async fn mask_reject_and_flag() {
+        let (routes, chat) = App::new("", vec!["*".to_string()])
+            .with_message_filter(WordList::new(["darn"], FilterAction::Mask))
+            .with_message_filter(LinkFilter::new(FilterAction::Reject))
+            .with_message_filter(WordList::new(["refund"], FilterAction::Flag))
+            .build()
+            .unwrap();
+        let addr = serve_routes(routes).await;
+        let mut events = chat.subscribe();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            a.talk_to(&b.id, "darn it").await;
+            a.talk_to(&b.id, "see https://example.com").await;
+            a.talk_to(&b.id, "a refund please").await;
+
+            let error = a
+                .recv_until(|data| data["msg_type"]["error"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(error["msg_type"]["error"]["code"], "rejected");
+            assert_eq!(error["msg_type"]["error"]["msg"], "a link not allowed");
+
+            let msg = b
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "**** it");
+            // the rejected message never arrives
+            let msg = b
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "a refund please");
+
+            loop {
+                match events.next().await.unwrap() {
+                    ChatEvent::Flagged {
+                        from,
+                        to,
+                        msg,
+                        reasons,
+                    } => {
+                        assert_eq!((from, to), (a.id.clone(), b.id.clone()));
+                        assert_eq!(msg.into_inner(), "a refund please");
+                        assert_eq!(reasons, ["a word not allowed"]);
+                        break;
+                    }
+                    ChatEvent::Message { msg, .. } => {
+                        assert_ne!(msg.into_inner(), "see https://example.com")
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_block_list {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{
+        limit::Rate,
+        signal::SignalPolicy,
+        test_client::{serve, TestClient},
+        App,
+    };
+
+    /// every frame of `client` is handled once it receives its own message
+    async fn synced(client: &mut TestClient) {
+        let id = client.id.clone();
+        client.talk_to(&id, "synced").await;
+        client
+            .recv_until(|data| data["msg_type"]["msg"]["msg"] == "synced")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_messages_and_deny_calls() {
+        let addr = serve(App::new("", vec!["*".to_string()])).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            a.send(json!({ "msg_type": { "block": { "id": b.id } } }))
+                .await;
+            synced(&mut a).await;
+
+            b.talk_to(&a.id, "blocked").await;
+            b.send(json!({ "msg_type": { "signal": {
+                "from_id": b.id,
+                "to_id": a.id,
+                "signal_type": "requestCall",
+                "value": "",
+                "call": { "media": "audio", "capabilities": ["audio"] },
+            } } }))
+            .await;
+            // denied the same as by the UI
+            let deny = b
+                .recv_until(|data| data["msg_type"]["signal"]["signal_type"] == "deny")
+                .await
+                .unwrap();
+            assert_eq!(deny["msg_type"]["signal"]["from_id"], a.id.as_str());
+            assert_eq!(deny["msg_type"]["signal"]["value"], "");
+
+            a.send(json!({ "msg_type": { "unblock": { "id": b.id } } }))
+                .await;
+            synced(&mut a).await;
+            b.talk_to(&a.id, "unblocked").await;
+            let msg = a
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "unblocked");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuse_over_the_limits_the_same() {
+        let mut policy = SignalPolicy::default();
+        policy.limits.calls_per_sender = Rate::new(1, Duration::from_secs(60));
+        let addr = serve(App::new("", vec!["*".to_string()]).with_signal_policy(policy)).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut caller = TestClient::connect(addr).await;
+            let mut blocker = TestClient::connect(addr).await;
+            let mut other = TestClient::connect(addr).await;
+            blocker
+                .send(json!({ "msg_type": { "block": { "id": caller.id } } }))
+                .await;
+            synced(&mut blocker).await;
+
+            let caller_id = caller.id.clone();
+            let call = |to: &UserId| {
+                json!({ "msg_type": { "signal": {
+                    "from_id": caller_id,
+                    "to_id": to,
+                    "signal_type": "requestCall",
+                    "value": "",
+                    "call": { "media": "audio", "capabilities": ["audio"] },
+                } } })
+            };
+            let denied_by = |id: UserId| {
+                move |data: &Value| {
+                    data["msg_type"]["signal"]["signal_type"] == "deny"
+                        && data["msg_type"]["signal"]["from_id"] == id.as_str()
+                }
+            };
+            // the only call of the caller
+            caller.send(call(&other.id)).await;
+            other
+                .recv_until(|data| data["msg_type"]["signal"]["signal_type"] == "requestCall")
+                .await
+                .unwrap();
+
+            caller.send(call(&blocker.id)).await;
+            let by_blocker = caller
+                .recv_until(denied_by(blocker.id.clone()))
+                .await
+                .unwrap();
+            caller.send(call(&other.id)).await;
+            let by_other = caller
+                .recv_until(denied_by(other.id.clone()))
+                .await
+                .unwrap();
+
+            let value = &by_other["msg_type"]["signal"]["value"];
+            assert_ne!(value, "");
+            assert_eq!(by_blocker["msg_type"]["signal"]["value"], *value);
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -31,8 +31,8 @@ impl EncryptDecrypt for ChaCha {
         self.cipher.encrypt(&self.nonce, data).unwrap()
     }
 
-    fn decrypt(&self, data: &[u8]) -> Vec<u8> {
-        self.cipher.decrypt(&self.nonce, data).unwrap()
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        self.cipher.decrypt(&self.nonce, data).ok()
     }
 
     fn split(self) -> (impl SplitedEncrypt, impl SplitedDecrypt) {
@@ -62,7 +62,7 @@ pub struct ChaChaDecrypt {
 }
 
 impl SplitedDecrypt for ChaChaDecrypt {
-    fn decrypt(&self, data: &[u8]) -> Vec<u8> {
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
         self.cipher.decrypt(data)
     }
 }
@@ -107,7 +107,7 @@ mod encrypt_decrypt_tests {
         const EXPECTED_PLAIN_TEXT: &[u8; 17] = b"plaintext message";
 
         let chacha = ChaCha::new(KEY);
-        let plain_text = chacha.decrypt(&EXPECTED_CIPHER_TEXT);
+        let plain_text = chacha.decrypt(&EXPECTED_CIPHER_TEXT).unwrap();
 
         let len = plain_text
             .iter()
@@ -117,6 +117,8 @@ mod encrypt_decrypt_tests {
 
         assert_eq!(len, plain_text.len());
         assert_eq!(len, EXPECTED_PLAIN_TEXT.len());
+
+        assert!(chacha.decrypt(b"not encrypted by the key").is_none());
     }
 }
 
@@ -150,7 +152,7 @@ mod split_tests {
         assert_eq!(count, cipher_text.len());
         assert_eq!(count, EXPECTED_CIPHER_TEXT.len());
 
-        let plain_text = decrypt.decrypt(&EXPECTED_CIPHER_TEXT);
+        let plain_text = decrypt.decrypt(&EXPECTED_CIPHER_TEXT).unwrap();
 
         let count = plain_text
             .iter()
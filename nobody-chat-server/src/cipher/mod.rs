@@ -6,7 +6,8 @@ where
 {
     fn encrypt(&self, data: &[u8]) -> Vec<u8>;
 
-    fn decrypt(&self, data: &[u8]) -> Vec<u8>;
+    /// `None` if the data is not encrypted by the shared key
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>>;
 
     fn split(self) -> (impl SplitedEncrypt, impl SplitedDecrypt);
 }
@@ -22,5 +23,6 @@ pub trait SplitedDecrypt: Unpin
 where
     Self: Send + Sync,
 {
-    fn decrypt(&self, data: &[u8]) -> Vec<u8>;
+    /// `None` if the data is not encrypted by the shared key
+    fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>>;
 }
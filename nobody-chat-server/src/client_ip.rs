@@ -0,0 +1,303 @@
+//! the address of a client, behind the reverse proxies trusted to tell it
+
+use std::{
+    convert::Infallible,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::FORWARDED, request::Parts, HeaderMap},
+};
+use tracing::warn;
+
+/// an address or a network, e.g. `10.0.0.1`, `172.16.0.0/12` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("`{s}` is not an address or a network: {e}"))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("`{s}` has a prefix length not in 0..={max}"))?,
+            None => max,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// the reverse proxies whose `Forwarded` or `X-Forwarded-For` is believed,
+/// none by default as anyone can send these headers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpNet>) -> Self {
+        Self(proxies)
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+
+    /// the client of a request from `peer`: the peer itself unless it is trusted,
+    /// else the last hop of the forwarding headers not trusted, read right to left.
+    /// `Forwarded` is preferred to `X-Forwarded-For`
+    pub fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer.ip().to_canonical();
+        if !self.is_trusted(&client) {
+            return client;
+        }
+
+        let hops = if headers.contains_key(FORWARDED) {
+            forwarded_for(headers)
+        } else {
+            x_forwarded_for(headers)
+        };
+        for hop in hops.iter().rev() {
+            // an obfuscated or `unknown` hop, the trusted proxy behind it is all we know
+            let Some(ip) = hop else {
+                break;
+            };
+            client = *ip;
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// the peer of a request, and the client behind the trusted proxies;
+/// none if the embedding application serves without connect info,
+/// then the bans and the connection limits cannot be applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientAddr {
+    pub remote: Option<SocketAddr>,
+    pub ip: Option<IpAddr>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientAddr {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let remote = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+        if remote.is_none() {
+            warn_no_connect_info();
+        }
+        let proxies = parts.extensions.get::<Arc<TrustedProxies>>();
+        let ip = remote.map(|remote| match proxies {
+            Some(proxies) => proxies.client_ip(remote, &parts.headers),
+            None => remote.ip().to_canonical(),
+        });
+
+        Ok(Self { remote, ip })
+    }
+}
+
+/// once, not to flood the logs by every request
+fn warn_no_connect_info() {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    if !WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "no connect info, the bans and the connection limits are skipped, \
+            serve with `into_make_service_with_connect_info::<SocketAddr>()`"
+        );
+    }
+}
+
+/// the `for` of every element of every `Forwarded`, in order
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then(|| parse_node(value))
+            })?
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// `192.0.2.43`, `"192.0.2.43:4711"`, `"[2001:db8::17]:4711"` or `2001:db8::17`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    let ip = IpAddr::from_str(node)
+        .or_else(|_| SocketAddr::from_str(node).map(|addr| addr.ip()))
+        .or_else(|_| IpAddr::from_str(node.trim_start_matches('[').trim_end_matches(']')))
+        .ok()?;
+
+    Some(ip.to_canonical())
+}
+
+#[cfg(test)]
+mod test_client_ip {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn proxies(nets: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(nets.iter().map(|net| net.parse().unwrap()).collect())
+    }
+
+    fn header_map(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 51234)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn contain_networks() {
+        let net: IpNet = "172.16.0.0/12".parse().unwrap();
+        assert!(net.contains(&ip("172.31.255.1")));
+        assert!(!net.contains(&ip("172.32.0.1")));
+        assert!(net.contains(&ip("::ffff:172.16.0.1")));
+
+        let net: IpNet = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&ip("fd12::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+
+        assert!("0.0.0.0/0"
+            .parse::<IpNet>()
+            .unwrap()
+            .contains(&ip("8.8.8.8")));
+        assert_eq!(
+            "10.0.0.1".parse::<IpNet>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("proxy".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn ignore_headers_of_untrusted_peers() {
+        let headers = header_map(&[("x-forwarded-for", "198.51.100.1")]);
+        assert_eq!(
+            TrustedProxies::default().client_ip(peer("203.0.113.7"), &headers),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            proxies(&["10.0.0.0/8"]).client_ip(peer("203.0.113.7"), &headers),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn skip_trusted_hops() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        // a client spoofing the first hop is not believed
+        let headers = header_map(&[
+            ("x-forwarded-for", "1.1.1.1, 198.51.100.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            proxies.client_ip(peer("10.0.0.1"), &headers),
+            ip("198.51.100.1")
+        );
+
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            proxies.client_ip(peer("10.0.0.1"), &headers),
+            ip("10.0.0.3")
+        );
+
+        assert_eq!(
+            proxies.client_ip(peer("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn prefer_forwarded() {
+        let proxies = proxies(&["10.0.0.0/8"]);
+        let headers = header_map(&[
+            ("x-forwarded-for", "1.1.1.1"),
+            (
+                "forwarded",
+                r#"for="[2001:db8:cafe::17]:4711";proto=https, for=10.0.0.2;by=10.0.0.1"#,
+            ),
+        ]);
+        assert_eq!(
+            proxies.client_ip(peer("10.0.0.1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+
+        let headers = header_map(&[("forwarded", "for=unknown, for=10.0.0.2")]);
+        assert_eq!(
+            proxies.client_ip(peer("10.0.0.1"), &headers),
+            ip("10.0.0.2")
+        );
+    }
+}
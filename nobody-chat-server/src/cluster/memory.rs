@@ -0,0 +1,50 @@
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use super::{ClusterMsg, MessageBus};
+use crate::error::Result;
+
+/// the nodes in one process share a clone, for tests and embedding
+#[derive(Clone)]
+pub struct MemoryBus {
+    tx: broadcast::Sender<ClusterMsg>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(1024);
+        Self { tx }
+    }
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    async fn publish(&self, msg: &ClusterMsg) -> Result<()> {
+        // nobody subscribed yet
+        let _ = self.tx.send(msg.clone());
+        Ok(())
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, ClusterMsg> {
+        stream::unfold(self.tx.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(msg) => return Some((msg, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("memory bus subscriber lagged, {} messages skipped", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
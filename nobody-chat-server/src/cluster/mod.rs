@@ -0,0 +1,344 @@
+mod memory;
+mod redis;
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_util::{stream::BoxStream, StreamExt};
+use kameo::{actor::ActorRef, request::MessageSend};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{
+    chat::{ChatRoom, ClusterTick},
+    error::Result,
+    logging::Redacted,
+    metrics::Metrics,
+    models::UserId,
+    signal::SignalInfo,
+};
+
+pub use memory::MemoryBus;
+pub use redis::RedisBus;
+
+pub type NodeId = String;
+
+/// what the nodes tell each other, about the users connected to the sender
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ClusterBody {
+    /// the node is alive, a node never seen before, or restarted, is told every user
+    Heartbeat,
+    /// the node is shutting down, its users are offline
+    Leave,
+    Online {
+        id: UserId,
+        name: String,
+        bot: bool,
+    },
+    Offline {
+        id: UserId,
+    },
+    Renamed {
+        id: UserId,
+        name: String,
+    },
+    /// a message to a user of another node
+    Msg {
+        from: UserId,
+        to: UserId,
+        msg: Redacted<String>,
+    },
+    /// checked by the node of the sender already
+    Signal(SignalInfo),
+    Notice {
+        msg: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClusterMsg {
+    pub node: NodeId,
+    /// random every process, so a node restarted under the same id is noticed
+    #[serde(default)]
+    pub incarnation: String,
+    pub body: ClusterBody,
+}
+
+/// how the nodes of a cluster reach each other
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    async fn publish(&self, msg: &ClusterMsg) -> Result<()>;
+    /// every message published from now on, the own ones included
+    fn subscribe(&self) -> BoxStream<'static, ClusterMsg>;
+}
+
+/// run the chat room as a node of a cluster, sharing the presence and routing
+/// the messages and signals to the users of other nodes
+#[derive(Clone)]
+pub struct ClusterOptions {
+    /// unique in the cluster, random by default
+    pub node: NodeId,
+    pub bus: Arc<dyn MessageBus>,
+    /// a node not heard for 3 heartbeats is gone, with its users
+    pub heartbeat: Duration,
+}
+
+impl ClusterOptions {
+    pub fn new(bus: Arc<dyn MessageBus>) -> Self {
+        Self {
+            node: Uuid::new_v4().simple().to_string(),
+            bus,
+            heartbeat: Duration::from_secs(5),
+        }
+    }
+}
+
+/// the side of the chat room towards the bus, never blocking on it
+pub(crate) struct ClusterLink {
+    pub node: NodeId,
+    pub heartbeat: Duration,
+    outbox: mpsc::UnboundedSender<ClusterBody>,
+}
+
+impl ClusterLink {
+    /// queue `body` for publishing, in order
+    pub fn publish(&self, body: ClusterBody) {
+        if self.outbox.send(body).is_err() {
+            error!("cluster publisher of node: {} is stopped", self.node);
+        }
+    }
+}
+
+/// the link of the chat room, and a task connecting it to the bus once spawned
+pub(crate) fn link(
+    options: ClusterOptions,
+) -> (
+    ClusterLink,
+    impl FnOnce(ActorRef<ChatRoom>, Arc<Metrics>) + Send + 'static,
+) {
+    let (outbox, mut outbox_rx) = mpsc::unbounded_channel();
+    let link = ClusterLink {
+        node: options.node.clone(),
+        heartbeat: options.heartbeat,
+        outbox,
+    };
+
+    let start = move |chat_room: ActorRef<ChatRoom>, metrics: Arc<Metrics>| {
+        let ClusterOptions {
+            node,
+            bus,
+            heartbeat,
+        } = options;
+
+        let (publish_bus, publish_node) = (bus.clone(), node.clone());
+        let incarnation = Uuid::new_v4().simple().to_string();
+        tokio::spawn(async move {
+            while let Some(body) = outbox_rx.recv().await {
+                let msg = ClusterMsg {
+                    node: publish_node.clone(),
+                    incarnation: incarnation.clone(),
+                    body,
+                };
+                if let Err(e) = publish_bus.publish(&msg).await {
+                    error!("publishing to the cluster failed: {}", e);
+                }
+            }
+        });
+
+        let mut inbound = bus.subscribe();
+        let (inbound_room, inbound_metrics) = (chat_room.clone(), metrics.clone());
+        tokio::spawn(async move {
+            while let Some(msg) = inbound.next().await {
+                if msg.node == node {
+                    continue;
+                }
+                inbound_metrics.chat_room_sent();
+                if inbound_room.tell(msg).send().await.is_err() {
+                    break;
+                }
+            }
+            debug!("cluster inbound of node: {} stopped", node);
+        });
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(heartbeat);
+            loop {
+                ticks.tick().await;
+                metrics.chat_room_sent();
+                if chat_room.tell(ClusterTick).send().await.is_err() {
+                    break;
+                }
+            }
+        });
+    };
+
+    (link, start)
+}
+
+#[cfg(test)]
+mod test_cluster {
+    use super::*;
+    use crate::{
+        test_client::{online_users, serve_routes, TestClient},
+        App, ChatHandle,
+    };
+    use serde_json::{json, Value};
+    use std::net::SocketAddr;
+
+    async fn node(bus: &Arc<MemoryBus>, name: &str) -> (SocketAddr, ChatHandle) {
+        let (routes, chat) = App::new("", vec!["*".to_string()])
+            .with_cluster(ClusterOptions {
+                node: name.to_string(),
+                heartbeat: Duration::from_millis(50),
+                ..ClusterOptions::new(bus.clone())
+            })
+            .build()
+            .unwrap();
+
+        (serve_routes(routes).await, chat)
+    }
+
+    fn is(kind: &'static str, id: &str) -> impl Fn(&Value) -> bool {
+        let id = id.to_string();
+        move |data| data["msg_type"][kind]["id"] == id.as_str()
+    }
+
+    #[tokio::test]
+    async fn route_across_nodes() {
+        let bus = Arc::new(MemoryBus::new());
+        let (addr_one, chat_one) = node(&bus, "one").await;
+        let (addr_two, _) = node(&bus, "two").await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr_one).await;
+            let mut b = TestClient::connect(addr_two).await;
+            a.recv_until(is("userOnline", &b.id)).await.unwrap();
+            // the users of node one before b joined are listed, not pushed
+            let mut both = vec![a.id.clone(), b.id.clone()];
+            both.sort();
+            loop {
+                let mut online = online_users(addr_two).await;
+                online.sort();
+                if online == both {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            a.talk_to(&b.id, "hi").await;
+            let msg = b
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["from"], a.id.as_str());
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "hi");
+
+            b.send(json!({ "msg_type": { "signal": {
+                "from_id": "someone else",
+                "to_id": a.id,
+                "signal_type": "stop",
+                "value": "",
+            } } }))
+            .await;
+            let signal = a
+                .recv_until(|data| data["msg_type"]["signal"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(signal["msg_type"]["signal"]["from_id"], b.id.as_str());
+
+            chat_one
+                .broadcast_notice("maintenance at noon")
+                .await
+                .unwrap();
+            let notice = b
+                .recv_until(|data| data["msg_type"]["notice"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(notice["msg_type"]["notice"]["msg"], "maintenance at noon");
+
+            let b_id = b.id.clone();
+            drop(b);
+            a.recv_until(is("userOffline", &b_id)).await.unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn forget_silent_nodes() {
+        let bus = Arc::new(MemoryBus::new());
+        let (addr, _) = node(&bus, "one").await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            bus.publish(&ClusterMsg {
+                node: "ghost".to_string(),
+                incarnation: "once".to_string(),
+                body: ClusterBody::Online {
+                    id: "ghost-user".to_string(),
+                    name: "ghost".to_string(),
+                    bot: false,
+                },
+            })
+            .await
+            .unwrap();
+            a.recv_until(is("userOnline", "ghost-user")).await.unwrap();
+
+            // no heartbeat of the ghost ever
+            a.recv_until(is("userOffline", "ghost-user")).await.unwrap();
+            assert_eq!(online_users(addr).await, vec![a.id.clone()]);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn tell_restarted_nodes() {
+        let bus = Arc::new(MemoryBus::new());
+        let (addr, _) = node(&bus, "one").await;
+        let mut inbound = bus.subscribe();
+        let restartable = |incarnation: &str, body: ClusterBody| ClusterMsg {
+            node: "two".to_string(),
+            incarnation: incarnation.to_string(),
+            body,
+        };
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let online = ClusterBody::Online {
+                id: "user-of-two".to_string(),
+                name: "two".to_string(),
+                bot: false,
+            };
+            bus.publish(&restartable("first", online)).await.unwrap();
+            a.recv_until(is("userOnline", "user-of-two")).await.unwrap();
+
+            // back before it is missed, under the same node id
+            bus.publish(&restartable("second", ClusterBody::Heartbeat))
+                .await
+                .unwrap();
+            a.recv_until(is("userOffline", "user-of-two"))
+                .await
+                .unwrap();
+
+            let announced = ClusterBody::Online {
+                id: a.id.clone(),
+                name: a.id[..5].to_string(),
+                bot: false,
+            };
+            // once connected, once two joined, once two restarted
+            let mut times = 0;
+            while times < 3 {
+                let msg = inbound.next().await.unwrap();
+                if msg.node == "one" && msg.body == announced {
+                    times += 1;
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
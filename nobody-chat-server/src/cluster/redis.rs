@@ -0,0 +1,417 @@
+use std::{future::Future, io, pin::Pin, time::Duration};
+
+use async_stream::stream;
+use async_trait::async_trait;
+use futures_util::stream::{BoxStream, StreamExt};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    sync::Mutex,
+};
+use tracing::{error, warn};
+
+use super::{ClusterMsg, MessageBus};
+use crate::error::{Error, Result};
+
+/// Redis pub/sub, or anything speaking its protocol, every node on one channel
+pub struct RedisBus {
+    addr: String,
+    channel: String,
+    password: Option<String>,
+    /// of publishing, subscribing takes a connection of its own
+    conn: Mutex<Option<BufStream<TcpStream>>>,
+}
+
+impl RedisBus {
+    /// connects once used, and again after failures
+    pub fn new(addr: impl Into<String>, channel: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            channel: channel.into(),
+            password: None,
+            conn: Mutex::new(None),
+        }
+    }
+
+    pub fn with_password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    async fn connect(&self) -> io::Result<BufStream<TcpStream>> {
+        connect(&self.addr, self.password.as_deref()).await
+    }
+
+    async fn try_publish(&self, payload: &[u8]) -> io::Result<()> {
+        let mut conn = self.conn.lock().await;
+        let stream = match conn.as_mut() {
+            Some(stream) => stream,
+            None => conn.insert(self.connect().await?),
+        };
+
+        let res = async {
+            write_command(stream, &[b"PUBLISH", self.channel.as_bytes(), payload]).await?;
+            match read_value(stream).await? {
+                Resp::Error(e) => Err(io::Error::other(e)),
+                _ => Ok(()),
+            }
+        }
+        .await;
+        if res.is_err() {
+            // reconnect next time
+            *conn = None;
+        }
+
+        res
+    }
+}
+
+#[async_trait]
+impl MessageBus for RedisBus {
+    async fn publish(&self, msg: &ClusterMsg) -> Result<()> {
+        let payload = serde_json::to_vec(msg).expect("serialize cluster message");
+
+        // once more on a new connection, the old one may be closed by the server
+        if let Err(e) = self.try_publish(&payload).await {
+            warn!("publishing to redis failed, retrying: {}", e);
+            self.try_publish(&payload).await.map_err(Error::Bus)?;
+        }
+
+        Ok(())
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, ClusterMsg> {
+        let (addr, channel, password) = (
+            self.addr.clone(),
+            self.channel.clone(),
+            self.password.clone(),
+        );
+
+        stream! {
+            loop {
+                let res = async {
+                    let mut stream = connect(&addr, password.as_deref()).await?;
+                    write_command(&mut stream, &[b"SUBSCRIBE", channel.as_bytes()]).await?;
+                    Ok::<_, io::Error>(stream)
+                }
+                .await;
+
+                match res {
+                    Ok(mut stream) => loop {
+                        match read_value(&mut stream).await {
+                            Ok(Resp::Array(items)) => {
+                                let [kind, _, payload] = items.as_slice() else {
+                                    continue;
+                                };
+                                if !matches!(kind, Resp::Bulk(Some(kind)) if kind == b"message") {
+                                    continue;
+                                }
+                                let Resp::Bulk(Some(payload)) = payload else {
+                                    continue;
+                                };
+                                match serde_json::from_slice(payload) {
+                                    Ok(msg) => yield msg,
+                                    Err(e) => warn!("unknown cluster message: {}", e),
+                                }
+                            }
+                            Ok(_) => continue,
+                            Err(e) => {
+                                error!("redis subscription lost: {}", e);
+                                break;
+                            }
+                        }
+                    },
+                    Err(e) => error!("subscribing redis at {} failed: {}", addr, e),
+                }
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+        .boxed()
+    }
+}
+
+async fn connect(addr: &str, password: Option<&str>) -> io::Result<BufStream<TcpStream>> {
+    let mut stream = BufStream::new(TcpStream::connect(addr).await?);
+
+    if let Some(password) = password {
+        write_command(&mut stream, &[b"AUTH", password.as_bytes()]).await?;
+        if let Resp::Error(e) = read_value(&mut stream).await? {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, e));
+        }
+    }
+
+    Ok(stream)
+}
+
+#[derive(Debug, PartialEq)]
+enum Resp {
+    Simple(String),
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Resp>),
+}
+
+/// a command is an array of bulk strings
+async fn write_command<W: AsyncWrite + Unpin>(w: &mut W, args: &[&[u8]]) -> io::Result<()> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+
+    w.write_all(&buf).await?;
+    w.flush().await
+}
+
+/// of a bulk string, far above any cluster message
+const MAX_BULK: usize = 16 * 1024 * 1024;
+/// items of an array, a pub/sub message has 3
+const MAX_ARRAY: usize = 1024;
+/// arrays in arrays
+const MAX_DEPTH: usize = 4;
+/// of the line of a type and length, or of a simple string or an error
+const MAX_LINE: u64 = 64 * 1024;
+
+/// a reply bigger than the caps is `InvalidData`, so a bad peer cannot take the memory
+fn read_value<R>(r: &mut R) -> Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + '_>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    read_nested(r, 0)
+}
+
+fn read_nested<R>(
+    r: &mut R,
+    depth: usize,
+) -> Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + '_>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    Box::pin(async move {
+        let mut line = String::new();
+        if (&mut *r).take(MAX_LINE).read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+        let line = line
+            .strip_suffix("\r\n")
+            .ok_or_else(|| invalid("reply line too long or unterminated"))?;
+        let (kind, rest) = (
+            line.get(..1).ok_or_else(|| invalid("invalid reply"))?,
+            &line[1..],
+        );
+        let len = |max: usize| -> io::Result<Option<usize>> {
+            let len: i64 = rest.parse().map_err(|_| invalid("invalid reply"))?;
+            match usize::try_from(len) {
+                Err(_) => Ok(None),
+                Ok(len) if len > max => Err(invalid("reply too large")),
+                Ok(len) => Ok(Some(len)),
+            }
+        };
+
+        match kind {
+            "+" => Ok(Resp::Simple(rest.to_string())),
+            "-" => Ok(Resp::Error(rest.to_string())),
+            ":" => Ok(Resp::Int(
+                rest.parse().map_err(|_| invalid("invalid reply"))?,
+            )),
+            "$" => {
+                let Some(len) = len(MAX_BULK)? else {
+                    return Ok(Resp::Bulk(None));
+                };
+                let mut data = vec![0; len + 2];
+                r.read_exact(&mut data).await?;
+                data.truncate(len);
+                Ok(Resp::Bulk(Some(data)))
+            }
+            "*" => {
+                if depth >= MAX_DEPTH {
+                    return Err(invalid("reply nested too deep"));
+                }
+                let len = len(MAX_ARRAY)?.unwrap_or_default();
+                let mut items = Vec::with_capacity(len);
+                for _ in 0..len {
+                    items.push(read_nested(r, depth + 1).await?);
+                }
+                Ok(Resp::Array(items))
+            }
+            _ => Err(invalid("invalid reply")),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test_redis {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::{
+        io::{split, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::cluster::ClusterBody;
+
+    type Subscribers = Arc<std::sync::Mutex<HashMap<Vec<u8>, Vec<mpsc::UnboundedSender<Vec<u8>>>>>>;
+
+    fn bulk(data: &[u8]) -> Vec<u8> {
+        let mut buf = format!("${}\r\n", data.len()).into_bytes();
+        buf.extend_from_slice(data);
+        buf.extend_from_slice(b"\r\n");
+        buf
+    }
+
+    /// the pub/sub of Redis, requiring the password `secret`
+    async fn stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let subscribers = Subscribers::default();
+
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let subscribers = subscribers.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = split(conn);
+                    let mut reader = BufReader::new(reader);
+                    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                    tokio::spawn(async move {
+                        while let Some(buf) = rx.recv().await {
+                            if writer.write_all(&buf).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+
+                    while let Ok(Resp::Array(args)) = read_value(&mut reader).await {
+                        let args: Vec<Vec<u8>> = args
+                            .into_iter()
+                            .filter_map(|arg| match arg {
+                                Resp::Bulk(Some(arg)) => Some(arg),
+                                _ => None,
+                            })
+                            .collect();
+                        match args.first().map(|cmd| cmd.as_slice()) {
+                            Some(b"AUTH") if args[1] == b"secret" => {
+                                let _ = tx.send(b"+OK\r\n".to_vec());
+                            }
+                            Some(b"AUTH") => {
+                                let _ = tx.send(b"-WRONGPASS invalid password\r\n".to_vec());
+                            }
+                            Some(b"SUBSCRIBE") => {
+                                let channel = args[1].clone();
+                                let mut reply = b"*3\r\n".to_vec();
+                                reply.extend(bulk(b"subscribe"));
+                                reply.extend(bulk(&channel));
+                                reply.extend(b":1\r\n");
+                                let _ = tx.send(reply);
+                                subscribers
+                                    .lock()
+                                    .unwrap()
+                                    .entry(channel)
+                                    .or_default()
+                                    .push(tx.clone());
+                            }
+                            Some(b"PUBLISH") => {
+                                let mut message = b"*3\r\n".to_vec();
+                                message.extend(bulk(b"message"));
+                                message.extend(bulk(&args[1]));
+                                message.extend(bulk(&args[2]));
+                                let mut subscribers = subscribers.lock().unwrap();
+                                let receivers = subscribers.entry(args[1].clone()).or_default();
+                                receivers.retain(|rx| rx.send(message.clone()).is_ok());
+                                let _ = tx.send(format!(":{}\r\n", receivers.len()).into_bytes());
+                            }
+                            _ => {
+                                let _ = tx.send(b"-ERR unknown command\r\n".to_vec());
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn msg(node: &str) -> ClusterMsg {
+        ClusterMsg {
+            node: node.to_string(),
+            incarnation: "once".to_string(),
+            body: ClusterBody::Online {
+                id: format!("user-of-{node}"),
+                name: "名字".to_string(),
+                bot: false,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn refuse_huge_replies() {
+        for reply in [
+            format!("${}\r\n", u64::MAX),
+            format!("${}\r\n", MAX_BULK + 1),
+            format!("*{}\r\n", MAX_ARRAY + 1),
+            "*1\r\n".repeat(MAX_DEPTH + 1),
+            "+".repeat(MAX_LINE as usize + 1),
+        ] {
+            let mut r = BufReader::new(reply.as_bytes());
+            let e = read_value(&mut r).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{e}");
+        }
+
+        let mut r = BufReader::new(&b"*2\r\n$-1\r\n*1\r\n:7\r\n"[..]);
+        assert_eq!(
+            read_value(&mut r).await.unwrap(),
+            Resp::Array(vec![Resp::Bulk(None), Resp::Array(vec![Resp::Int(7)])])
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_and_subscribe() {
+        let addr = stand_in().await;
+        let a = RedisBus::new(&addr, "nobody-chat").with_password("secret");
+        let b = RedisBus::new(&addr, "nobody-chat").with_password("secret");
+        let other_channel = RedisBus::new(&addr, "elsewhere").with_password("secret");
+
+        let mut a_inbound = a.subscribe();
+        let mut b_inbound = b.subscribe();
+        let mut other_inbound = other_channel.subscribe();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            // again until subscribed
+            for (from, node, to) in [(&a, "a", &mut b_inbound), (&b, "b", &mut a_inbound)] {
+                loop {
+                    from.publish(&msg(node)).await.unwrap();
+                    let received = tokio::time::timeout(Duration::from_millis(50), async {
+                        while let Some(received) = to.next().await {
+                            if received == msg(node) {
+                                return;
+                            }
+                        }
+                    });
+                    if received.await.is_ok() {
+                        break;
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), other_inbound.next())
+                .await
+                .is_err()
+        );
+
+        let wrong = RedisBus::new(&addr, "nobody-chat").with_password("wrong");
+        assert!(matches!(
+            wrong.publish(&msg("wrong")).await,
+            Err(Error::Bus(_))
+        ));
+    }
+}
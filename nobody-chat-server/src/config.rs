@@ -0,0 +1,1238 @@
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{HeaderValue, Uri};
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    client_ip::{IpNet, TrustedProxies},
+    cluster::{ClusterOptions, RedisBus},
+    filter::{FilterAction, LinkFilter, MaxLength, WordList},
+    limit::{ConnectionLimits, MessageLimits, Rate},
+    logging::LogFormat,
+    signal::{MediaType, SignalLimits, SignalPolicy},
+    tls::TlsOptions,
+    webhook::{WebhookOptions, MAX_ATTEMPTS},
+    App, CallLog, HeartbeatOptions, OutboxOptions, SlowConsumer,
+};
+
+/// prefix of the environment variables overriding the config file,
+/// e.g. `NOBODY_CHAT__SERVER__ADDR=0.0.0.0:3000` overrides `addr` of `[server]`
+pub const ENV_PREFIX: &str = "NOBODY_CHAT__";
+
+/// the whole configuration of the server, layered by:
+/// defaults < config file < environment variables < command line arguments
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub connections: ConnectionsConfig,
+    pub message_limits: MessageLimitsConfig,
+    pub message_filter: MessageFilterConfig,
+    pub log: LogConfig,
+    pub stun: StunConfig,
+    pub signal: SignalConfig,
+    pub call_log: CallLogConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub tls: TlsConfig,
+    pub cluster: ClusterConfig,
+    /// `[[bots]]`, the accounts connecting to `/bot/ws`
+    pub bots: Vec<BotConfig>,
+    /// `[[webhooks]]`, receiving the presence events and the messages opted in
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// listen address
+    pub addr: String,
+    /// CORS and WebSocket allowed origins of the UI, `*` for any
+    pub allow_origins: Vec<String>,
+    /// on SIGTERM or Ctrl-C, how long the users have to flush the messages in flight
+    pub shutdown_timeout_secs: u64,
+    /// frames waiting to be sent to each user
+    pub outbound_queue: usize,
+    /// once the queue of a user is full, `drop_presence` or `disconnect`
+    pub slow_consumer: SlowConsumer,
+    /// every client is pinged this often
+    pub ping_interval_secs: u64,
+    /// a client not answering a ping in time is disconnected
+    pub pong_timeout_secs: u64,
+    /// a client sending nothing this long is disconnected, 0 never
+    pub idle_timeout_secs: u64,
+    /// a browser not sending its public key this soon after the upgrade is disconnected
+    pub handshake_timeout_secs: u64,
+    /// `/readyz` fails unless the chat room and its pubsubs answer this soon
+    pub readiness_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:3000".to_string(),
+            allow_origins: vec![],
+            shutdown_timeout_secs: 10,
+            outbound_queue: OutboxOptions::default().capacity,
+            slow_consumer: SlowConsumer::default(),
+            ping_interval_secs: 20,
+            pong_timeout_secs: 10,
+            idle_timeout_secs: 0,
+            handshake_timeout_secs: 10,
+            readiness_timeout_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// the same syntax as `RUST_LOG`, e.g. `info` or `nobody_chat=debug,info`
+    pub level: String,
+    /// `text`, or `json` for a JSON object a line
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionsConfig {
+    /// WebSockets of `/ws` an address may hold at once, 0 for any
+    pub per_ip: usize,
+    /// new WebSockets of `/ws` an address may open, a burst of 0 for any
+    pub new_per_ip: RateConfig,
+    /// addresses or networks like `10.0.0.0/8` of the reverse proxies trusted to tell
+    /// the client by `Forwarded` or `X-Forwarded-For`
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ConnectionsConfig {
+    fn default() -> Self {
+        let limits = ConnectionLimits::default();
+        Self {
+            per_ip: limits.per_ip,
+            new_per_ip: limits
+                .new_per_ip
+                .map(RateConfig::from)
+                .unwrap_or(RateConfig {
+                    burst: 0,
+                    period_secs: 0,
+                }),
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageLimitsConfig {
+    pub enabled: bool,
+    /// messages a user may send to the others
+    pub messages: RateConfig,
+    /// bytes of every frame a user may send
+    pub bytes: RateConfig,
+    /// violations before the user is muted, warned at the first one
+    pub mute_after: u32,
+    pub mute_secs: u64,
+    /// violations before the user is disconnected
+    pub disconnect_after: u32,
+    /// the violations are forgotten once none for this long
+    pub forget_after_secs: u64,
+}
+
+impl Default for MessageLimitsConfig {
+    fn default() -> Self {
+        let limits = MessageLimits::default();
+        Self {
+            enabled: true,
+            messages: limits.messages.into(),
+            bytes: limits.bytes.into(),
+            mute_after: limits.mute_after,
+            mute_secs: limits.mute_for.as_secs(),
+            disconnect_after: limits.disconnect_after,
+            forget_after_secs: limits.forget_after.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageFilterConfig {
+    /// whole words of letters and digits, of any case
+    pub words: Vec<String>,
+    /// `reject`, `mask` by `*` or `flag` to the subscribers and the webhooks
+    pub words_action: FilterAction,
+    /// catch the `http://`, `https://` and `www.` links
+    pub links: bool,
+    pub links_action: FilterAction,
+    /// characters of a message, 0 for any
+    pub max_length: usize,
+    /// `mask` cuts the rest
+    pub max_length_action: FilterAction,
+}
+
+impl Default for MessageFilterConfig {
+    fn default() -> Self {
+        Self {
+            words: vec![],
+            words_action: FilterAction::Mask,
+            links: false,
+            links_action: FilterAction::Reject,
+            max_length: 0,
+            max_length_action: FilterAction::Reject,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StunConfig {
+    pub enabled: bool,
+    /// UDP listen address
+    pub addr: String,
+}
+
+impl Default for StunConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            addr: "0.0.0.0:3478".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalConfig {
+    pub max_sdp_size: usize,
+    pub max_candidate_size: usize,
+    pub strip_host_candidates: bool,
+    pub allowed_media: BTreeSet<MediaType>,
+    pub limits: SignalLimitsConfig,
+}
+
+impl Default for SignalConfig {
+    fn default() -> Self {
+        let policy = SignalPolicy::default();
+        Self {
+            max_sdp_size: policy.max_sdp_len,
+            max_candidate_size: policy.max_candidate_len,
+            strip_host_candidates: policy.strip_host_candidates,
+            allowed_media: policy.allowed_media,
+            limits: SignalLimitsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignalLimitsConfig {
+    pub signals_per_sender: RateConfig,
+    pub calls_per_sender: RateConfig,
+    pub calls_per_target: RateConfig,
+    pub deny_cooldown_secs: u64,
+}
+
+impl Default for SignalLimitsConfig {
+    fn default() -> Self {
+        let limits = SignalLimits::default();
+        Self {
+            signals_per_sender: limits.signals_per_sender.into(),
+            calls_per_sender: limits.calls_per_sender.into(),
+            calls_per_target: limits.calls_per_target.into(),
+            deny_cooldown_secs: limits.deny_cooldown.as_secs(),
+        }
+    }
+}
+
+/// `burst` in `period_secs`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub burst: u32,
+    pub period_secs: u64,
+}
+
+impl From<Rate> for RateConfig {
+    fn from(rate: Rate) -> Self {
+        Self {
+            burst: rate.burst,
+            period_secs: rate.period.as_secs(),
+        }
+    }
+}
+
+impl From<RateConfig> for Rate {
+    fn from(rate: RateConfig) -> Self {
+        Rate::new(rate.burst, Duration::from_secs(rate.period_secs))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CallLogConfig {
+    /// append JSON lines to the file
+    pub file: Option<PathBuf>,
+    /// keep the latest records in memory, served by `/admin/calls`, 0 to disable
+    pub memory: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub enabled: bool,
+    /// bearer token of the `/admin` endpoints
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// serve `/metrics` for Prometheus
+    pub enabled: bool,
+    /// bearer token of `/metrics`, open to anyone reaching it if not set
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    /// PEM certificate chain
+    pub cert: Option<PathBuf>,
+    /// PEM private key
+    pub key: Option<PathBuf>,
+    /// plain HTTP listen address redirecting to HTTPS, not set by default
+    pub redirect_addr: Option<String>,
+    /// how often the certificate files are checked for changes, SIGHUP reloads at once
+    pub reload_interval_secs: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert: None,
+            key: None,
+            redirect_addr: None,
+            reload_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    /// unique in the cluster, random if not set
+    pub node_id: Option<String>,
+    /// Redis, or anything speaking its pub/sub, shared by every node
+    pub redis_addr: String,
+    pub redis_password: Option<String>,
+    pub channel: String,
+    /// a node not heard for 3 heartbeats is gone, with its users
+    pub heartbeat_secs: u64,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            node_id: None,
+            redis_addr: "127.0.0.1:6379".to_string(),
+            redis_password: None,
+            channel: "nobody-chat".to_string(),
+            heartbeat_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BotConfig {
+    /// the bot is online as `bot-<name>`
+    pub name: String,
+    /// bearer key of the bot
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// the events are POSTed here
+    pub url: String,
+    /// key of the HMAC-SHA256 signature
+    pub secret: String,
+    /// the messages sent to these ids are delivered too, e.g. `bot-helpdesk`
+    pub messages_to: Vec<String>,
+    /// events waiting for delivery, the new ones are dropped once full
+    pub queue_size: usize,
+    pub max_attempts: u32,
+    /// before the first retry, doubling after every failure
+    pub backoff_ms: u64,
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        let options = WebhookOptions::new("", "");
+        Self {
+            url: options.url,
+            secret: options.secret.into_inner(),
+            messages_to: options.messages_to,
+            queue_size: options.queue_size,
+            max_attempts: options.max_attempts,
+            backoff_ms: options.backoff.as_millis() as u64,
+            timeout_secs: options.timeout.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {e}"),
+            ConfigError::Invalid(problems) => {
+                writeln!(f, "invalid config:")?;
+                for problem in problems {
+                    writeln!(f, "  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// layer the config file, if any, and the environment variables over the defaults,
+    /// the result is not validated yet
+    pub fn load(
+        path: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut value =
+            Value::try_from(Config::default()).map_err(|e| ConfigError::Parse(e.to_string()))?;
+
+        if let Some(path) = path {
+            let content =
+                fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+            let file: Table = content
+                .parse()
+                .map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))?;
+            merge(&mut value, Value::Table(file));
+        }
+
+        for (key, raw) in env {
+            let path: Vec<String> = if let Some(key) = key.strip_prefix(ENV_PREFIX) {
+                key.split("__").map(|k| k.to_lowercase()).collect()
+            } else {
+                // the variables this server was configured by before the config file
+                match key.as_str() {
+                    "RUST_LOG" => vec!["log".into(), "level".into()],
+                    "ALLOW_URLS" => vec!["server".into(), "allow_origins".into()],
+                    "ADMIN_TOKEN" => vec!["admin".into(), "token".into()],
+                    _ => continue,
+                }
+            };
+
+            let env_value = if key == "ALLOW_URLS" {
+                let urls: Vec<String> = serde_json::from_str(&raw).map_err(|e| {
+                    ConfigError::Parse(format!("ALLOW_URLS is not a JSON string array: {e}"))
+                })?;
+                Value::try_from(urls).map_err(|e| ConfigError::Parse(e.to_string()))?
+            } else {
+                match get(&value, &path) {
+                    // strings, and the optional values which are all strings
+                    Some(Value::String(_)) | None => Value::String(raw),
+                    Some(_) => parse_env_value(&raw),
+                }
+            };
+            set(&mut value, &path, env_value)
+                .map_err(|e| ConfigError::Parse(format!("{key}: {e}")))?;
+            if key == "ADMIN_TOKEN" {
+                set(
+                    &mut value,
+                    &["admin".into(), "enabled".into()],
+                    Value::Boolean(true),
+                )
+                .map_err(ConfigError::Parse)?;
+            }
+        }
+
+        value
+            .try_into()
+            .map_err(|e: toml::de::Error| ConfigError::Parse(e.to_string()))
+    }
+
+    /// every problem of the config, so the server fails loudly instead of misbehaving
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if let Err(e) = self.server.addr.to_socket_addrs() {
+            problems.push(format!("server.addr `{}`: {}", self.server.addr, e));
+        }
+
+        if self.server.allow_origins.is_empty() {
+            problems.push(
+                "server.allow_origins is empty, every origin would be rejected, use `*` to allow any"
+                    .to_string(),
+            );
+        }
+        for origin in &self.server.allow_origins {
+            if origin != "*" && !is_origin(origin) {
+                problems.push(format!(
+                    "server.allow_origins `{origin}` is not an origin like `https://example.com`"
+                ));
+            }
+        }
+
+        if self.server.outbound_queue == 0 {
+            problems.push("server.outbound_queue must be positive".to_string());
+        }
+        if self.server.ping_interval_secs == 0 {
+            problems.push("server.ping_interval_secs must be positive".to_string());
+        }
+        if self.server.pong_timeout_secs == 0 {
+            problems.push("server.pong_timeout_secs must be positive".to_string());
+        }
+        if self.server.handshake_timeout_secs == 0 {
+            problems.push("server.handshake_timeout_secs must be positive".to_string());
+        }
+        if self.server.readiness_timeout_ms == 0 {
+            problems.push("server.readiness_timeout_ms must be positive".to_string());
+        }
+
+        let new_per_ip = &self.connections.new_per_ip;
+        if new_per_ip.burst > 0 && new_per_ip.period_secs == 0 {
+            problems.push("connections.new_per_ip needs a positive period_secs".to_string());
+        }
+        for proxy in &self.connections.trusted_proxies {
+            if let Err(e) = proxy.parse::<IpNet>() {
+                problems.push(format!("connections.trusted_proxies {e}"));
+            }
+        }
+
+        if self.message_limits.enabled {
+            let limits = &self.message_limits;
+            for (name, rate) in [("messages", &limits.messages), ("bytes", &limits.bytes)] {
+                if rate.burst == 0 || rate.period_secs == 0 {
+                    problems.push(format!(
+                        "message_limits.{name} needs a positive burst and period_secs"
+                    ));
+                }
+            }
+            if limits.mute_after == 0 || limits.mute_secs == 0 {
+                problems
+                    .push("message_limits.mute_after and mute_secs must be positive".to_string());
+            }
+            if limits.disconnect_after <= limits.mute_after {
+                problems.push(
+                    "message_limits.disconnect_after must be more than mute_after".to_string(),
+                );
+            }
+        }
+
+        for word in &self.message_filter.words {
+            if word.is_empty() || !word.chars().all(char::is_alphanumeric) {
+                problems.push(format!(
+                    "message_filter.words `{word}` is not a word of letters and digits"
+                ));
+            }
+        }
+
+        for directive in self.log.level.split(',').filter(|d| !d.is_empty()) {
+            match directive.rsplit_once('=') {
+                Some((_, level)) if LevelFilter::from_str(level).is_err() => {
+                    problems.push(format!("log.level `{directive}` has an unknown level"));
+                }
+                // a bare word is a target at `trace` and everything else off, not meant
+                None if LevelFilter::from_str(directive).is_err()
+                    && !(directive.contains("::") || directive.contains('[')) =>
+                {
+                    problems.push(format!(
+                        "log.level `{directive}` is not a level, `{directive}=<level>` for a target"
+                    ));
+                }
+                _ if EnvFilter::try_new(directive).is_err() => {
+                    problems.push(format!("log.level `{directive}` is not a directive"));
+                }
+                _ => {}
+            }
+        }
+
+        if self.stun.enabled {
+            if let Err(e) = self.stun.addr.to_socket_addrs() {
+                problems.push(format!("stun.addr `{}`: {}", self.stun.addr, e));
+            }
+        }
+
+        let signal = &self.signal;
+        if signal.max_sdp_size == 0 {
+            problems.push("signal.max_sdp_size must be positive".to_string());
+        }
+        if signal.max_candidate_size == 0 {
+            problems.push("signal.max_candidate_size must be positive".to_string());
+        }
+        for (name, rate) in [
+            ("signals_per_sender", &signal.limits.signals_per_sender),
+            ("calls_per_sender", &signal.limits.calls_per_sender),
+            ("calls_per_target", &signal.limits.calls_per_target),
+        ] {
+            if rate.burst == 0 || rate.period_secs == 0 {
+                problems.push(format!(
+                    "signal.limits.{name} needs a positive burst and period_secs"
+                ));
+            }
+        }
+
+        if self.admin.enabled {
+            match &self.admin.token {
+                Some(token) if token.len() >= 16 => {}
+                _ => problems.push("admin.token needs at least 16 characters".to_string()),
+            }
+        }
+        if let (true, Some(token)) = (self.metrics.enabled, &self.metrics.token) {
+            if token.len() < 16 {
+                problems.push("metrics.token needs at least 16 characters".to_string());
+            }
+        }
+
+        if self.tls.enabled {
+            for (name, path) in [("cert", &self.tls.cert), ("key", &self.tls.key)] {
+                match path {
+                    Some(path) => {
+                        if let Err(e) = fs::metadata(path) {
+                            problems.push(format!("tls.{name} `{}`: {}", path.display(), e));
+                        }
+                    }
+                    None => problems.push(format!("tls.{name} is required when tls is enabled")),
+                }
+            }
+            if let Some(addr) = &self.tls.redirect_addr {
+                if let Err(e) = addr.to_socket_addrs() {
+                    problems.push(format!("tls.redirect_addr `{addr}`: {e}"));
+                }
+            }
+            if self.tls.reload_interval_secs == 0 {
+                problems.push("tls.reload_interval_secs must be positive".to_string());
+            }
+        }
+
+        if self.cluster.enabled {
+            if let Err(e) = self.cluster.redis_addr.to_socket_addrs() {
+                problems.push(format!(
+                    "cluster.redis_addr `{}`: {}",
+                    self.cluster.redis_addr, e
+                ));
+            }
+            if self.cluster.channel.is_empty() {
+                problems.push("cluster.channel must not be empty".to_string());
+            }
+            if self
+                .cluster
+                .node_id
+                .as_ref()
+                .is_some_and(|id| id.is_empty())
+            {
+                problems.push("cluster.node_id must not be empty".to_string());
+            }
+            if self.cluster.heartbeat_secs == 0 {
+                problems.push("cluster.heartbeat_secs must be positive".to_string());
+            }
+        }
+
+        let mut names = BTreeSet::new();
+        let mut keys = BTreeSet::new();
+        for bot in &self.bots {
+            let valid_name = !bot.name.is_empty()
+                && bot
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+            if !valid_name {
+                problems.push(format!(
+                    "bots.name `{}` needs lowercase letters, digits, `-` or `_`",
+                    bot.name
+                ));
+            }
+            if !names.insert(&bot.name) {
+                problems.push(format!("bots.name `{}` is duplicated", bot.name));
+            }
+            if bot.key.len() < 16 {
+                problems.push(format!(
+                    "bots.key of `{}` needs at least 16 characters",
+                    bot.name
+                ));
+            }
+            if !keys.insert(&bot.key) {
+                problems.push(format!("bots.key of `{}` is used by another bot", bot.name));
+            }
+        }
+
+        for webhook in &self.webhooks {
+            let valid_url = webhook.url.parse::<Uri>().is_ok_and(|uri| {
+                matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some()
+            });
+            if !valid_url {
+                problems.push(format!("webhooks.url `{}` is not an HTTP URL", webhook.url));
+            }
+            if webhook.secret.len() < 16 {
+                problems.push(format!(
+                    "webhooks.secret of `{}` needs at least 16 characters",
+                    webhook.url
+                ));
+            }
+            if webhook.queue_size == 0 || webhook.max_attempts == 0 || webhook.timeout_secs == 0 {
+                problems.push(format!(
+                    "webhooks of `{}` needs a positive queue_size, max_attempts and timeout_secs",
+                    webhook.url
+                ));
+            }
+            if webhook.max_attempts > MAX_ATTEMPTS {
+                problems.push(format!(
+                    "webhooks.max_attempts of `{}` is more than {MAX_ATTEMPTS}",
+                    webhook.url
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// the config as TOML, secrets are redacted
+    pub fn to_redacted_toml(&self) -> String {
+        let mut config = self.clone();
+        if config.metrics.token.is_some() {
+            config.metrics.token = Some("<redacted>".to_string());
+        }
+        if config.admin.token.is_some() {
+            config.admin.token = Some("<redacted>".to_string());
+        }
+        if config.cluster.redis_password.is_some() {
+            config.cluster.redis_password = Some("<redacted>".to_string());
+        }
+        for bot in &mut config.bots {
+            bot.key = "<redacted>".to_string();
+        }
+        for webhook in &mut config.webhooks {
+            webhook.secret = "<redacted>".to_string();
+        }
+
+        toml::to_string_pretty(&config).expect("serialize config")
+    }
+
+    pub fn signal_policy(&self) -> SignalPolicy {
+        let limits = &self.signal.limits;
+        SignalPolicy {
+            max_sdp_len: self.signal.max_sdp_size,
+            max_candidate_len: self.signal.max_candidate_size,
+            strip_host_candidates: self.signal.strip_host_candidates,
+            allowed_media: self.signal.allowed_media.clone(),
+            limits: SignalLimits {
+                signals_per_sender: limits.signals_per_sender.into(),
+                calls_per_sender: limits.calls_per_sender.into(),
+                calls_per_target: limits.calls_per_target.into(),
+                deny_cooldown: Duration::from_secs(limits.deny_cooldown_secs),
+            },
+        }
+    }
+
+    /// `None` if disabled
+    pub fn message_limits(&self) -> Option<MessageLimits> {
+        let limits = &self.message_limits;
+        limits.enabled.then(|| MessageLimits {
+            messages: limits.messages.into(),
+            bytes: limits.bytes.into(),
+            mute_after: limits.mute_after,
+            mute_for: Duration::from_secs(limits.mute_secs),
+            disconnect_after: limits.disconnect_after,
+            forget_after: Duration::from_secs(limits.forget_after_secs),
+        })
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        let new_per_ip = self.connections.new_per_ip;
+        ConnectionLimits {
+            per_ip: self.connections.per_ip,
+            new_per_ip: (new_per_ip.burst > 0).then(|| new_per_ip.into()),
+        }
+    }
+}
+
+impl App {
+    /// an `App` of a validated config
+    pub fn from_config(config: &Config) -> Self {
+        let mut app = App::new(&config.server.addr, config.server.allow_origins.clone())
+            .with_signal_policy(config.signal_policy())
+            .with_shutdown_timeout(Duration::from_secs(config.server.shutdown_timeout_secs))
+            .with_readiness_timeout(Duration::from_millis(config.server.readiness_timeout_ms))
+            .with_outbox(OutboxOptions {
+                capacity: config.server.outbound_queue,
+                slow_consumer: config.server.slow_consumer,
+            })
+            .with_heartbeat(HeartbeatOptions {
+                ping_interval: Duration::from_secs(config.server.ping_interval_secs),
+                pong_timeout: Duration::from_secs(config.server.pong_timeout_secs),
+                idle_timeout: (config.server.idle_timeout_secs > 0)
+                    .then(|| Duration::from_secs(config.server.idle_timeout_secs)),
+                handshake_timeout: Duration::from_secs(config.server.handshake_timeout_secs),
+            })
+            .with_message_limits(config.message_limits())
+            .with_connection_limits(config.connection_limits())
+            .with_trusted_proxies(TrustedProxies::new(
+                config
+                    .connections
+                    .trusted_proxies
+                    .iter()
+                    .filter_map(|proxy| proxy.parse().ok())
+                    .collect(),
+            ));
+
+        let filter = &config.message_filter;
+        if !filter.words.is_empty() {
+            app = app.with_message_filter(WordList::new(&filter.words, filter.words_action));
+        }
+        if filter.links {
+            app = app.with_message_filter(LinkFilter::new(filter.links_action));
+        }
+        if filter.max_length > 0 {
+            app = app
+                .with_message_filter(MaxLength::new(filter.max_length, filter.max_length_action));
+        }
+
+        if config.stun.enabled {
+            app = app.with_stun(&config.stun.addr);
+        }
+        if let Some(path) = &config.call_log.file {
+            app = app.with_call_log(CallLog::File(path.clone()));
+        }
+        if config.call_log.memory > 0 {
+            app = app.with_call_log(CallLog::Memory(config.call_log.memory));
+        }
+        if let (true, Some(token)) = (config.admin.enabled, &config.admin.token) {
+            app = app.with_admin_token(token);
+        }
+        if config.metrics.enabled {
+            app = app.with_metrics(config.metrics.token.clone());
+        }
+        if let (true, Some(cert), Some(key)) =
+            (config.tls.enabled, &config.tls.cert, &config.tls.key)
+        {
+            app = app.with_tls(TlsOptions {
+                redirect_addr: config.tls.redirect_addr.clone(),
+                reload_interval: Duration::from_secs(config.tls.reload_interval_secs),
+                ..TlsOptions::new(cert, key)
+            });
+        }
+
+        if config.cluster.enabled {
+            let mut bus = RedisBus::new(&config.cluster.redis_addr, &config.cluster.channel);
+            if let Some(password) = &config.cluster.redis_password {
+                bus = bus.with_password(password);
+            }
+            let mut cluster = ClusterOptions::new(Arc::new(bus));
+            if let Some(node) = &config.cluster.node_id {
+                cluster.node = node.clone();
+            }
+            cluster.heartbeat = Duration::from_secs(config.cluster.heartbeat_secs);
+            app = app.with_cluster(cluster);
+        }
+        for bot in &config.bots {
+            app = app.with_bot(&bot.name, &bot.key);
+        }
+        for webhook in &config.webhooks {
+            app = app.with_webhook(WebhookOptions {
+                messages_to: webhook.messages_to.clone(),
+                queue_size: webhook.queue_size,
+                max_attempts: webhook.max_attempts,
+                backoff: Duration::from_millis(webhook.backoff_ms),
+                timeout: Duration::from_secs(webhook.timeout_secs),
+                ..WebhookOptions::new(&webhook.url, &webhook.secret)
+            });
+        }
+
+        app
+    }
+}
+
+fn is_origin(origin: &str) -> bool {
+    if HeaderValue::from_str(origin).is_err() {
+        return false;
+    }
+
+    match origin.parse::<Uri>() {
+        Ok(uri) => {
+            matches!(uri.scheme_str(), Some("http") | Some("https"))
+                && uri.host().is_some()
+                && uri
+                    .path_and_query()
+                    .is_none_or(|p| p.as_str().is_empty() || p.as_str() == "/")
+                && !origin.ends_with('/')
+        }
+        Err(_) => false,
+    }
+}
+
+/// numbers, booleans and arrays are parsed as TOML, if not, the string fails deserializing
+fn parse_env_value(raw: &str) -> Value {
+    match format!("v = {raw}").parse::<Table>() {
+        Ok(mut table) => table
+            .remove("v")
+            .unwrap_or_else(|| Value::String(raw.to_string())),
+        Err(_) => Value::String(raw.to_string()),
+    }
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Table(base), Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+fn get<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter()
+        .try_fold(value, |current, key| current.as_table()?.get(key))
+}
+
+fn set(value: &mut Value, path: &[String], new_value: Value) -> Result<(), String> {
+    let Some((last, parents)) = path.split_last() else {
+        return Err("empty key".to_string());
+    };
+
+    let mut current = value;
+    for key in parents {
+        current = current
+            .as_table_mut()
+            .ok_or(format!("`{key}` is not a table"))?
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()));
+    }
+
+    current
+        .as_table_mut()
+        .ok_or(format!("`{last}` is not in a table"))?
+        .insert(last.clone(), new_value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}.toml", std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.server.allow_origins = vec!["http://localhost:3001".to_string()];
+        config
+    }
+
+    #[test]
+    fn defaults_without_file() {
+        let config = Config::load(None, vec![]).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(
+            config.signal_policy().max_sdp_len,
+            SignalPolicy::default().max_sdp_len
+        );
+    }
+
+    #[test]
+    fn layer_file_and_env() {
+        let path = write_config(
+            "layer",
+            r#"
+            [server]
+            addr = "127.0.0.1:4000"
+            allow_origins = ["https://chat.example.com"]
+
+            [signal]
+            allowed_media = ["audio"]
+
+            [signal.limits]
+            calls_per_sender = { burst = 3, period_secs = 30 }
+            "#,
+        );
+
+        let config = Config::load(
+            Some(&path),
+            env(&[
+                ("NOBODY_CHAT__SERVER__ADDR", "127.0.0.1:5000"),
+                ("NOBODY_CHAT__SIGNAL__MAX_SDP_SIZE", "2048"),
+                ("NOBODY_CHAT__STUN__ENABLED", "true"),
+                ("NOBODY_CHAT__SERVER__SLOW_CONSUMER", "disconnect"),
+                ("RUST_LOG", "debug"),
+                ("NOBODY_CHAT__LOG__FORMAT", "json"),
+                ("UNRELATED", "x"),
+            ]),
+        )
+        .unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(config.server.addr, "127.0.0.1:5000");
+        assert_eq!(
+            config.server.allow_origins,
+            vec!["https://chat.example.com"]
+        );
+        assert_eq!(config.signal.max_sdp_size, 2048);
+        assert_eq!(
+            config.signal.allowed_media,
+            BTreeSet::from([MediaType::Audio])
+        );
+        assert_eq!(
+            config.signal.limits.calls_per_sender,
+            RateConfig {
+                burst: 3,
+                period_secs: 30
+            }
+        );
+        // untouched values stay default
+        assert_eq!(
+            config.signal.limits.calls_per_target,
+            SignalLimitsConfig::default().calls_per_target
+        );
+        assert!(config.stun.enabled);
+        assert_eq!(config.server.slow_consumer, SlowConsumer::Disconnect);
+        assert_eq!(config.log.level, "debug");
+        assert_eq!(config.log.format, LogFormat::Json);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn legacy_allow_urls() {
+        let config = Config::load(None, env(&[("ALLOW_URLS", r#"["*"]"#)])).unwrap();
+        assert_eq!(config.server.allow_origins, vec!["*"]);
+
+        // no silent fallback to an empty list
+        assert!(matches!(
+            Config::load(None, env(&[("ALLOW_URLS", "*")])),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn string_env_stays_string() {
+        let config = Config::load(None, env(&[("ADMIN_TOKEN", "1234567890123456")])).unwrap();
+        assert_eq!(config.admin.token.as_deref(), Some("1234567890123456"));
+        assert!(config.admin.enabled);
+
+        let config = Config::load(None, env(&[("NOBODY_CHAT__CALL_LOG__MEMORY", "100")])).unwrap();
+        assert_eq!(config.call_log.memory, 100);
+
+        let config = Config::load(None, env(&[("NOBODY_CHAT__LOG__LEVEL", "42")])).unwrap();
+        assert_eq!(config.log.level, "42");
+    }
+
+    #[test]
+    fn reject_unknown_fields() {
+        let path = write_config("unknown", "[server]\nadress = \"127.0.0.1:4000\"\n");
+        let res = Config::load(Some(&path), vec![]);
+        let _ = fs::remove_file(path);
+        assert!(matches!(res, Err(ConfigError::Parse(_))));
+
+        assert!(matches!(
+            Config::load(None, env(&[("NOBODY_CHAT__SIGNAL__MAX_SDP_SIZE", "big")])),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn missing_file() {
+        assert!(matches!(
+            Config::load(Some(Path::new("/nonexistent/nobody-chat.toml")), vec![]),
+            Err(ConfigError::Io(..))
+        ));
+    }
+
+    #[test]
+    fn validate_every_problem() {
+        assert!(valid().validate().is_ok());
+
+        let mut config = valid();
+        config.server.addr = "not an address".to_string();
+        config.server.allow_origins = vec!["localhost:3001".to_string(), "*".to_string()];
+        config.log.level = "nobody_chat=loud,verbose".to_string();
+        config.signal.limits.calls_per_sender.burst = 0;
+        config.admin.enabled = true;
+        config.admin.token = Some("short".to_string());
+        config.server.outbound_queue = 0;
+        config.server.pong_timeout_secs = 0;
+        config.server.readiness_timeout_ms = 0;
+        config.metrics.enabled = true;
+        config.metrics.token = Some("short".to_string());
+        config.connections.new_per_ip.period_secs = 0;
+        config.connections.trusted_proxies = vec!["10.0.0.0/8".to_string(), "proxy".to_string()];
+        config.message_limits.disconnect_after = config.message_limits.mute_after;
+        config.message_filter.words = vec!["darn".to_string(), "darn it".to_string()];
+
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected invalid config");
+        };
+        assert_eq!(problems.len(), 14, "{problems:?}");
+
+        let mut config = valid();
+        config.log.level = "warn,nobody_chat=debug,nobody_chat::chat,[connection]".to_string();
+        assert!(config.validate().is_ok());
+
+        let mut config = valid();
+        config.server.allow_origins = vec![];
+        assert!(config.validate().is_err());
+
+        let mut config = valid();
+        config.tls.enabled = true;
+        config.tls.cert = Some(PathBuf::from("/nonexistent/cert.pem"));
+        config.tls.redirect_addr = Some("not an address".to_string());
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected invalid tls config");
+        };
+        // missing cert file, no key, bad redirect address
+        assert_eq!(problems.len(), 3, "{problems:?}");
+
+        let mut config = valid();
+        config.cluster.enabled = true;
+        config.cluster.redis_addr = "not an address".to_string();
+        config.cluster.heartbeat_secs = 0;
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected invalid cluster config");
+        };
+        assert_eq!(problems.len(), 2, "{problems:?}");
+
+        let mut config = valid();
+        let bot = |name: &str, key: &str| BotConfig {
+            name: name.to_string(),
+            key: key.to_string(),
+        };
+        config.bots = vec![
+            bot("helpdesk", "a long enough bot key"),
+            bot("helpdesk", "another long bot key"),
+            bot("Help Desk", "a long enough bot key"),
+            bot("short", "short"),
+        ];
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected invalid bots");
+        };
+        // duplicated name, bad name, reused key, short key
+        assert_eq!(problems.len(), 4, "{problems:?}");
+
+        let mut config = valid();
+        config.webhooks = vec![
+            WebhookConfig {
+                url: "https://hooks.example.com/chat".to_string(),
+                secret: "a long enough webhook secret".to_string(),
+                ..Default::default()
+            },
+            WebhookConfig {
+                url: "hooks.example.com".to_string(),
+                max_attempts: 0,
+                ..Default::default()
+            },
+            WebhookConfig {
+                url: "https://hooks.example.com/forever".to_string(),
+                secret: "a long enough webhook secret".to_string(),
+                max_attempts: MAX_ATTEMPTS + 1,
+                ..Default::default()
+            },
+        ];
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("expected invalid webhooks");
+        };
+        // bad url, short secret, no attempt, too many attempts
+        assert_eq!(problems.len(), 4, "{problems:?}");
+    }
+
+    #[test]
+    fn origins() {
+        assert!(is_origin("http://localhost:3001"));
+        assert!(is_origin("https://chat.example.com"));
+        assert!(!is_origin("chat.example.com"));
+        assert!(!is_origin("https://chat.example.com/"));
+        assert!(!is_origin("https://chat.example.com/path"));
+        assert!(!is_origin("ftp://chat.example.com"));
+    }
+
+    #[test]
+    fn redact_secrets() {
+        let mut config = valid();
+        config.admin.token = Some("a very secret admin token".to_string());
+        config.metrics.token = Some("a very secret metrics token".to_string());
+        config.cluster.redis_password = Some("a very secret redis password".to_string());
+        config.bots = vec![BotConfig {
+            name: "helpdesk".to_string(),
+            key: "a very secret bot key".to_string(),
+        }];
+
+        config.webhooks = vec![WebhookConfig {
+            url: "https://hooks.example.com/chat".to_string(),
+            secret: "a very secret webhook secret".to_string(),
+            ..Default::default()
+        }];
+
+        let printed = config.to_redacted_toml();
+        assert!(!printed.contains("a very secret admin token"));
+        assert!(!printed.contains("a very secret metrics token"));
+        assert!(!printed.contains("a very secret bot key"));
+        assert!(!printed.contains("a very secret webhook secret"));
+        assert!(!printed.contains("a very secret redis password"));
+        assert!(printed.contains("<redacted>"));
+
+        // the printed config is loadable again
+        let path = write_config("printed", &config.to_redacted_toml());
+        let res = Config::load(Some(&path), vec![]);
+        let _ = fs::remove_file(path);
+        assert_eq!(res.unwrap().server, config.server);
+    }
+}
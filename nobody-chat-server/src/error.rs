@@ -0,0 +1,59 @@
+use std::fmt;
+
+use kameo::error::SendError;
+
+/// what goes wrong on the way of a message, the actors log it and
+/// drop the user instead of panicking
+#[derive(Debug)]
+pub enum Error {
+    /// the WebSocket is closed or broken
+    Socket(axum::Error),
+    /// the client does not follow the protocol, e.g. a broken key exchange
+    Protocol(String),
+    /// the receiving actor is stopped
+    ActorGone,
+    /// the mailbox of the receiving actor is full
+    MailboxFull,
+    /// no reply in time
+    Timeout,
+    /// no such user online
+    UserNotFound(String),
+    /// the message bus of the cluster is unreachable
+    Bus(std::io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Socket(e) => write!(f, "socket: {e}"),
+            Error::Protocol(e) => write!(f, "protocol: {e}"),
+            Error::ActorGone => write!(f, "actor is stopped"),
+            Error::MailboxFull => write!(f, "mailbox is full"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::UserNotFound(id) => write!(f, "user id: {id} is not online"),
+            Error::Bus(e) => write!(f, "message bus: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<axum::Error> for Error {
+    fn from(e: axum::Error) -> Self {
+        Error::Socket(e)
+    }
+}
+
+impl<M, E> From<SendError<M, E>> for Error {
+    fn from(e: SendError<M, E>) -> Self {
+        match e {
+            SendError::ActorNotRunning(_) | SendError::ActorStopped => Error::ActorGone,
+            SendError::MailboxFull(_) => Error::MailboxFull,
+            SendError::Timeout(_) => Error::Timeout,
+            // no handler of ours fails
+            SendError::HandlerError(_) => Error::ActorGone,
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! what may be said: the messages between users are checked before they are routed
+
+use std::{collections::HashSet, fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+/// what a filter makes of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filtered {
+    Pass,
+    /// deliver this instead
+    Mask(String),
+    /// deliver as is, but tell the moderators why
+    Flag(String),
+    /// never delivered, the sender is told why
+    Reject(String),
+}
+
+/// checks a message to another user, the extension point of the embedding application
+pub trait MessageFilter: Send + Sync {
+    fn check(&self, from: &str, to: &str, msg: &str) -> Filtered;
+}
+
+/// what a built-in filter does with a message it catches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    #[default]
+    Reject,
+    Mask,
+    Flag,
+}
+
+impl FilterAction {
+    fn apply(self, reason: String, mask: impl FnOnce() -> String) -> Filtered {
+        match self {
+            Self::Reject => Filtered::Reject(reason),
+            Self::Mask => Filtered::Mask(mask()),
+            Self::Flag => Filtered::Flag(reason),
+        }
+    }
+}
+
+/// whole words, of any case; masked by `*`
+#[derive(Debug, Clone)]
+pub struct WordList {
+    words: HashSet<String>,
+    action: FilterAction,
+}
+
+impl WordList {
+    pub fn new(words: impl IntoIterator<Item = impl AsRef<str>>, action: FilterAction) -> Self {
+        Self {
+            words: words
+                .into_iter()
+                .map(|word| word.as_ref().to_lowercase())
+                .collect(),
+            action,
+        }
+    }
+}
+
+impl MessageFilter for WordList {
+    fn check(&self, _from: &str, _to: &str, msg: &str) -> Filtered {
+        let found: Vec<_> = words(msg)
+            .filter(|(start, end)| self.words.contains(&msg[*start..*end].to_lowercase()))
+            .collect();
+        if found.is_empty() {
+            return Filtered::Pass;
+        }
+
+        self.action.apply("a word not allowed".to_string(), || {
+            let mut masked = msg.to_string();
+            // from the end, the spans before stay where they are
+            for (start, end) in found.into_iter().rev() {
+                masked.replace_range(start..end, &"*".repeat(msg[start..end].chars().count()));
+            }
+            masked
+        })
+    }
+}
+
+/// the byte spans of the alphanumeric runs of `msg`
+fn words(msg: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let mut start = None;
+    msg.char_indices()
+        .chain(std::iter::once((msg.len(), ' ')))
+        .filter_map(move |(i, c)| match (start, c.is_alphanumeric()) {
+            (None, true) => {
+                start = Some(i);
+                None
+            }
+            (Some(from), false) => {
+                start = None;
+                Some((from, i))
+            }
+            _ => None,
+        })
+}
+
+/// `http://`, `https://` and `www.` links; masked by `*`
+#[derive(Debug, Clone, Default)]
+pub struct LinkFilter {
+    action: FilterAction,
+}
+
+impl LinkFilter {
+    pub fn new(action: FilterAction) -> Self {
+        Self { action }
+    }
+}
+
+impl MessageFilter for LinkFilter {
+    fn check(&self, _from: &str, _to: &str, msg: &str) -> Filtered {
+        let is_link = |token: &str| {
+            let token = token.to_ascii_lowercase();
+            ["http://", "https://", "www."]
+                .iter()
+                .any(|scheme| token.starts_with(scheme))
+        };
+        if !msg.split_whitespace().any(is_link) {
+            return Filtered::Pass;
+        }
+
+        self.action.apply("a link not allowed".to_string(), || {
+            msg.split_inclusive(char::is_whitespace)
+                .map(|token| {
+                    let word = token.trim_end();
+                    if is_link(word) {
+                        "*".repeat(word.chars().count()) + &token[word.len()..]
+                    } else {
+                        token.to_string()
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+/// at most `max_chars` characters; masked by cutting the rest
+#[derive(Debug, Clone)]
+pub struct MaxLength {
+    max_chars: usize,
+    action: FilterAction,
+}
+
+impl MaxLength {
+    pub fn new(max_chars: usize, action: FilterAction) -> Self {
+        Self { max_chars, action }
+    }
+}
+
+impl MessageFilter for MaxLength {
+    fn check(&self, _from: &str, _to: &str, msg: &str) -> Filtered {
+        if msg.chars().count() <= self.max_chars {
+            return Filtered::Pass;
+        }
+
+        self.action
+            .apply(format!("longer than {} characters", self.max_chars), || {
+                msg.chars().take(self.max_chars).collect()
+            })
+    }
+}
+
+/// what the filters make of a message, all of them together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Checked {
+    /// masked by some filters maybe, with the reasons of the filters flagging it
+    Deliver {
+        msg: String,
+        flags: Vec<String>,
+    },
+    Reject(String),
+}
+
+/// one filter after another, each seeing the message masked by the ones before;
+/// the first rejecting it stops the others
+#[derive(Clone, Default)]
+pub struct MessageFilters(Vec<Arc<dyn MessageFilter>>);
+
+impl MessageFilters {
+    pub fn push(&mut self, filter: Arc<dyn MessageFilter>) {
+        self.0.push(filter);
+    }
+
+    pub fn check(&self, from: &str, to: &str, mut msg: String) -> Checked {
+        let mut flags = vec![];
+        for filter in &self.0 {
+            match filter.check(from, to, &msg) {
+                Filtered::Pass => {}
+                Filtered::Mask(masked) => msg = masked,
+                Filtered::Flag(reason) => flags.push(reason),
+                Filtered::Reject(reason) => return Checked::Reject(reason),
+            }
+        }
+
+        Checked::Deliver { msg, flags }
+    }
+}
+
+impl fmt::Debug for MessageFilters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageFilters({} filters)", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod test_filter {
+    use super::*;
+
+    fn check(filter: &impl MessageFilter, msg: &str) -> Filtered {
+        filter.check("a", "b", msg)
+    }
+
+    #[test]
+    fn catch_whole_words() {
+        let words = WordList::new(["darn", "heck"], FilterAction::Mask);
+        assert_eq!(
+            check(&words, "Darn it, what the HECK!"),
+            Filtered::Mask("**** it, what the ****!".to_string())
+        );
+        // a part of a word is not the word
+        assert_eq!(check(&words, "darning socks"), Filtered::Pass);
+
+        let words = WordList::new(["héck"], FilterAction::Reject);
+        assert_eq!(
+            check(&words, "oh héck"),
+            Filtered::Reject("a word not allowed".to_string())
+        );
+    }
+
+    #[test]
+    fn catch_links() {
+        let links = LinkFilter::new(FilterAction::Mask);
+        assert_eq!(
+            check(&links, "see https://example.com\tor WWW.example.org now"),
+            Filtered::Mask("see *******************\tor *************** now".to_string())
+        );
+        assert_eq!(check(&links, "see example dot com"), Filtered::Pass);
+
+        let links = LinkFilter::new(FilterAction::Flag);
+        assert_eq!(
+            check(&links, "http://example.com"),
+            Filtered::Flag("a link not allowed".to_string())
+        );
+    }
+
+    #[test]
+    fn catch_long_messages() {
+        let max = MaxLength::new(3, FilterAction::Mask);
+        assert_eq!(check(&max, "héllo"), Filtered::Mask("hél".to_string()));
+        assert_eq!(check(&max, "hé"), Filtered::Pass);
+    }
+
+    #[test]
+    fn chain_the_filters() {
+        struct Shout;
+        impl MessageFilter for Shout {
+            fn check(&self, _from: &str, _to: &str, msg: &str) -> Filtered {
+                if msg.chars().any(char::is_lowercase) {
+                    Filtered::Pass
+                } else {
+                    Filtered::Flag("shouting".to_string())
+                }
+            }
+        }
+
+        let mut filters = MessageFilters::default();
+        filters.push(Arc::new(WordList::new(["darn"], FilterAction::Mask)));
+        filters.push(Arc::new(Shout));
+        filters.push(Arc::new(MaxLength::new(8, FilterAction::Reject)));
+
+        // the later filters see the masked message
+        assert_eq!(
+            filters.check("a", "b", "DARN IT".to_string()),
+            Checked::Deliver {
+                msg: "**** IT".to_string(),
+                flags: vec!["shouting".to_string()]
+            }
+        );
+        assert_eq!(
+            filters.check("a", "b", "darn it all".to_string()),
+            Checked::Reject("longer than 8 characters".to_string())
+        );
+        assert_eq!(
+            MessageFilters::default().check("a", "b", "hi".to_string()),
+            Checked::Deliver {
+                msg: "hi".to_string(),
+                flags: vec![]
+            }
+        );
+    }
+}
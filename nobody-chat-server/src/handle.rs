@@ -0,0 +1,288 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use futures_util::stream::{self, BoxStream, StreamExt};
+use kameo::{actor::ActorRef, request::MessageSend};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    chat::{BroadcastNotice, ChatRoom, Kick, Relay, SendMsg},
+    error::{Error, Result},
+    health::{ListenerState, Readiness},
+    limit::limited_addr,
+    logging::Redacted,
+    models::UserId,
+    moderation::{Ban, Bans, ConnectedUser},
+    routes::home::OnlineUser,
+    socket::OutboxStats,
+};
+
+/// what happens in the chat room, observed by `ChatHandle::subscribe` and the webhooks
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum ChatEvent {
+    Online {
+        id: String,
+        name: String,
+        bot: bool,
+    },
+    Offline {
+        id: String,
+    },
+    Renamed {
+        id: String,
+        name: String,
+    },
+    /// a message delivered to the receiver
+    Message {
+        from: String,
+        to: String,
+        msg: Redacted<String>,
+    },
+    /// a message a filter flagged, delivered as is
+    Flagged {
+        from: String,
+        to: String,
+        msg: Redacted<String>,
+        reasons: Vec<String>,
+    },
+}
+
+/// the chat room of a built `App`, for the embedding application and bots
+#[derive(Clone)]
+pub struct ChatHandle {
+    pub(crate) chat_room: ActorRef<ChatRoom>,
+    pub(crate) relay: Arc<Relay>,
+    pub(crate) listener: Arc<ListenerState>,
+    bans: Arc<Bans>,
+}
+
+impl ChatHandle {
+    pub(crate) fn new(chat_room: ActorRef<ChatRoom>, relay: Arc<Relay>) -> Self {
+        Self {
+            chat_room,
+            relay,
+            listener: Arc::default(),
+            bans: Arc::default(),
+        }
+    }
+
+    pub async fn online_users(&self) -> Result<Vec<OnlineUser>> {
+        Ok(self.relay.directory.users())
+    }
+
+    /// the users of this node and their connections, oldest first
+    pub fn connected_users(&self) -> Vec<ConnectedUser> {
+        let mut users: Vec<ConnectedUser> = self
+            .relay
+            .directory
+            .local_users()
+            .into_iter()
+            .map(ConnectedUser::from)
+            .collect();
+        users.sort_by(|a, b| (a.connected_at, &a.id).cmp(&(b.connected_at, &b.id)));
+
+        users
+    }
+
+    /// a user of this node
+    pub fn connected_user(&self, id: &str) -> Option<ConnectedUser> {
+        self.relay.directory.user(id).map(ConnectedUser::from)
+    }
+
+    /// close the socket of a user of this node, the others are told it is offline
+    pub async fn kick(&self, id: &str) -> Result<()> {
+        let Some(user) = self.relay.directory.get(id) else {
+            return Err(Error::UserNotFound(id.to_string()));
+        };
+        user.tell(Kick).send().await?;
+
+        Ok(())
+    }
+
+    /// refuse the connections of `ip` for `duration`, and kick its users,
+    /// the ids of which are returned; `None` if `duration` is beyond any time.
+    /// An IPv6 address is banned with its /64, listed by the first address of it
+    pub async fn ban(
+        &self,
+        ip: IpAddr,
+        duration: Duration,
+        reason: Option<String>,
+    ) -> Option<(Ban, Vec<UserId>)> {
+        let ban = self.bans.ban(ip, duration, reason)?;
+
+        let mut kicked = vec![];
+        for user in self.relay.directory.local_users() {
+            let banned = user.connection.ip.map(limited_addr) == Some(ban.ip);
+            if banned && user.actor_ref.tell(Kick).send().await.is_ok() {
+                kicked.push(user.id);
+            }
+        }
+
+        Some((ban, kicked))
+    }
+
+    /// `false` if `ip` was not banned
+    pub fn unban(&self, ip: &IpAddr) -> bool {
+        self.bans.unban(ip)
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.is_banned(ip)
+    }
+
+    /// the bans not expired
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.list()
+    }
+
+    /// the outbound queues of the users of this node
+    pub fn outbox_stats(&self) -> OutboxStats {
+        self.relay.outbox_metrics().stats()
+    }
+
+    /// on this node or, if a cluster, on another
+    pub fn is_online(&self, id: &str) -> bool {
+        self.relay.directory.contains(id)
+    }
+
+    /// a notice of the server to every user online
+    pub async fn broadcast_notice(&self, msg: impl Into<String>) -> Result<()> {
+        self.relay.metrics().chat_room_sent();
+        self.chat_room
+            .ask(BroadcastNotice(msg.into()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    /// a message to the user `to`, shown as from `from`, which is not required to be a user
+    pub async fn send_to(
+        &self,
+        to: impl Into<String>,
+        from: impl Into<String>,
+        msg: impl Into<String>,
+    ) -> Result<()> {
+        let to = to.into();
+        let msg = SendMsg {
+            from: from.into(),
+            to: to.clone(),
+            msg: msg.into(),
+        };
+        let delivered = self.relay.send_msg(msg, &self.chat_room).await;
+
+        if delivered {
+            Ok(())
+        } else {
+            Err(Error::UserNotFound(to))
+        }
+    }
+
+    /// the events from now on, a subscriber lagging too far behind skips the oldest
+    pub fn subscribe(&self) -> BoxStream<'static, ChatEvent> {
+        stream::unfold(self.relay.events().subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("chat event subscriber lagged, {} events skipped", skipped)
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// whether the chat room and its pubsubs answer within `deadline`, and the listener
+    /// of `App::serve` is accepting
+    pub async fn readiness(&self, deadline: Duration) -> Readiness {
+        let mut components = ChatRoom::probe(&self.chat_room, self.relay.metrics(), deadline).await;
+        if let Some(listener) = self.listener.status() {
+            components.insert("listener", listener);
+        }
+
+        Readiness::new(components)
+    }
+
+    /// close every user with going away, waiting at most `timeout`, then stop the chat room
+    pub async fn shutdown(&self, timeout: Duration) {
+        ChatRoom::shutdown(&self.chat_room, self.relay.metrics(), timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod test_chat_handle {
+    use super::*;
+    use crate::{
+        test_client::{serve_routes, TestClient},
+        App,
+    };
+
+    #[tokio::test]
+    async fn talk_and_observe() {
+        let (routes, chat) = App::new("", vec!["*".to_string()]).build().unwrap();
+        let addr = serve_routes(routes).await;
+        let mut events = chat.subscribe();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            assert_eq!(
+                events.next().await,
+                Some(ChatEvent::Online {
+                    id: a.id.clone(),
+                    name: a.id[..5].to_string(),
+                    bot: false,
+                })
+            );
+            let mut b = TestClient::connect(addr).await;
+            events.next().await;
+
+            a.talk_to(&b.id, "hi").await;
+            assert_eq!(
+                events.next().await,
+                Some(ChatEvent::Message {
+                    from: a.id.clone(),
+                    to: b.id.clone(),
+                    msg: "hi".to_string().into(),
+                })
+            );
+
+            chat.send_to(&a.id, "bot", "hello").await.unwrap();
+            let msg = a
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["from"], "bot");
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "hello");
+            assert!(matches!(
+                chat.send_to("nobody", "bot", "hello").await,
+                Err(Error::UserNotFound(_))
+            ));
+
+            chat.broadcast_notice("maintenance at noon").await.unwrap();
+            for client in [&mut a, &mut b] {
+                let notice = client
+                    .recv_until(|data| data["msg_type"]["notice"].is_object())
+                    .await
+                    .unwrap();
+                assert_eq!(notice["msg_type"]["notice"]["msg"], "maintenance at noon");
+            }
+
+            let b_id = b.id.clone();
+            drop(b);
+            loop {
+                match events.next().await.unwrap() {
+                    ChatEvent::Offline { id } if id == b_id => break,
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(chat.online_users().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,80 @@
+//! what `/healthz` and `/readyz` report, for the probes of a container orchestrator
+
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// not answered within the deadline
+    Timeout,
+    Stopped,
+    /// not asked, the chat room did not answer
+    Unknown,
+    /// the listener is not accepting yet
+    Starting,
+    ShuttingDown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Readiness {
+    /// every component is ok
+    pub ready: bool,
+    pub components: BTreeMap<&'static str, Status>,
+}
+
+impl Readiness {
+    pub fn new(components: BTreeMap<&'static str, Status>) -> Self {
+        Self {
+            ready: components.values().all(|status| *status == Status::Ok),
+            components,
+        }
+    }
+}
+
+const UNMANAGED: u8 = 0;
+const STARTING: u8 = 1;
+const ACCEPTING: u8 = 2;
+const SHUTTING_DOWN: u8 = 3;
+
+/// the listener of `App::serve`, unmanaged if the embedding application serves the routes
+#[derive(Debug)]
+pub(crate) struct ListenerState(AtomicU8);
+
+impl Default for ListenerState {
+    fn default() -> Self {
+        Self(AtomicU8::new(UNMANAGED))
+    }
+}
+
+impl ListenerState {
+    pub fn starting(&self) {
+        self.0.store(STARTING, Ordering::Relaxed);
+    }
+
+    pub fn accepting(&self) {
+        // never back once shutting down
+        let _ = self
+            .0
+            .compare_exchange(STARTING, ACCEPTING, Ordering::Relaxed, Ordering::Relaxed);
+    }
+
+    pub fn shutting_down(&self) {
+        self.0.store(SHUTTING_DOWN, Ordering::Relaxed);
+    }
+
+    /// `None` if unmanaged
+    pub fn status(&self) -> Option<Status> {
+        match self.0.load(Ordering::Relaxed) {
+            STARTING => Some(Status::Starting),
+            ACCEPTING => Some(Status::Ok),
+            SHUTTING_DOWN => Some(Status::ShuttingDown),
+            _ => None,
+        }
+    }
+}
@@ -1,94 +1,607 @@
-mod socket;
 mod cipher;
-use std::{io, net::SocketAddr};
+mod socket;
+use std::{
+    future::Future,
+    io,
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::routes::home::{all_online_users, web_socket_connection};
-use axum::{http::HeaderValue, routing::get, Extension, Router};
-use chat::ChatRoom;
-use log::{debug, info};
-use tokio::net::TcpListener;
-use tower_http::cors::{AllowOrigin, CorsLayer};
+use crate::routes::{
+    admin::{admin_auth, ban, bans, call_records, connected_user, connected_users, kick, unban},
+    bot::{bot_connection, BotAccount},
+    health::{healthz, readyz, ReadinessTimeout},
+    home::{all_online_users, web_socket_connection},
+    metrics::metrics,
+};
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Extension, Router,
+};
+use axum_server::Handle;
+use chat::{ChatRoom, UserOptions};
+use client_ip::TrustedProxies;
+use cluster::ClusterOptions;
+use filter::{MessageFilter, MessageFilters};
+use limit::{ConnectionLimiter, ConnectionLimits, MessageLimits};
+use signal::{CallRecorder, JsonLinesRecorder, MemoryRecorder, SignalPolicy};
+use tokio::sync::broadcast;
+use tower_http::cors::CorsLayer;
+use tracing::{debug, error, info};
 
 mod chat;
+pub mod client_ip;
+pub mod cluster;
+pub mod config;
+pub mod error;
+pub mod filter;
+pub mod handle;
+pub mod health;
+pub mod limit;
+pub mod logging;
+pub mod metrics;
 pub(crate) mod models;
+pub mod moderation;
 pub mod routes;
-mod signal;
+pub mod signal;
 pub mod state;
+pub mod stun;
+#[cfg(test)]
+mod test_client;
+pub mod tls;
+pub mod webhook;
 
-use state::new_allow_origin_state;
+pub use chat::HeartbeatOptions;
+pub use handle::{ChatEvent, ChatHandle};
+pub use socket::{OutboxOptions, OutboxStats, SlowConsumer};
+pub use state::OriginPolicy;
+use stun::StunServer;
+use tls::{redirect_routes, TlsOptions};
+use webhook::{WebhookOptions, Webhooks};
 
 pub struct App {
     addr: String,
-    allow_urls: Vec<String>,
+    origin_policy: OriginPolicy,
+    path_prefix: Option<String>,
+    assets: Option<PathBuf>,
+    stun_addr: Option<String>,
+    signal_policy: SignalPolicy,
+    call_logs: Vec<CallLog>,
+    admin_token: Option<String>,
+    metrics: bool,
+    metrics_token: Option<String>,
+    bots: Vec<BotAccount>,
+    tls: Option<TlsOptions>,
+    webhooks: Vec<WebhookOptions>,
+    cluster: Option<ClusterOptions>,
+    outbox: OutboxOptions,
+    heartbeat: HeartbeatOptions,
+    message_limits: Option<MessageLimits>,
+    message_filters: MessageFilters,
+    connection_limits: ConnectionLimits,
+    trusted_proxies: TrustedProxies,
+    readiness_timeout: Duration,
+    shutdown_timeout: Duration,
+}
+
+/// where the call lifecycle events are recorded
+pub enum CallLog {
+    /// append JSON lines to the file
+    File(PathBuf),
+    /// keep the latest records in memory, exposed by `/admin/calls`
+    Memory(usize),
 }
 
 impl App {
     pub fn new(addr: impl AsRef<str>, allow_urls: Vec<String>) -> Self {
         Self {
             addr: addr.as_ref().to_string(),
-            allow_urls,
+            origin_policy: OriginPolicy::from_list(allow_urls),
+            path_prefix: None,
+            assets: Some(PathBuf::from("assets")),
+            stun_addr: None,
+            signal_policy: SignalPolicy::default(),
+            call_logs: vec![],
+            admin_token: None,
+            metrics: false,
+            metrics_token: None,
+            bots: vec![],
+            tls: None,
+            webhooks: vec![],
+            cluster: None,
+            outbox: OutboxOptions::default(),
+            heartbeat: HeartbeatOptions::default(),
+            message_limits: None,
+            message_filters: MessageFilters::default(),
+            connection_limits: ConnectionLimits::unlimited(),
+            trusted_proxies: TrustedProxies::default(),
+            readiness_timeout: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_secs(10),
         }
     }
 
+    /// which origins of the UI may connect, replaces the `allow_urls` of `new`
+    pub fn with_origin_policy(mut self, origin_policy: OriginPolicy) -> Self {
+        self.origin_policy = origin_policy;
+        self
+    }
+
+    /// mount every route under `prefix`, e.g. `/chat` serves `/chat/ws`
+    pub fn with_path_prefix(mut self, prefix: impl AsRef<str>) -> Self {
+        let prefix = prefix.as_ref().trim_end_matches('/');
+        self.path_prefix =
+            (!prefix.is_empty()).then(|| format!("/{}", prefix.trim_start_matches('/')));
+        self
+    }
+
+    /// serve `/assets` and `/favicon.ico` from `dir`, `None` to leave them to the embedding application
+    pub fn with_assets(mut self, dir: Option<PathBuf>) -> Self {
+        self.assets = dir;
+        self
+    }
+
+    /// record the call lifecycle events
+    pub fn with_call_log(mut self, call_log: CallLog) -> Self {
+        self.call_logs.push(call_log);
+        self
+    }
+
+    /// enable `/admin`, requests need the bearer `token`
+    pub fn with_admin_token(mut self, token: impl AsRef<str>) -> Self {
+        self.admin_token = Some(token.as_ref().to_string());
+        self
+    }
+
+    /// serve `/metrics` for Prometheus, requests need the bearer `token` if any
+    pub fn with_metrics(mut self, token: Option<String>) -> Self {
+        self.metrics = true;
+        self.metrics_token = token;
+        self
+    }
+
+    /// a bot may connect to `/bot/ws` with the bearer `key`, and is online as `bot-<name>`
+    pub fn with_bot(mut self, name: impl AsRef<str>, key: impl AsRef<str>) -> Self {
+        self.bots.push(BotAccount {
+            name: name.as_ref().to_string(),
+            key: key.as_ref().to_string().into(),
+        });
+        self
+    }
+
+    /// validation of the WebRTC signals forwarded between users
+    pub fn with_signal_policy(mut self, signal_policy: SignalPolicy) -> Self {
+        self.signal_policy = signal_policy;
+        self
+    }
+
+    /// also answer STUN Binding requests on the UDP `addr`
+    pub fn with_stun(mut self, addr: impl AsRef<str>) -> Self {
+        self.stun_addr = Some(addr.as_ref().to_string());
+        self
+    }
+
+    /// how long the users have to flush the messages in flight on SIGTERM or Ctrl-C
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// how long `/readyz` waits for the chat room and its pubsubs to answer
+    pub fn with_readiness_timeout(mut self, timeout: Duration) -> Self {
+        self.readiness_timeout = timeout;
+        self
+    }
+
+    /// serve HTTPS and WSS, certificates are reloaded on SIGHUP or file change
+    pub fn with_tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// POST the presence events, and the messages opted in, to `webhook.url`
+    pub fn with_webhook(mut self, webhook: WebhookOptions) -> Self {
+        self.webhooks.push(webhook);
+        self
+    }
+
+    /// a node of a cluster, sharing the users with the other nodes on the bus
+    pub fn with_cluster(mut self, cluster: ClusterOptions) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// how many frames may wait for each user, and what to do with a client too slow
+    pub fn with_outbox(mut self, outbox: OutboxOptions) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    /// how often the clients are pinged, and how long they may stay silent
+    pub fn with_heartbeat(mut self, heartbeat: HeartbeatOptions) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// how fast a user may send, `None` for no limit, the default
+    pub fn with_message_limits(mut self, limits: Option<MessageLimits>) -> Self {
+        self.message_limits = limits;
+        self
+    }
+
+    /// check the messages between users by `filter`, after the filters added before
+    pub fn with_message_filter(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.message_filters.push(Arc::new(filter));
+        self
+    }
+
+    /// how many WebSockets of `/ws` an address may hold and open, unlimited by default.
+    /// Behind a reverse proxy list it by `with_trusted_proxies`, or else every client
+    /// has the address of the proxy and shares its limits
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.connection_limits = limits;
+        self
+    }
+
+    /// the reverse proxies trusted to tell the address of the client,
+    /// by `Forwarded` or `X-Forwarded-For`
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
     pub async fn run(&self) -> io::Result<()> {
-        let routes = self
-            .build_routes()
-            .into_make_service_with_connect_info::<SocketAddr>();
+        if let Some(stun_addr) = &self.stun_addr {
+            let stun = StunServer::bind(stun_addr).await?;
+            tokio::spawn(stun.run());
+        }
 
         let listener = if cfg!(debug_assertions) {
             debug!("Debug environment");
 
             use listenfd::ListenFd;
             let mut listenfd = ListenFd::from_env();
-            match listenfd.take_tcp_listener(0).unwrap() {
+            match listenfd.take_tcp_listener(0)? {
                 // if we are given a tcp listener on listen fd 0, we use that one
                 Some(listener) => {
                     debug!("Hot Reloading");
-                    listener.set_nonblocking(true).unwrap();
-                    TcpListener::from_std(listener).unwrap()
+                    listener
                 }
                 // otherwise fall back to local listening
-                None => TcpListener::bind(self.addr.clone()).await.unwrap(),
+                None => TcpListener::bind(&self.addr)?,
             }
         } else {
-            TcpListener::bind(self.addr.clone()).await.unwrap()
+            TcpListener::bind(&self.addr)?
         };
 
-        axum::serve(listener, routes).await
+        self.serve(listener, shutdown_signal()).await
     }
 
-    fn build_routes(&self) -> Router {
+    pub(crate) async fn serve(
+        &self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> io::Result<()> {
+        listener.set_nonblocking(true)?;
+        let (routes, chat) = self.build()?;
+        let routes = routes.into_make_service_with_connect_info::<SocketAddr>();
+
+        let handle = Handle::new();
+        chat.listener.starting();
+        tokio::spawn({
+            let (handle, listener) = (handle.clone(), chat.listener.clone());
+            async move {
+                if handle.listening().await.is_some() {
+                    listener.accepting();
+                }
+            }
+        });
+        let mut server = match &self.tls {
+            None => tokio::spawn(
+                axum_server::from_tcp(listener)
+                    .handle(handle.clone())
+                    .serve(routes),
+            ),
+            Some(tls) => {
+                let tls_config = tls.load().await?;
+                tokio::spawn(tls.clone().watch(tls_config.clone()));
+
+                if let Some(redirect_addr) = &tls.redirect_addr {
+                    let https_port = listener.local_addr()?.port();
+                    let redirect_listener = TcpListener::bind(redirect_addr)?;
+                    redirect_listener.set_nonblocking(true)?;
+                    info!("Redirecting HTTP on {} to HTTPS", redirect_addr);
+                    let redirect = axum_server::from_tcp(redirect_listener)
+                        .handle(handle.clone())
+                        .serve(redirect_routes(https_port).into_make_service());
+                    tokio::spawn(async move {
+                        if let Err(e) = redirect.await {
+                            error!("HTTP redirect listener: {:?}", e);
+                        }
+                    });
+                }
+
+                tokio::spawn(
+                    axum_server::from_tcp_rustls(listener, tls_config)
+                        .handle(handle.clone())
+                        .serve(routes),
+                )
+            }
+        };
+
+        tokio::select! {
+            res = &mut server => return res?,
+            _ = shutdown => {}
+        }
+
+        info!("Shutting down in {:?}", self.shutdown_timeout);
+        // stop accepting, the upgraded WebSockets are not waited here
+        chat.listener.shutting_down();
+        handle.graceful_shutdown(Some(self.shutdown_timeout));
+        chat.shutdown(self.shutdown_timeout).await;
+
+        server.await?
+    }
+
+    /// the routes and the chat room, for embedding into another axum application,
+    /// STUN, TLS and shutdown are left to the embedding application.
+    /// Serve with `into_make_service_with_connect_info::<SocketAddr>()`: without it
+    /// the address of a client is unknown, so the bans and the connection limits
+    /// are skipped and a warning is logged once
+    pub fn build(&self) -> io::Result<(Router, ChatHandle)> {
         let api_routes = Router::new().route("/allonlineusers", get(all_online_users));
 
-        let app = Router::new()
+        let mut call_recorders: Vec<Arc<dyn CallRecorder>> = vec![];
+        let mut memory_recorder = None;
+        for call_log in &self.call_logs {
+            match call_log {
+                CallLog::File(path) => {
+                    call_recorders.push(Arc::new(JsonLinesRecorder::open(path)?));
+                }
+                CallLog::Memory(capacity) => {
+                    let recorder = Arc::new(MemoryRecorder::new(*capacity));
+                    memory_recorder = Some(recorder.clone());
+                    call_recorders.push(recorder);
+                }
+            }
+        }
+        let call_recorder: Option<Arc<dyn CallRecorder>> = match call_recorders.len() {
+            0 => None,
+            1 => call_recorders.pop(),
+            _ => Some(Arc::new(call_recorders)),
+        };
+
+        let mut app = Router::new()
             .route("/", get(|| async { "Running" }))
-            .route("/ws", get(web_socket_connection))
-            .nest("/api", api_routes)
-            .nest_service("/assets", tower_http::services::ServeDir::new("assets"))
-            .nest_service(
-                "/favicon.ico",
-                tower_http::services::ServeFile::new("assets/favicon.ico"),
+            .route("/healthz", get(healthz))
+            .route(
+                "/readyz",
+                get(readyz).layer(Extension(ReadinessTimeout(self.readiness_timeout))),
             )
-            .with_state(new_allow_origin_state(self.allow_urls.clone()))
+            .route(
+                "/ws",
+                get(web_socket_connection).layer(Extension(Arc::new(ConnectionLimiter::new(
+                    self.connection_limits,
+                )))),
+            )
+            .nest("/api", api_routes)
+            .with_state(Arc::new(self.origin_policy.clone()));
+
+        if let Some(assets) = &self.assets {
+            app = app
+                .nest_service("/assets", tower_http::services::ServeDir::new(assets))
+                .nest_service(
+                    "/favicon.ico",
+                    tower_http::services::ServeFile::new(assets.join("favicon.ico")),
+                );
+        }
+
+        if !self.bots.is_empty() {
+            app = app.route(
+                "/bot/ws",
+                get(bot_connection).layer(Extension(Arc::new(self.bots.clone()))),
+            );
+        }
+
+        if let Some(token) = &self.admin_token {
+            let mut admin_routes = Router::new()
+                .route("/users", get(connected_users))
+                .route("/users/:id", get(connected_user))
+                .route("/users/:id/kick", post(kick))
+                .route("/bans", get(bans).post(ban))
+                .route("/bans/:ip", delete(unban));
+            if let Some(recorder) = memory_recorder {
+                admin_routes = admin_routes
+                    .route("/calls", get(call_records))
+                    .layer(Extension(recorder));
+            }
+
+            app = app.nest(
+                "/admin",
+                admin_routes.layer(middleware::from_fn_with_state(
+                    Arc::new(token.clone()),
+                    admin_auth,
+                )),
+            );
+        }
+
+        if self.metrics {
+            let mut metrics_route = get(metrics);
+            if let Some(token) = &self.metrics_token {
+                metrics_route = metrics_route.layer(middleware::from_fn_with_state(
+                    Arc::new(token.clone()),
+                    admin_auth,
+                ));
+            }
+            app = app.route("/metrics", metrics_route);
+        }
+
+        let (events, _) = broadcast::channel(1024);
+        let webhooks = if self.webhooks.is_empty() {
+            None
+        } else {
+            Some(Webhooks::spawn(self.webhooks.clone())?)
+        };
+        let chat = ChatRoom::spawn(
+            self.signal_policy.clone(),
+            call_recorder,
+            events,
+            webhooks,
+            self.cluster.clone(),
+            UserOptions {
+                outbox: self.outbox.clone(),
+                heartbeat: self.heartbeat.clone(),
+                message_limits: self.message_limits,
+                message_filters: self.message_filters.clone(),
+            },
+        );
+
+        let mut app = app
             .layer(self.cors())
-            .layer(Extension(ChatRoom::new()));
+            .layer(Extension(Arc::new(self.trusted_proxies.clone())))
+            .layer(Extension(chat.clone()));
+        if let Some(prefix) = &self.path_prefix {
+            app = Router::new().nest(prefix, app);
+        }
 
-        app
+        Ok((app, chat))
     }
 
     fn cors(&self) -> CorsLayer {
-        let allow_origins = if self.allow_urls.iter().any(|url| url == "*") {
-            AllowOrigin::any()
-        } else {
-            AllowOrigin::list(
-                self.allow_urls
-                    .iter()
-                    .map(|url| url.parse::<HeaderValue>().unwrap())
-                    .collect::<Vec<_>>(),
-            )
-        };
+        info!("Cros allow origins: {:?}", self.origin_policy);
+        CorsLayer::new().allow_origin(self.origin_policy.cors())
+    }
+}
+
+/// Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot listen Ctrl-C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("cannot listen SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+    use crate::test_client::{Received, TestClient};
+    use tokio::sync::oneshot;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    #[tokio::test]
+    async fn close_every_user_going_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-        info!("Cros allow origins: {:?}", allow_origins);
-        CorsLayer::new().allow_origin(allow_origins)
+        let app = App::new("127.0.0.1:0", vec!["*".to_string()])
+            .with_shutdown_timeout(Duration::from_secs(1));
+        let server = tokio::spawn(async move {
+            app.serve(listener, async {
+                let _ = shutdown_rx.await;
+            })
+            .await
+        });
+
+        let mut a = TestClient::connect(addr).await;
+        let mut b = TestClient::connect(addr).await;
+        a.talk_to(&b.id, "before shutdown").await;
+        let msg = b
+            .recv_until(|data| data["msg_type"]["msg"].is_object())
+            .await
+            .unwrap();
+        assert_eq!(msg["msg_type"]["msg"]["msg"], "before shutdown");
+
+        shutdown_tx.send(()).unwrap();
+
+        for client in [&mut a, &mut b] {
+            let frame = loop {
+                match client.recv().await {
+                    Received::Close(frame) => break frame,
+                    Received::Data(_) => continue,
+                }
+            };
+            assert_eq!(frame.unwrap().code, CloseCode::Away);
+        }
+
+        let res = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server stopped in time");
+        assert!(res.unwrap().is_ok());
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_embed {
+    use super::*;
+    use crate::test_client::{http_get, serve_routes, TestClient};
+    use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+    #[tokio::test]
+    async fn mount_under_prefix() {
+        let (chat_routes, chat) = App::new("", vec![])
+            .with_origin_policy(OriginPolicy::Predicate(Arc::new(|origin| {
+                origin.starts_with("http://localhost")
+            })))
+            .with_path_prefix("/chat/")
+            .with_assets(None)
+            .build()
+            .unwrap();
+        let host = Router::new()
+            .route("/", get(|| async { "host" }))
+            .merge(chat_routes);
+        let addr = serve_routes(host).await;
+
+        assert_eq!(http_get(addr, "/", &[]).await, (200, "host".to_string()));
+        assert_eq!(http_get(addr, "/chat", &[]).await.1, "Running");
+        assert_eq!(http_get(addr, "/chat/assets/x.js", &[]).await.0, 404);
+
+        let client = TestClient::connect_path(addr, "/chat/ws").await;
+        let users = chat.online_users().await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].id, client.id);
+
+        let mut request = format!("ws://{addr}/chat/ws")
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("origin", "https://elsewhere.example".parse().unwrap());
+        request
+            .headers_mut()
+            .insert("user-agent", "test client".parse().unwrap());
+        let Err(tungstenite::Error::Http(response)) =
+            tokio_tungstenite::connect_async(request).await
+        else {
+            panic!("expected the origin rejected");
+        };
+        assert_eq!(response.status(), 401);
     }
 }
@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// `burst` tokens, refilled evenly over `period`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Rate {
+    pub fn new(burst: u32, period: Duration) -> Self {
+        Self { burst, period }
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket
+    pub fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst as f64,
+            last: now,
+        }
+    }
+
+    /// take `n` tokens if there are enough
+    pub fn try_take(&mut self, n: u32, now: Instant) -> bool {
+        self.refill(now);
+
+        if self.tokens < n as f64 {
+            return false;
+        }
+        self.tokens -= n as f64;
+        true
+    }
+
+    /// whether there are `n` tokens, none taken
+    pub fn has(&mut self, n: u32, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= n as f64
+    }
+
+    /// a full bucket is the same as a new one, so it can be forgotten
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate.burst as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * self.rate.tokens_per_sec()).min(self.rate.burst as f64);
+        self.last = now;
+    }
+}
+
+/// how many WebSockets an address may hold and open, against a host flooding
+/// the chat room with users. An IPv6 client is counted by its /64, as a host
+/// usually holds one and could change its address every connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionLimits {
+    /// open at once, 0 for any
+    pub per_ip: usize,
+    /// new connections, `None` for any
+    pub new_per_ip: Option<Rate>,
+}
+
+impl ConnectionLimits {
+    /// any connections, the default of `App::new`
+    pub fn unlimited() -> Self {
+        Self {
+            per_ip: 0,
+            new_per_ip: None,
+        }
+    }
+}
+
+/// the default of the config, 20 open and 20 new a minute
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            per_ip: 20,
+            new_per_ip: Some(Rate::new(20, Duration::from_secs(60))),
+        }
+    }
+}
+
+/// the address counted for `ip`: itself if IPv4, its /64 if IPv6,
+/// the same for the limits and the bans
+pub(crate) fn limited_addr(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & (u128::MAX << 64)).into()),
+        ip => ip,
+    }
+}
+
+/// why a connection is refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refused {
+    TooMany,
+    TooFast,
+}
+
+#[derive(Debug)]
+struct Admitted {
+    open: usize,
+    bucket: Option<TokenBucket>,
+}
+
+/// the connections of every address
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    ips: Mutex<HashMap<IpAddr, Admitted>>,
+    swept: Mutex<Instant>,
+}
+
+/// the addresses above which the idle ones are forgotten
+const SWEEP_AT: usize = 4096;
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            ips: Mutex::default(),
+            swept: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// a slot of `ip`, held as long as the connection is open
+    pub fn admit(self: &Arc<Self>, ip: IpAddr, now: Instant) -> Result<ConnectionSlot, Refused> {
+        let ip = limited_addr(ip);
+        let mut ips = lock(&self.ips);
+        if ips.len() >= SWEEP_AT {
+            self.sweep(&mut ips, now);
+        }
+
+        let admitted = ips.entry(ip).or_insert_with(|| Admitted {
+            open: 0,
+            bucket: self
+                .limits
+                .new_per_ip
+                .map(|rate| TokenBucket::new(rate, now)),
+        });
+        if self.limits.per_ip > 0 && admitted.open >= self.limits.per_ip {
+            return Err(Refused::TooMany);
+        }
+        if let Some(bucket) = &mut admitted.bucket {
+            if !bucket.try_take(1, now) {
+                return Err(Refused::TooFast);
+            }
+        }
+        admitted.open += 1;
+
+        Ok(ConnectionSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// the connections open of `ip`, of its /64 if IPv6
+    pub fn open(&self, ip: &IpAddr) -> usize {
+        lock(&self.ips)
+            .get(&limited_addr(*ip))
+            .map_or(0, |admitted| admitted.open)
+    }
+
+    /// at most once a second, not to scan every address each connection
+    fn sweep(&self, ips: &mut HashMap<IpAddr, Admitted>, now: Instant) {
+        let mut swept = lock(&self.swept);
+        if now.saturating_duration_since(*swept) < Duration::from_secs(1) {
+            return;
+        }
+        *swept = now;
+        ips.retain(|_, admitted| !admitted.is_idle(now));
+    }
+}
+
+impl Admitted {
+    /// no connection open and a full bucket, the same as unknown
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.open == 0
+            && self
+                .bucket
+                .as_mut()
+                .is_none_or(|bucket| bucket.is_full(now))
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// released once dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut ips = lock(&self.limiter.ips);
+        if let Some(admitted) = ips.get_mut(&self.ip) {
+            admitted.open = admitted.open.saturating_sub(1);
+            if admitted.is_idle(Instant::now()) {
+                ips.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// how fast a user may send, and how one sending faster is punished: warned once,
+/// muted if it goes on, disconnected if it still goes on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageLimits {
+    /// messages to other users
+    pub messages: Rate,
+    /// bytes of every frame received
+    pub bytes: Rate,
+    /// violations before muted
+    pub mute_after: u32,
+    pub mute_for: Duration,
+    /// violations before disconnected, the messages sent while muted are violations too
+    pub disconnect_after: u32,
+    /// the violations are forgotten once none for this long
+    pub forget_after: Duration,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        Self {
+            messages: Rate::new(20, Duration::from_secs(10)),
+            bytes: Rate::new(64 * 1024, Duration::from_secs(10)),
+            mute_after: 3,
+            mute_for: Duration::from_secs(60),
+            disconnect_after: 10,
+            forget_after: Duration::from_secs(120),
+        }
+    }
+}
+
+/// what to do with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    /// the first violation, dropped and the user warned
+    Warn,
+    /// dropped silently, not to answer a flood with a flood
+    Drop,
+    /// dropped, and the messages of the user are dropped until then
+    Mute(Instant),
+    Disconnect,
+}
+
+/// the limits of a user
+#[derive(Debug)]
+pub struct MessageLimiter {
+    limits: MessageLimits,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    violations: u32,
+    last_violation: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+impl MessageLimiter {
+    pub fn new(limits: MessageLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            messages: TokenBucket::new(limits.messages, now),
+            bytes: TokenBucket::new(limits.bytes, now),
+            violations: 0,
+            last_violation: None,
+            muted_until: None,
+        }
+    }
+
+    /// a frame of `len` bytes, a message to another user or not
+    pub fn check(&mut self, len: usize, message: bool, now: Instant) -> Verdict {
+        let muted = self.muted_until.is_some_and(|until| until > now);
+        let len = u32::try_from(len).unwrap_or(u32::MAX);
+        // the bytes are taken even of a message over its limit, a flood costs both
+        let bytes_ok = self.bytes.try_take(len, now);
+        let messages_ok = !message || self.messages.try_take(1, now);
+        // the mute is of the messages, the signals and the blocks of a muted user
+        // are held by the bytes alone
+        if !(muted && message) && bytes_ok && messages_ok {
+            return Verdict::Pass;
+        }
+
+        if self
+            .last_violation
+            .is_some_and(|last| now.saturating_duration_since(last) > self.limits.forget_after)
+        {
+            self.violations = 0;
+        }
+        self.violations += 1;
+        self.last_violation = Some(now);
+
+        if self.violations >= self.limits.disconnect_after {
+            Verdict::Disconnect
+        } else if muted {
+            Verdict::Drop
+        } else if self.violations >= self.limits.mute_after {
+            // muted beyond any time is disconnected
+            let Some(until) = now.checked_add(self.limits.mute_for) else {
+                return Verdict::Disconnect;
+            };
+            self.muted_until = Some(until);
+            Verdict::Mute(until)
+        } else if self.violations == 1 {
+            Verdict::Warn
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_token_bucket {
+    use super::*;
+
+    #[test]
+    fn take_and_refill() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(2, Duration::from_secs(10)), now);
+
+        assert!(bucket.try_take(1, now));
+        assert!(bucket.try_take(1, now));
+        assert!(!bucket.try_take(1, now));
+
+        // one token every 5 seconds
+        assert!(!bucket.try_take(1, now + Duration::from_secs(4)));
+        assert!(bucket.try_take(1, now + Duration::from_secs(5)));
+
+        assert!(!bucket.is_full(now + Duration::from_secs(5)));
+        assert!(bucket.is_full(now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn never_exceed_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(Rate::new(3, Duration::from_secs(1)), now);
+
+        let later = now + Duration::from_secs(3600);
+        assert!(bucket.try_take(3, later));
+        assert!(!bucket.try_take(1, later));
+    }
+}
+
+#[cfg(test)]
+mod test_connection_limiter {
+    use super::*;
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::from([203, 0, 113, n])
+    }
+
+    #[test]
+    fn cap_open_connections() {
+        let now = Instant::now();
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            per_ip: 2,
+            new_per_ip: None,
+        }));
+
+        let first = limiter.admit(ip(1), now).unwrap();
+        let _second = limiter.admit(ip(1), now).unwrap();
+        assert_eq!(limiter.admit(ip(1), now).unwrap_err(), Refused::TooMany);
+        // another address is not affected
+        let _other = limiter.admit(ip(2), now).unwrap();
+
+        drop(first);
+        assert_eq!(limiter.open(&ip(1)), 1);
+        let _third = limiter.admit(ip(1), now).unwrap();
+    }
+
+    #[test]
+    fn throttle_new_connections() {
+        let now = Instant::now();
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            per_ip: 0,
+            new_per_ip: Some(Rate::new(2, Duration::from_secs(10))),
+        }));
+
+        // closed at once, still counted
+        drop(limiter.admit(ip(1), now).unwrap());
+        drop(limiter.admit(ip(1), now).unwrap());
+        assert_eq!(limiter.admit(ip(1), now).unwrap_err(), Refused::TooFast);
+        assert!(limiter.admit(ip(1), now + Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn count_ipv6_by_the_64() {
+        let now = Instant::now();
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            per_ip: 2,
+            new_per_ip: None,
+        }));
+        let v6 = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let _first = limiter.admit(v6("2001:db8:0:1::1"), now).unwrap();
+        let _second = limiter.admit(v6("2001:db8:0:1:ffff::2"), now).unwrap();
+        assert_eq!(
+            limiter.admit(v6("2001:db8:0:1::3"), now).unwrap_err(),
+            Refused::TooMany
+        );
+        assert_eq!(limiter.open(&v6("2001:db8:0:1::42")), 2);
+        // another /64, and an IPv4 client mapped to IPv6 by itself
+        let _other = limiter.admit(v6("2001:db8:0:2::1"), now).unwrap();
+        let _mapped = limiter.admit(v6("::ffff:203.0.113.1"), now).unwrap();
+        assert_eq!(limiter.open(&ip(1)), 1);
+    }
+
+    #[test]
+    fn forget_idle_addresses() {
+        let limiter = Arc::new(ConnectionLimiter::new(ConnectionLimits {
+            per_ip: 1,
+            new_per_ip: None,
+        }));
+
+        drop(limiter.admit(ip(1), Instant::now()).unwrap());
+        assert!(lock(&limiter.ips).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test_message_limiter {
+    use super::*;
+
+    fn limits() -> MessageLimits {
+        MessageLimits {
+            messages: Rate::new(2, Duration::from_secs(10)),
+            bytes: Rate::new(100, Duration::from_secs(10)),
+            mute_after: 3,
+            mute_for: Duration::from_secs(60),
+            disconnect_after: 5,
+            forget_after: Duration::from_secs(120),
+        }
+    }
+
+    #[test]
+    fn escalate() {
+        let now = Instant::now();
+        let mut limiter = MessageLimiter::new(limits(), now);
+
+        assert_eq!(limiter.check(10, true, now), Verdict::Pass);
+        assert_eq!(limiter.check(10, true, now), Verdict::Pass);
+        // not a message, only the bytes count
+        assert_eq!(limiter.check(10, false, now), Verdict::Pass);
+
+        assert_eq!(limiter.check(10, true, now), Verdict::Warn);
+        assert_eq!(limiter.check(10, true, now), Verdict::Drop);
+        let until = now + Duration::from_secs(60);
+        assert_eq!(limiter.check(10, true, now), Verdict::Mute(until));
+
+        // muted, even once the bucket is refilled
+        let later = now + Duration::from_secs(30);
+        assert_eq!(limiter.check(10, true, later), Verdict::Drop);
+        assert_eq!(limiter.check(10, true, later), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn mute_only_messages() {
+        let now = Instant::now();
+        let mut limiter = MessageLimiter::new(limits(), now);
+        for _ in 0..5 {
+            limiter.check(10, true, now);
+        }
+
+        // the signals pass, and are no violations
+        for _ in 0..3 {
+            assert_eq!(limiter.check(10, false, now), Verdict::Pass);
+        }
+        // unless over the bytes
+        assert_eq!(limiter.check(50, false, now), Verdict::Drop);
+        assert_eq!(limiter.check(10, true, now), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn disconnect_muted_beyond_any_time() {
+        let now = Instant::now();
+        let mut limiter = MessageLimiter::new(
+            MessageLimits {
+                mute_for: Duration::MAX,
+                ..limits()
+            },
+            now,
+        );
+        for _ in 0..4 {
+            limiter.check(10, true, now);
+        }
+
+        assert_eq!(limiter.check(10, true, now), Verdict::Disconnect);
+    }
+
+    #[test]
+    fn limit_bytes() {
+        let now = Instant::now();
+        let mut limiter = MessageLimiter::new(limits(), now);
+
+        assert_eq!(limiter.check(90, false, now), Verdict::Pass);
+        assert_eq!(limiter.check(20, false, now), Verdict::Warn);
+    }
+
+    #[test]
+    fn unmute_and_forget() {
+        let now = Instant::now();
+        let mut limiter = MessageLimiter::new(limits(), now);
+        for _ in 0..2 {
+            limiter.check(10, true, now);
+        }
+        for _ in 0..3 {
+            limiter.check(10, true, now);
+        }
+
+        let unmuted = now + Duration::from_secs(61);
+        assert_eq!(limiter.check(10, true, unmuted), Verdict::Pass);
+
+        // a violation long after the others is the first again
+        let forgotten = now + Duration::from_secs(300);
+        for _ in 0..2 {
+            limiter.check(10, true, forgotten);
+        }
+        assert_eq!(limiter.check(10, true, forgotten), Verdict::Warn);
+    }
+}
@@ -0,0 +1,231 @@
+//! the logs, by `tracing`, as plain text or JSON lines
+//!
+//! every connection has a span of its user id and remote address. keys and message
+//! bodies never reach the logs at any level: the frames received and sent are never
+//! logged, and the types carrying a body or a key hold it `Redacted`
+
+use std::{
+    fmt::{self, Debug},
+    net::{IpAddr, SocketAddr},
+    ops::{Deref, DerefMut},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{error_span, field, Span};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Text,
+    /// a JSON object a line, with the fields of the connection span
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {s}")),
+        }
+    }
+}
+
+/// install the global subscriber, `level` is the same syntax as `RUST_LOG`;
+/// the logs of the dependencies using `log` are included
+pub fn init(level: &str, format: LogFormat) {
+    let filter = EnvFilter::builder().parse_lossy(level);
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let res = match format {
+        LogFormat::Text => builder.try_init(),
+        // the list of spans ignores the explicit parent of an event, the current span does not
+        LogFormat::Json => builder.json().with_span_list(false).try_init(),
+    };
+    if let Err(e) = res {
+        eprintln!("logging not initialized: {e}");
+    }
+}
+
+/// of a WebSocket connection, `user` is recorded once the user has an id, `client`
+/// only if behind a proxy; at the error level so the user and address are in every
+/// log of the connection
+pub fn connection_span(remote: Option<SocketAddr>, client: Option<IpAddr>) -> Span {
+    let span = error_span!(
+        "connection",
+        user = field::Empty,
+        remote = field::Empty,
+        client = field::Empty
+    );
+    if let Some(remote) = remote {
+        span.record("remote", field::display(remote));
+    }
+    if let Some(client) = client.filter(|client| Some(*client) != remote.map(|r| r.ip())) {
+        span.record("client", field::display(client));
+    }
+
+    span
+}
+
+/// a value never printed, `Debug` shows `<redacted>` whatever it is, and no `Display`
+/// so `to_string` cannot lose it; serialized as the value itself
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Redacted<T>(pub T);
+
+impl<T> Redacted<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Redacted<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Deref for Redacted<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Redacted<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[cfg(test)]
+mod test_logging {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::json;
+    use tracing::Level;
+
+    use super::*;
+    use crate::{
+        cluster::ClusterBody,
+        test_client::{serve, TestClient},
+        App,
+    };
+
+    /// every log written, to read once the chat is done
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Logs {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    const BODY: &str = "the body nobody should read";
+    const SDP: &str = "sdp nobody should read";
+
+    #[tokio::test]
+    async fn never_log_bodies() {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // the runtime of the test is of this thread only
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let addr = serve(App::new("", vec!["*".to_string()])).await;
+        let (a_id, b_id) = tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            a.talk_to(&b.id, BODY).await;
+            b.recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+
+            let offer = json!({ "type": "offer", "sdp": format!("v=0\r\n{SDP}") });
+            for (signal_type, value) in [("offer", offer.to_string()), ("stop", SDP.to_string())] {
+                a.send(json!({ "msg_type": { "signal": {
+                    "from_id": a.id,
+                    "to_id": b.id,
+                    "signal_type": signal_type,
+                    "value": value,
+                } } }))
+                .await;
+            }
+            b.recv_until(|data| data["msg_type"]["signal"]["signal_type"] == "stop")
+                .await
+                .unwrap();
+            // handled in order, the logs are written once the next message arrives
+            a.send(json!({ "unknown": BODY })).await;
+            a.talk_to(&b.id, "done").await;
+            b.recv_until(|data| data["msg_type"]["msg"]["msg"] == "done")
+                .await
+                .unwrap();
+
+            (a.id.clone(), b.id.clone())
+        })
+        .await
+        .unwrap();
+        let text = logs.text();
+        assert!(text.contains(&format!("user={a_id}")), "{text}");
+        assert!(text.contains(&format!("user={b_id}")), "{text}");
+        assert!(text.contains("remote=127.0.0.1:"), "{text}");
+        assert!(text.contains("received signal"), "{text}");
+        assert!(text.contains("received unknown data"), "{text}");
+        assert!(!text.contains(BODY), "{text}");
+        assert!(!text.contains(SDP), "{text}");
+    }
+
+    #[test]
+    fn redact_debug() {
+        let msg = ClusterBody::Msg {
+            from: "a".to_string(),
+            to: "b".to_string(),
+            msg: BODY.to_string().into(),
+        };
+        assert_eq!(
+            format!("{msg:?}"),
+            r#"Msg { from: "a", to: "b", msg: <redacted> }"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Redacted(BODY)).unwrap(),
+            format!("\"{BODY}\"")
+        );
+    }
+}
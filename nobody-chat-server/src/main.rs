@@ -1,38 +1,179 @@
+use std::{path::PathBuf, process::ExitCode};
+
 use clap::Parser;
-use log::{debug, info};
+use nobody_chat::{config::Config, logging, signal::MediaType, App};
+use tracing::{debug, error, info};
 
+/// Every option overrides the config file and the environment variables
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
+    /// TOML config file
+    #[arg(short, long, env = "NOBODY_CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the effective config and exit, fails if it is invalid
+    #[arg(long)]
+    check_config: bool,
+
     /// Listen address of App
     #[arg(short, long)]
-    addr: String,
+    addr: Option<String>,
+
+    /// Allowed origins of the UI, `*` for any
+    #[arg(long, value_delimiter = ',')]
+    allow_origins: Option<Vec<String>>,
+
+    /// Log level, the same syntax as `RUST_LOG`
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Log format, `text` or `json`
+    #[arg(long)]
+    log_format: Option<logging::LogFormat>,
+
+    /// UDP listen address of the embedded STUN responder, enables it
+    #[arg(long)]
+    stun_addr: Option<String>,
+
+    /// Max bytes of the SDP of an offer or answer signal
+    #[arg(long)]
+    max_sdp_size: Option<usize>,
+
+    /// Max bytes of an ICE candidate signal
+    #[arg(long)]
+    max_candidate_size: Option<usize>,
+
+    /// Drop ICE host candidates leaking private LAN addresses
+    #[arg(long)]
+    strip_host_candidates: bool,
+
+    /// Media users may not call with, e.g. `video,screen` to save bandwidth
+    #[arg(long, value_delimiter = ',')]
+    disable_media: Vec<MediaType>,
+
+    /// Append call lifecycle events to this JSON lines file
+    #[arg(long)]
+    call_log: Option<PathBuf>,
+
+    /// Keep the latest N call lifecycle events in memory, served by `/admin/calls`
+    #[arg(long)]
+    call_log_memory: Option<usize>,
+
+    /// Bearer token of the `/admin` endpoints, enables them
+    #[arg(long)]
+    admin_token: Option<String>,
+
+    /// PEM certificate chain, serves HTTPS and WSS together with `--tls-key`
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--tls-cert`
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Plain HTTP listen address redirecting to HTTPS
+    #[arg(long)]
+    tls_redirect_addr: Option<String>,
+}
+
+impl Args {
+    fn override_config(self, config: &mut Config) {
+        if let Some(addr) = self.addr {
+            config.server.addr = addr;
+        }
+        if let Some(allow_origins) = self.allow_origins {
+            config.server.allow_origins = allow_origins;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log.level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log.format = log_format;
+        }
+        if let Some(stun_addr) = self.stun_addr {
+            config.stun.enabled = true;
+            config.stun.addr = stun_addr;
+        }
+        if let Some(max_sdp_size) = self.max_sdp_size {
+            config.signal.max_sdp_size = max_sdp_size;
+        }
+        if let Some(max_candidate_size) = self.max_candidate_size {
+            config.signal.max_candidate_size = max_candidate_size;
+        }
+        if self.strip_host_candidates {
+            config.signal.strip_host_candidates = true;
+        }
+        config
+            .signal
+            .allowed_media
+            .retain(|media| !self.disable_media.contains(media));
+        if let Some(path) = self.call_log {
+            config.call_log.file = Some(path);
+        }
+        if let Some(capacity) = self.call_log_memory {
+            config.call_log.memory = capacity;
+        }
+        if let Some(token) = self.admin_token {
+            config.admin.enabled = true;
+            config.admin.token = Some(token);
+        }
+        if let (Some(cert), Some(key)) = (self.tls_cert, self.tls_key) {
+            config.tls.enabled = true;
+            config.tls.cert = Some(cert);
+            config.tls.key = Some(key);
+        }
+        if let Some(addr) = self.tls_redirect_addr {
+            config.tls.redirect_addr = Some(addr);
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     if cfg!(debug_assertions) {
         debug!("Dev mode");
         dotenv::dotenv().ok();
     }
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let check_config = args.check_config;
+
+    let config =
+        Config::load(args.config.take().as_deref(), std::env::vars()).and_then(|mut config| {
+            args.override_config(&mut config);
+            config.validate().map(|_| config)
+        });
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if check_config {
+        print!("{}", config.to_redacted_toml());
+        return ExitCode::SUCCESS;
+    }
 
-    env_logger::init();
+    logging::init(&config.log.level, config.log.format);
 
     info!("Nobody Chat start!");
-    info!("Listening: {}", args.addr);
-
-    let urls = if let Ok(allow_urls) = std::env::var("ALLOW_URLS") {
-        if let Ok(list) = serde_json::from_str(&allow_urls) {
-            list
-        } else {
-            vec![]
-        }
+    if config.tls.enabled {
+        info!("Listening HTTPS: {}", config.server.addr);
     } else {
-        vec![]
-    };
+        info!("Listening: {}", config.server.addr);
+    }
+    if config.stun.enabled {
+        info!("STUN: {}", config.stun.addr);
+    }
+
+    let app = App::from_config(&config);
+    if let Err(e) = app.run().await {
+        error!("{:?}", e);
+        return ExitCode::FAILURE;
+    }
 
-    let app = ::nobody_chat::App::new(args.addr, urls);
-    app.run().await.unwrap();
+    ExitCode::SUCCESS
 }
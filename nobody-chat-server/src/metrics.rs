@@ -0,0 +1,366 @@
+//! counters of the chat room, exposed in the Prometheus text format by `/metrics`
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Instant,
+};
+
+use crate::{signal::SignalType, socket::OutboxStats};
+
+/// upper bounds of the handler latency buckets, in seconds
+const BUCKETS: [f64; 10] = [
+    0.000_1, 0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SignalOutcome {
+    Forwarded,
+    /// by the signal policy or the limits
+    Denied,
+    /// the receiver is offline, or nothing left once checked
+    Dropped,
+}
+
+impl SignalOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SignalOutcome::Forwarded => "forwarded",
+            SignalOutcome::Denied => "denied",
+            SignalOutcome::Dropped => "dropped",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    handshakes_started: AtomicU64,
+    handshakes_failed: AtomicU64,
+    messages_routed: AtomicU64,
+    messages_dropped: AtomicU64,
+    decrypt_failures: AtomicU64,
+    /// sent to the chat room and not taken by its handlers yet
+    chat_room_queued: AtomicU64,
+    signals: Mutex<BTreeMap<(&'static str, SignalOutcome), u64>>,
+    handlers: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+/// a poisoned map is still a valid map
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn signal_type(signal_type: SignalType) -> &'static str {
+    match signal_type {
+        SignalType::Offer => "offer",
+        SignalType::Answer => "answer",
+        SignalType::NewCandidate => "newCandidate",
+        SignalType::RequestVideo => "requestVideo",
+        SignalType::RequestCall => "requestCall",
+        SignalType::Deny => "deny",
+        SignalType::Stop => "stop",
+    }
+}
+
+impl Metrics {
+    pub fn handshake_started(&self) {
+        self.handshakes_started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshakes_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_routed(&self) {
+        self.messages_routed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn message_dropped(&self) {
+        self.messages_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrypt_failed(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// before sending anything to the chat room, a message to a stopped chat
+    /// room stays counted, there is nothing left to measure then
+    pub fn chat_room_sent(&self) {
+        self.chat_room_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// first thing of every handler of the chat room
+    pub fn chat_room_took(&self) {
+        self.chat_room_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn signal(&self, kind: SignalType, outcome: SignalOutcome) {
+        *lock(&self.signals)
+            .entry((signal_type(kind), outcome))
+            .or_default() += 1;
+    }
+
+    /// the time until the returned guard is dropped is of `handler`
+    pub fn time(self: &Arc<Self>, handler: &'static str) -> HandlerTimer {
+        HandlerTimer {
+            metrics: self.clone(),
+            handler,
+            started: Instant::now(),
+        }
+    }
+
+    fn observe(&self, handler: &'static str, seconds: f64) {
+        let mut handlers = lock(&self.handlers);
+        let histogram = handlers.entry(handler).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// the text format, with the gauges of the moment
+    ///
+    /// the mailboxes of the users are not measured: their handlers only push to
+    /// the outbox of the user, which never waits, so a user falling behind shows
+    /// in the outbox gauges instead
+    pub fn render(&self, users: usize, remote_users: usize, outbox: OutboxStats) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        let counter = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+        metric(
+            "nobody_chat_users",
+            "gauge",
+            "users connected to this node",
+            &users,
+        );
+        metric(
+            "nobody_chat_remote_users",
+            "gauge",
+            "users connected to the other nodes of the cluster",
+            &remote_users,
+        );
+        metric(
+            "nobody_chat_handshakes_started_total",
+            "counter",
+            "key exchanges started by browsers",
+            &counter(&self.handshakes_started),
+        );
+        metric(
+            "nobody_chat_handshakes_failed_total",
+            "counter",
+            "key exchanges failed",
+            &counter(&self.handshakes_failed),
+        );
+        metric(
+            "nobody_chat_messages_routed_total",
+            "counter",
+            "messages delivered to a user of this node or sent to another node",
+            &counter(&self.messages_routed),
+        );
+        metric(
+            "nobody_chat_messages_dropped_total",
+            "counter",
+            "messages to a user offline or dead",
+            &counter(&self.messages_dropped),
+        );
+        metric(
+            "nobody_chat_decrypt_failures_total",
+            "counter",
+            "frames received that could not be decrypted or read",
+            &counter(&self.decrypt_failures),
+        );
+        metric(
+            "nobody_chat_chat_room_queued",
+            "gauge",
+            "messages waiting in the mailbox of the chat room",
+            &counter(&self.chat_room_queued),
+        );
+        metric(
+            "nobody_chat_outbox_queued",
+            "gauge",
+            "frames waiting in the outbound queues of every user",
+            &outbox.queued,
+        );
+        metric(
+            "nobody_chat_outbox_queued_max",
+            "gauge",
+            "the most frames ever waiting in one outbound queue",
+            &outbox.deepest,
+        );
+        metric(
+            "nobody_chat_outbox_dropped_total",
+            "counter",
+            "presence updates dropped for slow users",
+            &outbox.dropped,
+        );
+        metric(
+            "nobody_chat_slow_disconnects_total",
+            "counter",
+            "users disconnected for being too slow",
+            &outbox.slow_disconnects,
+        );
+
+        out.push_str("# HELP nobody_chat_signals_total signals received, by type and outcome\n");
+        out.push_str("# TYPE nobody_chat_signals_total counter\n");
+        for ((kind, outcome), count) in lock(&self.signals).iter() {
+            let _ = writeln!(
+                out,
+                "nobody_chat_signals_total{{type=\"{kind}\",outcome=\"{}\"}} {count}",
+                outcome.as_str()
+            );
+        }
+
+        out.push_str(
+            "# HELP nobody_chat_handler_duration_seconds time handling a message, by handler\n",
+        );
+        out.push_str("# TYPE nobody_chat_handler_duration_seconds histogram\n");
+        for (handler, histogram) in lock(&self.handlers).iter() {
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "nobody_chat_handler_duration_seconds_bucket{{handler=\"{handler}\",le=\"{le}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "nobody_chat_handler_duration_seconds_bucket{{handler=\"{handler}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "nobody_chat_handler_duration_seconds_sum{{handler=\"{handler}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "nobody_chat_handler_duration_seconds_count{{handler=\"{handler}\"}} {}",
+                histogram.count
+            );
+        }
+
+        out
+    }
+}
+
+pub struct HandlerTimer {
+    metrics: Arc<Metrics>,
+    handler: &'static str,
+    started: Instant,
+}
+
+impl Drop for HandlerTimer {
+    fn drop(&mut self) {
+        self.metrics
+            .observe(self.handler, self.started.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::{
+        test_client::{http_get, serve, TestClient},
+        App,
+    };
+
+    /// the value of the sample `name`, labels included
+    fn sample(body: &str, name: &str) -> Option<f64> {
+        body.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+    }
+
+    #[tokio::test]
+    async fn count_what_happens() {
+        let app =
+            App::new("", vec!["*".to_string()]).with_metrics(Some("a metrics token".to_string()));
+        let addr = serve(app).await;
+        let auth = [("authorization", "Bearer a metrics token")];
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut a = TestClient::connect(addr).await;
+            let mut b = TestClient::connect(addr).await;
+            a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+                .await
+                .unwrap();
+
+            a.talk_to(&b.id, "hi").await;
+            b.recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            a.talk_to(&"nobody".to_string(), "hello?").await;
+            a.send(json!({ "msg_type": { "signal": {
+                "from_id": a.id,
+                "to_id": b.id,
+                "signal_type": "stop",
+                "value": "",
+            } } }))
+            .await;
+            b.recv_until(|data| data["msg_type"]["signal"].is_object())
+                .await
+                .unwrap();
+
+            let (status, body) = http_get(addr, "/metrics", &auth).await;
+            assert_eq!(status, 200);
+            assert_eq!(sample(&body, "nobody_chat_users"), Some(2.0));
+            assert_eq!(
+                sample(&body, "nobody_chat_handshakes_started_total"),
+                Some(2.0)
+            );
+            assert_eq!(
+                sample(&body, "nobody_chat_handshakes_failed_total"),
+                Some(0.0)
+            );
+            assert_eq!(
+                sample(&body, "nobody_chat_messages_routed_total"),
+                Some(1.0)
+            );
+            assert_eq!(
+                sample(&body, "nobody_chat_messages_dropped_total"),
+                Some(1.0)
+            );
+            assert_eq!(
+                sample(
+                    &body,
+                    r#"nobody_chat_signals_total{type="stop",outcome="forwarded"}"#
+                ),
+                Some(1.0)
+            );
+            assert_eq!(
+                sample(
+                    &body,
+                    r#"nobody_chat_handler_duration_seconds_count{handler="forword_signal"}"#
+                ),
+                Some(1.0)
+            );
+            // every message to the chat room was handled by the time it answered
+            assert_eq!(sample(&body, "nobody_chat_chat_room_queued"), Some(0.0));
+            assert!(sample(&body, "nobody_chat_outbox_queued_max").is_some());
+
+            assert_eq!(http_get(addr, "/metrics", &[]).await.0, 401);
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+//! what the admins see of the users, and the addresses they banned
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::RwLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{chat::UserRef, limit::limited_addr, models::UserId};
+
+/// a user of this node and its connection
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectedUser {
+    pub id: UserId,
+    pub name: String,
+    pub bot: bool,
+    pub remote: Option<SocketAddr>,
+    /// the client behind the trusted proxies, the peer if none
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    /// unix seconds
+    pub connected_at: u64,
+}
+
+impl From<UserRef> for ConnectedUser {
+    fn from(user: UserRef) -> Self {
+        Self {
+            id: user.id,
+            name: user.name,
+            bot: user.bot,
+            remote: user.connection.remote,
+            ip: user.connection.ip,
+            user_agent: user.connection.user_agent,
+            connected_at: unix_secs(user.connection.connected_at),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+/// an address refused until `until`, unix seconds
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// the banned addresses of this node, forgotten once expired or on restart.
+/// An IPv6 address is banned with its /64, as a host could change its address in it.
+/// There is no ban by resume token, a user has no session to resume:
+/// a new connection is a new user with a new id
+#[derive(Debug, Default)]
+pub struct Bans(RwLock<HashMap<IpAddr, (SystemTime, Option<String>)>>);
+
+impl Bans {
+    /// replaces a ban of the same address, `None` if `duration` is beyond any time
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: Option<String>) -> Option<Ban> {
+        let ip = limited_addr(ip);
+        let until = SystemTime::now().checked_add(duration)?;
+        let mut bans = self.0.write().unwrap_or_else(|e| e.into_inner());
+        bans.retain(|_, (until, _)| *until > SystemTime::now());
+        bans.insert(ip, (until, reason.clone()));
+
+        Some(Ban {
+            ip,
+            until: unix_secs(until),
+            reason,
+        })
+    }
+
+    /// `false` if not banned
+    pub fn unban(&self, ip: &IpAddr) -> bool {
+        let mut bans = self.0.write().unwrap_or_else(|e| e.into_inner());
+        bans.remove(&limited_addr(*ip))
+            .is_some_and(|(until, _)| until > SystemTime::now())
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        let bans = self.0.read().unwrap_or_else(|e| e.into_inner());
+        bans.get(&limited_addr(*ip))
+            .is_some_and(|(until, _)| *until > SystemTime::now())
+    }
+
+    /// the bans not expired
+    pub fn list(&self) -> Vec<Ban> {
+        let now = SystemTime::now();
+        let bans = self.0.read().unwrap_or_else(|e| e.into_inner());
+        let mut list: Vec<_> = bans
+            .iter()
+            .filter(|(_, (until, _))| *until > now)
+            .map(|(ip, (until, reason))| Ban {
+                ip: *ip,
+                until: unix_secs(*until),
+                reason: reason.clone(),
+            })
+            .collect();
+        list.sort_by_key(|ban| ban.ip);
+
+        list
+    }
+}
@@ -0,0 +1,299 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    error::Error,
+    handle::ChatHandle,
+    models::UserId,
+    moderation::{Ban, ConnectedUser},
+    signal::MemoryRecorder,
+};
+
+pub type AdminTokenState = Arc<String>;
+
+/// only requests with `Authorization: Bearer <admin token>` pass
+pub async fn admin_auth(
+    State(token): State<AdminTokenState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match auth {
+        Some(TypedHeader(Authorization(bearer))) if constant_eq(bearer.token(), &token) => {
+            next.run(req).await
+        }
+        _ => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+    }
+}
+
+/// the latest call records kept in memory
+pub async fn call_records(
+    Extension(recorder): Extension<Arc<MemoryRecorder>>,
+) -> impl IntoResponse {
+    Json(recorder.records())
+}
+
+/// the users of this node, oldest connection first
+pub async fn connected_users(Extension(chat): Extension<ChatHandle>) -> Json<Vec<ConnectedUser>> {
+    Json(chat.connected_users())
+}
+
+pub async fn connected_user(
+    Path(id): Path<UserId>,
+    Extension(chat): Extension<ChatHandle>,
+) -> Response {
+    match chat.connected_user(&id) {
+        Some(user) => Json(user).into_response(),
+        None => (StatusCode::NOT_FOUND, "User not found").into_response(),
+    }
+}
+
+/// close the socket of a user of this node
+pub async fn kick(Path(id): Path<UserId>, Extension(chat): Extension<ChatHandle>) -> Response {
+    match chat.kick(&id).await {
+        Ok(()) => {
+            info!("kicked {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(Error::UserNotFound(_)) => (StatusCode::NOT_FOUND, "User not found").into_response(),
+        Err(e) => {
+            warn!("kick {} failed: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn bans(Extension(chat): Extension<ChatHandle>) -> Json<Vec<Ban>> {
+    Json(chat.bans())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub ip: IpAddr,
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Banned {
+    pub ban: Ban,
+    /// the users of the address, disconnected
+    pub kicked: Vec<UserId>,
+}
+
+/// refuse the connections of an address for a while, and kick its users
+pub async fn ban(Extension(chat): Extension<ChatHandle>, Json(req): Json<BanRequest>) -> Response {
+    if req.duration_secs == 0 {
+        return (StatusCode::BAD_REQUEST, "duration_secs must be positive").into_response();
+    }
+
+    // the clients are canonical, `::ffff:198.51.100.7` is `198.51.100.7`
+    let ip = req.ip.to_canonical();
+    let Some((ban, kicked)) = chat
+        .ban(ip, Duration::from_secs(req.duration_secs), req.reason)
+        .await
+    else {
+        return (StatusCode::BAD_REQUEST, "duration_secs is too long").into_response();
+    };
+    info!("banned {} until {}, kicked {:?}", ban.ip, ban.until, kicked);
+    (StatusCode::CREATED, Json(Banned { ban, kicked })).into_response()
+}
+
+pub async fn unban(Path(ip): Path<IpAddr>, Extension(chat): Extension<ChatHandle>) -> Response {
+    let ip = ip.to_canonical();
+    if chat.unban(&ip) {
+        info!("unbanned {}", ip);
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Ban not found").into_response()
+    }
+}
+
+/// compares secrets without leaking the position of the first difference
+pub(crate) fn constant_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod test_admin {
+    use serde_json::{json, Value};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    use crate::{
+        client_ip::TrustedProxies,
+        test_client::{http_get, http_request, refused, serve, Received, TestClient},
+        App,
+    };
+
+    const AUTH: (&str, &str) = ("authorization", "Bearer admin token");
+
+    fn app() -> App {
+        App::new("", vec!["*".to_string()]).with_admin_token("admin token")
+    }
+
+    async fn closed(client: &mut TestClient) -> CloseCode {
+        loop {
+            match client.recv().await {
+                Received::Close(frame) => return frame.unwrap().code,
+                Received::Data(_) => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn list_users() {
+        let addr = serve(app()).await;
+        let a = TestClient::connect(addr).await;
+
+        let (status, _) = http_get(addr, "/admin/users", &[]).await;
+        assert_eq!(status, 401);
+
+        let (status, body) = http_get(addr, "/admin/users", &[AUTH]).await;
+        assert_eq!(status, 200, "{body}");
+        let users: Vec<Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0]["id"], a.id.as_str());
+        assert_eq!(users[0]["bot"], false);
+        assert_eq!(users[0]["user_agent"], "test client");
+        assert!(users[0]["remote"]
+            .as_str()
+            .unwrap()
+            .starts_with("127.0.0.1:"));
+
+        let (status, body) = http_get(addr, &format!("/admin/users/{}", a.id), &[AUTH]).await;
+        assert_eq!(status, 200);
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), users[0]);
+
+        let (status, _) = http_get(addr, "/admin/users/nobody", &[AUTH]).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn kick_user() {
+        let addr = serve(app()).await;
+        let mut a = TestClient::connect(addr).await;
+        let mut b = TestClient::connect(addr).await;
+        a.recv_until(|data| data["msg_type"]["userOnline"]["id"] == b.id.as_str())
+            .await
+            .unwrap();
+
+        let kick = format!("/admin/users/{}/kick", b.id);
+        let (status, _) = http_request(addr, "POST", &kick, &[AUTH], None).await;
+        assert_eq!(status, 204);
+        assert_eq!(closed(&mut b).await, CloseCode::Policy);
+        a.recv_until(|data| data["msg_type"]["userOffline"]["id"] == b.id.as_str())
+            .await
+            .unwrap();
+
+        let (status, _) = http_request(addr, "POST", &kick, &[AUTH], None).await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn ban_address() {
+        let addr = serve(app()).await;
+        let mut a = TestClient::connect(addr).await;
+
+        let ban = json!({ "ip": "127.0.0.1", "duration_secs": 0 });
+        let (status, _) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 400);
+        let ban = json!({ "ip": "127.0.0.1", "duration_secs": u64::MAX });
+        let (status, _) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 400);
+
+        let ban = json!({ "ip": "127.0.0.1", "duration_secs": 60, "reason": "spam" });
+        let (status, body) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 201, "{body}");
+        let banned: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(banned["ban"]["ip"], "127.0.0.1");
+        assert_eq!(banned["ban"]["reason"], "spam");
+        assert_eq!(banned["kicked"], json!([a.id]));
+        assert_eq!(closed(&mut a).await, CloseCode::Policy);
+
+        assert_eq!(refused(addr, "/ws", &[]).await, Some(403));
+
+        let (_, body) = http_get(addr, "/admin/bans", &[AUTH]).await;
+        let bans: Vec<Value> = serde_json::from_str(&body).unwrap();
+        assert_eq!(bans, vec![banned["ban"].clone()]);
+
+        let (status, _) =
+            http_request(addr, "DELETE", "/admin/bans/127.0.0.1", &[AUTH], None).await;
+        assert_eq!(status, 204);
+        let (status, _) =
+            http_request(addr, "DELETE", "/admin/bans/127.0.0.1", &[AUTH], None).await;
+        assert_eq!(status, 404);
+        TestClient::connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn ban_mapped_address() {
+        let addr = serve(app()).await;
+        let mut a = TestClient::connect(addr).await;
+
+        let ban = json!({ "ip": "::ffff:127.0.0.1", "duration_secs": 60 });
+        let (status, body) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 201, "{body}");
+        let banned: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(banned["ban"]["ip"], "127.0.0.1");
+        assert_eq!(banned["kicked"], json!([a.id]));
+        assert_eq!(closed(&mut a).await, CloseCode::Policy);
+        assert_eq!(refused(addr, "/ws", &[]).await, Some(403));
+
+        let (status, _) = http_request(
+            addr,
+            "DELETE",
+            "/admin/bans/::ffff:127.0.0.1",
+            &[AUTH],
+            None,
+        )
+        .await;
+        assert_eq!(status, 204);
+        TestClient::connect(addr).await;
+    }
+
+    #[tokio::test]
+    async fn ban_ipv6_by_the_64() {
+        let proxy = TrustedProxies::new(vec!["127.0.0.1".parse().unwrap()]);
+        let addr = serve(app().with_trusted_proxies(proxy)).await;
+        let forwarded = |ip| [("x-forwarded-for", ip)];
+        let mut a = TestClient::connect_with(addr, "/ws", &forwarded("2001:db8:0:1::1")).await;
+
+        let ban = json!({ "ip": "2001:db8:0:1::2", "duration_secs": 60 });
+        let (status, body) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 201, "{body}");
+        let banned: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(banned["ban"]["ip"], "2001:db8:0:1::");
+        assert_eq!(banned["kicked"], json!([a.id]));
+        assert_eq!(closed(&mut a).await, CloseCode::Policy);
+
+        // another address of the /64 is banned, another /64 is not
+        assert_eq!(
+            refused(addr, "/ws", &forwarded("2001:db8:0:1::3")).await,
+            Some(403)
+        );
+        TestClient::connect_with(addr, "/ws", &forwarded("2001:db8:0:2::1")).await;
+
+        let (status, _) =
+            http_request(addr, "DELETE", "/admin/bans/2001:db8:0:1::3", &[AUTH], None).await;
+        assert_eq!(status, 204);
+        TestClient::connect_with(addr, "/ws", &forwarded("2001:db8:0:1::3")).await;
+    }
+}
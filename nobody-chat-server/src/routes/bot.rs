@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::WebSocketUpgrade,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use tracing::{info, warn, Instrument};
+
+use super::{admin::constant_eq, home::refuse_banned};
+use crate::{
+    chat::{bot_id, Connection, User},
+    client_ip::ClientAddr,
+    handle::ChatHandle,
+    logging::{connection_span, Redacted},
+};
+
+/// a bot connecting with `Authorization: Bearer <key>`
+#[derive(Debug, Clone)]
+pub struct BotAccount {
+    pub name: String,
+    pub key: Redacted<String>,
+}
+
+pub type BotAccountsState = Arc<Vec<BotAccount>>;
+
+/// a WebSocket of plain JSON messages, the same as the browsers but not encrypted,
+/// TLS protects it
+pub async fn bot_connection(
+    ws: WebSocketUpgrade,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(bots): Extension<BotAccountsState>,
+    ClientAddr { remote, ip }: ClientAddr,
+    Extension(chat): Extension<ChatHandle>,
+) -> Response {
+    let Some(bot) = auth.and_then(|TypedHeader(Authorization(bearer))| {
+        bots.iter()
+            .find(|bot| constant_eq(bearer.token(), &bot.key))
+    }) else {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    };
+
+    let name = bot.name.clone();
+    if chat.is_online(&bot_id(&name)) {
+        return (StatusCode::CONFLICT, "Bot already connected").into_response();
+    }
+
+    if let Some(res) = refuse_banned(&chat, ip) {
+        return res;
+    }
+    let span = connection_span(remote, ip);
+    info!(parent: &span, "bot {} connected", name);
+    let connection = Connection::new(remote, ip, None);
+    ws.on_upgrade(move |socket| {
+        async move {
+            if let Err(e) = User::new_bot(socket, name, chat, connection).await {
+                warn!("bot connection failed: {}", e);
+            }
+        }
+        .instrument(span)
+    })
+}
+
+#[cfg(test)]
+mod test_bot {
+    use std::{net::SocketAddr, time::Duration};
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{json, Value};
+    use tokio_tungstenite::{
+        connect_async,
+        tungstenite::{client::IntoClientRequest, Error as WsError, Message},
+        MaybeTlsStream, WebSocketStream,
+    };
+
+    use crate::{
+        test_client::{http_get, serve, TestClient},
+        App,
+    };
+
+    type BotSocket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+    async fn connect_bot(addr: SocketAddr, key: &str) -> Result<BotSocket, WsError> {
+        let mut request = format!("ws://{addr}/bot/ws").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("authorization", format!("Bearer {key}").parse().unwrap());
+
+        connect_async(request).await.map(|(ws, _)| ws)
+    }
+
+    async fn recv_until(bot: &mut BotSocket, pred: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            if let Some(Ok(Message::Text(text))) = bot.next().await {
+                let data: Value = serde_json::from_str(&text).unwrap();
+                if pred(&data) {
+                    return data;
+                }
+            }
+        }
+    }
+
+    fn status(res: Result<BotSocket, WsError>) -> u16 {
+        match res {
+            Err(WsError::Http(response)) => response.status().as_u16(),
+            _ => panic!("expected an HTTP error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn talk_with_humans() {
+        let app = App::new("127.0.0.1:0", vec!["*".to_string()])
+            .with_bot("helpdesk", "a long enough bot key");
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            assert_eq!(status(connect_bot(addr, "a wrong key").await), 401);
+
+            let mut bot = connect_bot(addr, "a long enough bot key").await.unwrap();
+            let set_user =
+                recv_until(&mut bot, |data| data["msg_type"]["setUser"].is_object()).await;
+            assert_eq!(set_user["msg_type"]["setUser"]["id"], "bot-helpdesk");
+
+            assert_eq!(
+                status(connect_bot(addr, "a long enough bot key").await),
+                409
+            );
+
+            let (_, body) = http_get(addr, "/api/allonlineusers", &[]).await;
+            let users: Vec<Value> = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                users,
+                vec![json!({ "id": "bot-helpdesk", "name": "helpdesk", "bot": true })]
+            );
+
+            let mut human = TestClient::connect(addr).await;
+            human.talk_to(&"bot-helpdesk".to_string(), "help").await;
+            let msg = recv_until(&mut bot, |data| data["msg_type"]["msg"].is_object()).await;
+            assert_eq!(msg["msg_type"]["msg"]["from"], human.id.as_str());
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "help");
+
+            let talk_to = json!({ "msg_type": { "talkTo": { "to": human.id, "msg": "hello" } } });
+            bot.send(Message::Text(talk_to.to_string())).await.unwrap();
+            let msg = human
+                .recv_until(|data| data["msg_type"]["msg"].is_object())
+                .await
+                .unwrap();
+            assert_eq!(msg["msg_type"]["msg"]["from"], "bot-helpdesk");
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "hello");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keep_the_block_list_across_reconnects() {
+        let app = App::new("127.0.0.1:0", vec!["*".to_string()])
+            .with_bot("helpdesk", "a long enough bot key");
+        let addr = serve(app).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let mut human = TestClient::connect(addr).await;
+            let mut bot = connect_bot(addr, "a long enough bot key").await.unwrap();
+            human
+                .recv_until(|data| data["msg_type"]["userOnline"]["id"] == "bot-helpdesk")
+                .await
+                .unwrap();
+
+            let block = json!({ "msg_type": { "block": { "id": human.id } } });
+            bot.send(Message::Text(block.to_string())).await.unwrap();
+            bot_synced(&mut bot).await;
+            bot.close(None).await.unwrap();
+            human
+                .recv_until(|data| data["msg_type"]["userOffline"]["id"] == "bot-helpdesk")
+                .await
+                .unwrap();
+
+            let mut bot = connect_bot(addr, "a long enough bot key").await.unwrap();
+            human
+                .recv_until(|data| data["msg_type"]["userOnline"]["id"] == "bot-helpdesk")
+                .await
+                .unwrap();
+            human.talk_to(&"bot-helpdesk".to_string(), "blocked").await;
+            // every message of the human is handled once it receives its own
+            let id = human.id.clone();
+            human.talk_to(&id, "synced").await;
+            human
+                .recv_until(|data| data["msg_type"]["msg"]["msg"] == "synced")
+                .await
+                .unwrap();
+
+            let unblock = json!({ "msg_type": { "unblock": { "id": human.id } } });
+            bot.send(Message::Text(unblock.to_string())).await.unwrap();
+            let talk_to =
+                json!({ "msg_type": { "talkTo": { "to": "bot-helpdesk", "msg": "synced" } } });
+            bot.send(Message::Text(talk_to.to_string())).await.unwrap();
+            // the message blocked would have come first
+            let msg = recv_until(&mut bot, |data| data["msg_type"]["msg"].is_object()).await;
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "synced");
+            human
+                .talk_to(&"bot-helpdesk".to_string(), "unblocked")
+                .await;
+            let msg = recv_until(&mut bot, |data| {
+                data["msg_type"]["msg"]["from"] == human.id.as_str()
+            })
+            .await;
+            assert_eq!(msg["msg_type"]["msg"]["msg"], "unblocked");
+        })
+        .await
+        .unwrap();
+    }
+
+    /// every frame of the bot is handled once it receives its own message
+    async fn bot_synced(bot: &mut BotSocket) {
+        let talk_to =
+            json!({ "msg_type": { "talkTo": { "to": "bot-helpdesk", "msg": "synced" } } });
+        bot.send(Message::Text(talk_to.to_string())).await.unwrap();
+        recv_until(bot, |data| data["msg_type"]["msg"]["msg"] == "synced").await;
+    }
+}
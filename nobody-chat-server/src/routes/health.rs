@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use serde_json::json;
+
+use crate::handle::ChatHandle;
+
+/// how long `/readyz` waits for the actors to answer
+#[derive(Debug, Clone, Copy)]
+pub struct ReadinessTimeout(pub Duration);
+
+/// the process is up
+pub async fn healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// 503 unless every component is ok
+pub async fn readyz(
+    Extension(chat): Extension<ChatHandle>,
+    Extension(ReadinessTimeout(deadline)): Extension<ReadinessTimeout>,
+) -> impl IntoResponse {
+    let readiness = chat.readiness(deadline).await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness))
+}
+
+#[cfg(test)]
+mod test_health {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        test_client::{http_get, serve, serve_routes},
+        App,
+    };
+
+    fn app() -> App {
+        App::new("", vec!["*".to_string()])
+    }
+
+    #[tokio::test]
+    async fn ready_once_listening() {
+        let addr = serve(app()).await;
+
+        let (status, body) = http_get(addr, "/healthz", &[]).await;
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"{"status":"ok"}"#);
+
+        let (status, body) = http_get(addr, "/readyz", &[]).await;
+        assert_eq!(status, 200, "{body}");
+        let readiness: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(readiness["ready"], true);
+        for component in [
+            "chat_room",
+            "online_pubsub",
+            "offline_pubsub",
+            "new_name_pubsub",
+            "listener",
+        ] {
+            assert_eq!(readiness["components"][component], "ok", "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_ready_once_stopped() {
+        let (routes, chat) = app().build().unwrap();
+        let addr = serve_routes(routes).await;
+
+        // the listener is of the embedding application
+        let (status, body) = http_get(addr, "/readyz", &[]).await;
+        assert_eq!(status, 200, "{body}");
+        let readiness: Value = serde_json::from_str(&body).unwrap();
+        assert!(readiness["components"].get("listener").is_none(), "{body}");
+
+        chat.shutdown(Duration::from_secs(1)).await;
+        let (status, body) = http_get(addr, "/readyz", &[]).await;
+        assert_eq!(status, 503, "{body}");
+        let readiness: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(readiness["ready"], false);
+        assert_eq!(readiness["components"]["chat_room"], "stopped");
+        assert_eq!(readiness["components"]["online_pubsub"], "unknown");
+
+        // still alive
+        assert_eq!(http_get(addr, "/healthz", &[]).await.0, 200);
+    }
+}
@@ -1,82 +1,207 @@
-use std::net::SocketAddr;
+use std::{net::IpAddr, sync::Arc, time::Instant};
 
 use axum::{
-    extract::{ws::WebSocket, ConnectInfo, State, WebSocketUpgrade},
+    extract::{ws::WebSocket, State, WebSocketUpgrade},
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension, Json,
 };
 use axum_extra::{headers, TypedHeader};
-use kameo::{actor::ActorRef, request::MessageSend};
-use log::info;
 use serde::{Deserialize, Serialize};
+use tracing::{info, warn, Instrument};
 
-use crate::state::AllowOriginState;
+use crate::state::OriginPolicyState;
 use crate::{
-    chat::{AllActivityUsers, ChatRoom, User},
+    chat::{Connection, User},
+    client_ip::ClientAddr,
+    handle::ChatHandle,
+    limit::{ConnectionLimiter, Refused},
+    logging::connection_span,
     models::UserId,
 };
 
-#[derive(Serialize, Deserialize)]
+pub type ConnectionLimiterState = Arc<ConnectionLimiter>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct OnlineUser {
     pub id: UserId,
     pub name: String,
+    /// connected by an API key
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub bot: bool,
 }
 
-pub async fn all_online_users(
-    Extension(chat_room): Extension<ActorRef<ChatRoom>>,
-) -> impl IntoResponse {
-    let list = chat_room.ask(AllActivityUsers).send().await.unwrap();
+pub async fn all_online_users(Extension(chat): Extension<ChatHandle>) -> impl IntoResponse {
+    let Ok(list) = chat.online_users().await else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Chat room is stopped").into_response();
+    };
 
-    let list: Vec<_> = list
-        .iter()
-        .map(|item| OnlineUser {
-            id: item.0.clone(),
-            name: item.1.clone(),
-        })
-        .collect();
-
-    Json(list)
+    Json(list).into_response()
 }
 
 pub async fn web_socket_connection(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     origin: Option<TypedHeader<headers::Origin>>,
-    State(allow_origins): State<AllowOriginState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Extension(chat): Extension<ActorRef<ChatRoom>>,
+    State(allow_origins): State<OriginPolicyState>,
+    ClientAddr { remote, ip }: ClientAddr,
+    Extension(limiter): Extension<ConnectionLimiterState>,
+    Extension(chat): Extension<ChatHandle>,
 ) -> impl IntoResponse {
+    let agent = user_agent
+        .as_ref()
+        .map(|TypedHeader(agent)| agent.to_string());
     if let Some(res) = valify_header(origin, user_agent, allow_origins) {
         return res;
     }
 
-    info!("{:?} connected.", addr);
+    if let Some(res) = refuse_banned(&chat, ip) {
+        return res;
+    }
+    let mut connection = Connection::new(remote, ip, agent);
+    if let Some(ip) = ip {
+        match limiter.admit(ip, Instant::now()) {
+            Ok(slot) => connection = connection.with_slot(slot),
+            Err(refused) => {
+                info!("{} refused: {:?}", ip, refused);
+                let reason = match refused {
+                    Refused::TooMany => "Too many connections",
+                    Refused::TooFast => "Too many new connections",
+                };
+                return (StatusCode::TOO_MANY_REQUESTS, reason).into_response();
+            }
+        }
+    }
+    let span = connection_span(remote, ip);
+    info!(parent: &span, "connected");
 
-    ws.on_upgrade(move |socket| append_new_connection(socket, chat))
+    ws.on_upgrade(move |socket| append_new_connection(socket, chat, connection).instrument(span))
 }
 
-async fn append_new_connection(ws: WebSocket, chat_room: ActorRef<ChatRoom>) {
-    let _ = User::new_actor(ws, chat_room).await;
+/// before the upgrade, so a banned client costs no key exchange
+pub(crate) fn refuse_banned(chat: &ChatHandle, ip: Option<IpAddr>) -> Option<Response> {
+    let ip = ip?;
+    if chat.is_banned(&ip) {
+        info!("{} is banned, refused", ip);
+        return Some((StatusCode::FORBIDDEN, "Banned").into_response());
+    }
+
+    None
+}
+
+async fn append_new_connection(ws: WebSocket, chat: ChatHandle, connection: Connection) {
+    if let Err(e) = User::new_actor(ws, chat, connection).await {
+        warn!("new connection failed: {}", e);
+    }
 }
 
 fn valify_header(
     origin: Option<TypedHeader<headers::Origin>>,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
-    allow_origins: AllowOriginState,
+    allow_origins: OriginPolicyState,
 ) -> Option<Response> {
     match origin {
-        Some(TypedHeader(value))
-            if allow_origins
-                .iter()
-                .any(|url| url == "*" || url == &value.to_string()) => {}
+        Some(TypedHeader(value)) if allow_origins.allows(&value.to_string()) => {}
         _ => return Some((StatusCode::UNAUTHORIZED, "Disallowed Origin").into_response()),
     }
 
     const UNKNOW_BROWSER: &str = "Unknown browser";
-    if let None = user_agent {
+    if user_agent.is_none() {
         return Some((StatusCode::BAD_REQUEST, UNKNOW_BROWSER).into_response());
     }
 
     None
 }
+
+#[cfg(test)]
+mod test_connection_limits {
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+
+    use crate::{
+        client_ip::TrustedProxies,
+        limit::{ConnectionLimits, Rate},
+        test_client::{http_get, http_request, refused, serve, TestClient},
+        App,
+    };
+
+    const AUTH: (&str, &str) = ("authorization", "Bearer admin token");
+
+    fn app(limits: ConnectionLimits) -> App {
+        App::new("", vec!["*".to_string()])
+            .with_admin_token("admin token")
+            .with_connection_limits(limits)
+    }
+
+    #[tokio::test]
+    async fn cap_connections_per_ip() {
+        let addr = serve(app(ConnectionLimits {
+            per_ip: 2,
+            new_per_ip: None,
+        }))
+        .await;
+
+        let a = TestClient::connect(addr).await;
+        let _b = TestClient::connect(addr).await;
+        assert_eq!(refused(addr, "/ws", &[]).await, Some(429));
+
+        // the slot is released once the user is gone
+        drop(a);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while refused(addr, "/ws", &[]).await.is_some() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttle_new_connections() {
+        let addr = serve(app(ConnectionLimits {
+            per_ip: 0,
+            new_per_ip: Some(Rate::new(2, Duration::from_secs(60))),
+        }))
+        .await;
+
+        drop(TestClient::connect(addr).await);
+        drop(TestClient::connect(addr).await);
+        assert_eq!(refused(addr, "/ws", &[]).await, Some(429));
+    }
+
+    #[tokio::test]
+    async fn client_behind_trusted_proxy() {
+        let proxy = TrustedProxies::new(vec!["127.0.0.1".parse().unwrap()]);
+        let addr = serve(
+            app(ConnectionLimits {
+                per_ip: 1,
+                new_per_ip: None,
+            })
+            .with_trusted_proxies(proxy),
+        )
+        .await;
+
+        let forwarded = |ip| [("x-forwarded-for", ip)];
+        let a = TestClient::connect_with(addr, "/ws", &forwarded("198.51.100.7")).await;
+        // a client of its own behind the same proxy
+        let _b = TestClient::connect_with(addr, "/ws", &forwarded("198.51.100.8")).await;
+        assert_eq!(
+            refused(addr, "/ws", &forwarded("198.51.100.7")).await,
+            Some(429)
+        );
+
+        let (_, body) = http_get(addr, &format!("/admin/users/{}", a.id), &[AUTH]).await;
+        let user: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(user["ip"], "198.51.100.7");
+        assert!(user["remote"].as_str().unwrap().starts_with("127.0.0.1:"));
+
+        let ban = json!({ "ip": "198.51.100.8", "duration_secs": 60 });
+        let (status, _) = http_request(addr, "POST", "/admin/bans", &[AUTH], Some(ban)).await;
+        assert_eq!(status, 201);
+        assert_eq!(
+            refused(addr, "/ws", &forwarded("198.51.100.8")).await,
+            Some(403)
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use axum::{http::header, response::IntoResponse, Extension};
+
+use crate::handle::ChatHandle;
+
+/// the Prometheus text format
+pub async fn metrics(Extension(chat): Extension<ChatHandle>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        chat.relay.render_metrics(),
+    )
+}
@@ -1 +1,5 @@
+pub mod admin;
+pub mod bot;
+pub mod health;
 pub mod home;
+pub mod metrics;
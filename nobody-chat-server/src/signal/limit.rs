@@ -0,0 +1,327 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use super::{SignalError, SignalInfo, SignalType};
+use crate::{
+    limit::{Rate, TokenBucket},
+    models::UserId,
+};
+
+/// how often users may signal, against call-spam
+#[derive(Debug, Clone)]
+pub struct SignalLimits {
+    /// any signal a user sends, ICE candidates come in bursts
+    pub signals_per_sender: Rate,
+    /// call requests a user sends
+    pub calls_per_sender: Rate,
+    /// call requests a user receives, from anyone
+    pub calls_per_target: Rate,
+    /// after a `Deny` of its request, the same caller cannot ring the callee again for this long
+    pub deny_cooldown: Duration,
+}
+
+impl Default for SignalLimits {
+    fn default() -> Self {
+        Self {
+            signals_per_sender: Rate::new(200, Duration::from_secs(10)),
+            calls_per_sender: Rate::new(5, Duration::from_secs(60)),
+            calls_per_target: Rate::new(10, Duration::from_secs(60)),
+            deny_cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct SignalLimiter {
+    limits: SignalLimits,
+    signals: HashMap<UserId, TokenBucket>,
+    calls: HashMap<UserId, TokenBucket>,
+    rung: HashMap<UserId, TokenBucket>,
+    /// (caller, callee) of the call requests not answered yet
+    ringing: HashSet<(UserId, UserId)>,
+    /// (caller, callee) -> until when the caller cannot ring the callee
+    denied: HashMap<(UserId, UserId), Instant>,
+}
+
+impl SignalLimiter {
+    pub fn new(limits: SignalLimits) -> Self {
+        Self {
+            limits,
+            signals: HashMap::new(),
+            calls: HashMap::new(),
+            rung: HashMap::new(),
+            ringing: HashSet::new(),
+            denied: HashMap::new(),
+        }
+    }
+
+    /// whether the signal may be forwarded, only checked once the signal passed the policy;
+    /// a `Deny` of a ringing call starts the cooldown of the caller
+    pub fn check(&mut self, signal: &SignalInfo, now: Instant) -> Result<(), SignalError> {
+        let limits = &self.limits;
+        let key = (signal.from_id.clone(), signal.to_id.clone());
+        // the callee answers the caller
+        let by_callee = (signal.to_id.clone(), signal.from_id.clone());
+
+        if signal.signal_type.is_call_request() {
+            match self.denied.get(&key) {
+                Some(until) if *until > now => return Err(SignalError::RateLimited),
+                Some(_) => {
+                    self.denied.remove(&key);
+                }
+                None => {}
+            }
+        }
+
+        // a token of every bucket or none, a refused signal costs nothing
+        let mut buckets = vec![self
+            .signals
+            .entry(signal.from_id.clone())
+            .or_insert_with(|| TokenBucket::new(limits.signals_per_sender, now))];
+        if signal.signal_type.is_call_request() {
+            buckets.push(
+                self.calls
+                    .entry(signal.from_id.clone())
+                    .or_insert_with(|| TokenBucket::new(limits.calls_per_sender, now)),
+            );
+            buckets.push(
+                self.rung
+                    .entry(signal.to_id.clone())
+                    .or_insert_with(|| TokenBucket::new(limits.calls_per_target, now)),
+            );
+        }
+        if !buckets.iter_mut().all(|bucket| bucket.has(1, now)) {
+            return Err(SignalError::RateLimited);
+        }
+        for bucket in buckets {
+            bucket.try_take(1, now);
+        }
+
+        match signal.signal_type {
+            SignalType::RequestVideo | SignalType::RequestCall => {
+                self.ringing.insert(key);
+            }
+            SignalType::Deny if self.ringing.remove(&by_callee) => {
+                self.denied.insert(by_callee, now + limits.deny_cooldown);
+            }
+            // the callee offers once accepting
+            SignalType::Offer => {
+                self.ringing.remove(&by_callee);
+            }
+            SignalType::Stop => {
+                self.ringing.remove(&key);
+                self.ringing.remove(&by_callee);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// drop everything about a disconnected user
+    pub fn forget(&mut self, id: &UserId) {
+        self.signals.remove(id);
+        self.calls.remove(id);
+        self.rung.remove(id);
+        self.ringing
+            .retain(|(caller, callee)| caller != id && callee != id);
+        self.denied
+            .retain(|(caller, callee), _| caller != id && callee != id);
+    }
+}
+
+#[cfg(test)]
+mod test_signal_limiter {
+    use super::*;
+
+    fn signal(from: &str, to: &str, signal_type: SignalType) -> SignalInfo {
+        SignalInfo {
+            from_id: from.to_string(),
+            to_id: to.to_string(),
+            signal_type,
+            value: Default::default(),
+            call: None,
+        }
+    }
+
+    #[test]
+    fn limit_calls_per_sender() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits {
+            calls_per_sender: Rate::new(2, Duration::from_secs(60)),
+            ..Default::default()
+        });
+
+        assert!(limiter
+            .check(&signal("a", "b", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("a", "c", SignalType::RequestCall), now)
+            .is_ok());
+        assert_eq!(
+            limiter.check(&signal("a", "d", SignalType::RequestVideo), now),
+            Err(SignalError::RateLimited)
+        );
+        // other signals of the call are not call requests
+        assert!(limiter
+            .check(&signal("a", "b", SignalType::Offer), now)
+            .is_ok());
+        // another caller is not affected
+        assert!(limiter
+            .check(&signal("e", "d", SignalType::RequestVideo), now)
+            .is_ok());
+
+        let later = now + Duration::from_secs(30);
+        assert!(limiter
+            .check(&signal("a", "d", SignalType::RequestVideo), later)
+            .is_ok());
+    }
+
+    #[test]
+    fn limit_calls_per_target() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits {
+            calls_per_target: Rate::new(2, Duration::from_secs(60)),
+            ..Default::default()
+        });
+
+        assert!(limiter
+            .check(&signal("a", "t", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("b", "t", SignalType::RequestVideo), now)
+            .is_ok());
+        assert_eq!(
+            limiter.check(&signal("c", "t", SignalType::RequestVideo), now),
+            Err(SignalError::RateLimited)
+        );
+    }
+
+    #[test]
+    fn refused_calls_cost_nothing() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits {
+            signals_per_sender: Rate::new(3, Duration::from_secs(60)),
+            calls_per_sender: Rate::new(2, Duration::from_secs(60)),
+            calls_per_target: Rate::new(1, Duration::from_secs(60)),
+            ..Default::default()
+        });
+
+        assert!(limiter
+            .check(&signal("a", "t", SignalType::RequestVideo), now)
+            .is_ok());
+        // refused by the target, the caller keeps its budget
+        assert_eq!(
+            limiter.check(&signal("b", "t", SignalType::RequestVideo), now),
+            Err(SignalError::RateLimited)
+        );
+        assert!(limiter.calls.get_mut("b").unwrap().is_full(now));
+        assert!(limiter.signals.get_mut("b").unwrap().is_full(now));
+        assert!(limiter
+            .check(&signal("b", "c", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("b", "d", SignalType::RequestVideo), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn limit_all_signals_per_sender() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits {
+            signals_per_sender: Rate::new(3, Duration::from_secs(10)),
+            ..Default::default()
+        });
+
+        for _ in 0..3 {
+            assert!(limiter
+                .check(&signal("a", "b", SignalType::NewCandidate), now)
+                .is_ok());
+        }
+        assert_eq!(
+            limiter.check(&signal("a", "b", SignalType::NewCandidate), now),
+            Err(SignalError::RateLimited)
+        );
+    }
+
+    #[test]
+    fn cooldown_after_deny() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits {
+            deny_cooldown: Duration::from_secs(30),
+            ..Default::default()
+        });
+
+        assert!(limiter
+            .check(&signal("caller", "callee", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::Deny), now)
+            .is_ok());
+
+        let soon = now + Duration::from_secs(10);
+        assert_eq!(
+            limiter.check(&signal("caller", "callee", SignalType::RequestVideo), soon),
+            Err(SignalError::RateLimited)
+        );
+        // the callee may still call the caller, and the caller others
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::RequestVideo), soon)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("caller", "other", SignalType::RequestVideo), soon)
+            .is_ok());
+
+        let later = now + Duration::from_secs(31);
+        assert!(limiter
+            .check(&signal("caller", "callee", SignalType::RequestVideo), later)
+            .is_ok());
+    }
+
+    #[test]
+    fn cooldown_only_of_ringing_calls() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits::default());
+
+        // nothing to deny
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::Deny), now)
+            .is_ok());
+        assert!(limiter.denied.is_empty());
+
+        // an accepted call, hung up by a deny of the UI
+        assert!(limiter
+            .check(&signal("caller", "callee", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::Offer), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::Deny), now)
+            .is_ok());
+        assert!(limiter.denied.is_empty());
+        assert!(limiter
+            .check(&signal("caller", "callee", SignalType::RequestVideo), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn forget_disconnected_user() {
+        let now = Instant::now();
+        let mut limiter = SignalLimiter::new(SignalLimits::default());
+
+        assert!(limiter
+            .check(&signal("caller", "callee", SignalType::RequestVideo), now)
+            .is_ok());
+        assert!(limiter
+            .check(&signal("callee", "caller", SignalType::Deny), now)
+            .is_ok());
+        assert!(!limiter.denied.is_empty());
+        limiter.forget(&"caller".to_string());
+
+        assert!(limiter.ringing.is_empty());
+        assert!(limiter.denied.is_empty());
+        assert!(!limiter.signals.contains_key("caller"));
+    }
+}
@@ -1,26 +1,113 @@
+mod limit;
+mod policy;
+mod record;
+mod sdp;
+
+use std::{collections::BTreeSet, str::FromStr};
+
 use serde::{Deserialize, Serialize};
 
-use crate::models::UserId;
+use crate::{logging::Redacted, models::UserId};
+
+pub use limit::*;
+pub use policy::*;
+pub use record::*;
 
 /// forwording negotiation message
 /// # Example:
 /// "{"msg_type":{"signal":{"from_id":"from_id","signal_type":"offer","to_id":"to_id","value":"value"}}}"
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// a call request carries its media:
+/// "{"msg_type":{"signal":{"from_id":"from_id","signal_type":"requestCall","to_id":"to_id","value":"","call":{"media":"audio","capabilities":["audio"]}}}}"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SignalInfo {
     pub from_id: UserId,
     pub to_id: UserId,
     pub signal_type: SignalType,
-    pub value: String,
+    /// SDP or a candidate, kept out of the logs
+    pub value: Redacted<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub call: Option<CallRequest>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum SignalType {
     Offer,
     Answer,
     NewCandidate,
-    /// user requests to video communicate to other user
+    /// user requests to video communicate to other user,
+    /// the same as a `RequestCall` of `MediaType::Video`
     RequestVideo,
+    /// user requests to call other user, with the `call` field
+    RequestCall,
     Deny,
     Stop,
 }
+
+impl SignalType {
+    pub fn is_call_request(&self) -> bool {
+        matches!(self, SignalType::RequestVideo | SignalType::RequestCall)
+    }
+}
+
+/// what a call is about
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum MediaType {
+    Audio,
+    Video,
+    Screen,
+}
+
+impl FromStr for MediaType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "audio" => Ok(MediaType::Audio),
+            "video" => Ok(MediaType::Video),
+            "screen" => Ok(MediaType::Screen),
+            _ => Err(format!("unknown media type: {s}")),
+        }
+    }
+}
+
+/// what the caller is able to send and receive during a call
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    Audio,
+    Video,
+    Screen,
+    DataChannel,
+}
+
+impl Capability {
+    /// the media this capability depends on
+    pub fn media(&self) -> Option<MediaType> {
+        match self {
+            Capability::Audio => Some(MediaType::Audio),
+            Capability::Video => Some(MediaType::Video),
+            Capability::Screen => Some(MediaType::Screen),
+            Capability::DataChannel => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CallRequest {
+    pub media: MediaType,
+    #[serde(default)]
+    pub capabilities: BTreeSet<Capability>,
+}
+
+impl CallRequest {
+    /// what a legacy `RequestVideo` means
+    pub fn legacy_video() -> Self {
+        Self {
+            media: MediaType::Video,
+            capabilities: BTreeSet::from([Capability::Audio, Capability::Video]),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    sdp::{Candidate, Sdp},
+    CallRequest, MediaType, SignalInfo, SignalLimits, SignalType,
+};
+
+/// the value of `RequestVideo`, `Deny` and `Stop` is short, e.g. a timestamp
+const CONTROL_VALUE_LIMIT: usize = 256;
+
+/// what the server accepts to forward, configured per deployment
+#[derive(Debug, Clone)]
+pub struct SignalPolicy {
+    /// max bytes of an `Offer`/`Answer` value
+    pub max_sdp_len: usize,
+    /// max bytes of a `NewCandidate` value
+    pub max_candidate_len: usize,
+    /// drop host candidates with private LAN addresses, so peers cannot learn them
+    pub strip_host_candidates: bool,
+    /// the media users may call with, e.g. disable video to save bandwidth
+    pub allowed_media: BTreeSet<MediaType>,
+    pub limits: SignalLimits,
+}
+
+impl Default for SignalPolicy {
+    fn default() -> Self {
+        Self {
+            max_sdp_len: 16 * 1024,
+            max_candidate_len: 1024,
+            strip_host_candidates: false,
+            allowed_media: BTreeSet::from([MediaType::Audio, MediaType::Video, MediaType::Screen]),
+            limits: SignalLimits::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SignalError {
+    TooLarge { len: usize, limit: usize },
+    MalformedSdp(String),
+    MalformedCandidate(String),
+    MissingCall,
+    MediaNotAllowed(MediaType),
+    RateLimited,
+}
+
+impl std::fmt::Display for SignalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignalError::TooLarge { len, limit } => {
+                write!(f, "signal value too large: {len} > {limit}")
+            }
+            SignalError::MalformedSdp(e) => write!(f, "malformed SDP: {e}"),
+            SignalError::MalformedCandidate(e) => write!(f, "malformed ICE candidate: {e}"),
+            SignalError::MissingCall => write!(f, "call request without media"),
+            SignalError::MediaNotAllowed(media) => write!(f, "{media:?} call is not allowed"),
+            SignalError::RateLimited => write!(f, "too many signals"),
+        }
+    }
+}
+
+impl std::error::Error for SignalError {}
+
+/// `RTCSessionDescriptionInit` serialized by the client
+#[derive(Serialize, Deserialize)]
+struct SessionDescription {
+    #[serde(rename = "type")]
+    sdp_type: String,
+    sdp: String,
+}
+
+/// `RTCIceCandidateInit` serialized by the client
+#[derive(Serialize, Deserialize)]
+struct IceCandidate {
+    candidate: String,
+    #[serde(flatten)]
+    rest: Map<String, Value>,
+}
+
+impl SignalPolicy {
+    /// validate the signal before forwarding, call requests always carry their `call` afterwards,
+    /// returns `Ok(None)` if the signal should be dropped silently
+    pub fn check(&self, mut signal: SignalInfo) -> Result<Option<SignalInfo>, SignalError> {
+        let limit = match signal.signal_type {
+            SignalType::Offer | SignalType::Answer => self.max_sdp_len,
+            SignalType::NewCandidate => self.max_candidate_len,
+            _ => CONTROL_VALUE_LIMIT,
+        };
+        if signal.value.len() > limit {
+            return Err(SignalError::TooLarge {
+                len: signal.value.len(),
+                limit,
+            });
+        }
+
+        match signal.signal_type {
+            SignalType::Offer | SignalType::Answer => {
+                signal.value = self
+                    .check_description(&signal.signal_type, &signal.value)?
+                    .into();
+            }
+            SignalType::NewCandidate if !self.check_candidate(&signal.value)? => {
+                return Ok(None);
+            }
+            SignalType::RequestVideo => {
+                signal.call = Some(self.check_call(CallRequest::legacy_video())?);
+            }
+            SignalType::RequestCall => {
+                let call = signal.call.take().ok_or(SignalError::MissingCall)?;
+                signal.call = Some(self.check_call(call)?);
+            }
+            _ => {}
+        }
+
+        Ok(Some(signal))
+    }
+
+    /// the callee only learns the capabilities allowed by this deployment
+    fn check_call(&self, mut call: CallRequest) -> Result<CallRequest, SignalError> {
+        if !self.allowed_media.contains(&call.media) {
+            return Err(SignalError::MediaNotAllowed(call.media));
+        }
+
+        call.capabilities
+            .retain(|capability| match capability.media() {
+                Some(media) => self.allowed_media.contains(&media),
+                None => true,
+            });
+
+        Ok(call)
+    }
+
+    fn check_description(
+        &self,
+        signal_type: &SignalType,
+        value: &str,
+    ) -> Result<String, SignalError> {
+        let mut desc: SessionDescription =
+            serde_json::from_str(value).map_err(|e| SignalError::MalformedSdp(e.to_string()))?;
+
+        let expected = match signal_type {
+            SignalType::Offer => "offer",
+            _ => "answer",
+        };
+        if desc.sdp_type != expected {
+            return Err(SignalError::MalformedSdp(format!(
+                "type `{}` in {expected}",
+                desc.sdp_type
+            )));
+        }
+
+        let mut sdp = Sdp::parse(&desc.sdp).map_err(SignalError::MalformedSdp)?;
+        if !self.strip_host_candidates {
+            return Ok(value.to_string());
+        }
+
+        sdp.strip_private_host_candidates();
+        desc.sdp = sdp.to_string_crlf();
+        Ok(serde_json::to_string(&desc).expect("serialize session description"))
+    }
+
+    /// returns whether the candidate should be forwarded
+    fn check_candidate(&self, value: &str) -> Result<bool, SignalError> {
+        let candidate: IceCandidate = serde_json::from_str(value)
+            .map_err(|e| SignalError::MalformedCandidate(e.to_string()))?;
+
+        // an empty candidate indicates the end of candidates
+        if candidate.candidate.is_empty() {
+            return Ok(true);
+        }
+
+        let parsed =
+            Candidate::parse(&candidate.candidate).map_err(SignalError::MalformedCandidate)?;
+
+        Ok(!(self.strip_host_candidates && parsed.leaks_private_address()))
+    }
+}
+
+#[cfg(test)]
+mod test_signal_policy {
+    use super::*;
+    use crate::signal::Capability;
+    use serde_json::json;
+
+    const SDP: &str = "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n\
+        a=candidate:1 1 udp 2122260223 192.168.1.5 46154 typ host\r\n\
+        a=candidate:2 1 udp 1677729535 203.0.113.7 46154 typ srflx\r\n";
+
+    fn signal(signal_type: SignalType, value: String) -> SignalInfo {
+        SignalInfo {
+            from_id: "from".to_string(),
+            to_id: "to".to_string(),
+            signal_type,
+            value: value.into(),
+            call: None,
+        }
+    }
+
+    fn offer(sdp: &str) -> SignalInfo {
+        signal(
+            SignalType::Offer,
+            json!({"type": "offer", "sdp": sdp}).to_string(),
+        )
+    }
+
+    fn candidate(candidate: &str) -> SignalInfo {
+        signal(
+            SignalType::NewCandidate,
+            json!({"candidate": candidate, "sdpMid": "0", "sdpMLineIndex": 0}).to_string(),
+        )
+    }
+
+    #[test]
+    fn forward_valid_signals() {
+        let policy = SignalPolicy::default();
+
+        let res = policy.check(offer(SDP)).unwrap().unwrap();
+        assert_eq!(res.value, offer(SDP).value);
+
+        assert!(policy
+            .check(candidate("candidate:1 1 udp 1 192.168.1.5 9 typ host"))
+            .unwrap()
+            .is_some());
+        assert!(policy.check(candidate("")).unwrap().is_some());
+        assert!(policy
+            .check(signal(
+                SignalType::RequestVideo,
+                "1727000000000".to_string()
+            ))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn reject_oversized() {
+        let policy = SignalPolicy {
+            max_sdp_len: 16,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            policy.check(offer(SDP)),
+            Err(SignalError::TooLarge { limit: 16, .. })
+        ));
+        assert!(matches!(
+            policy.check(signal(
+                SignalType::Deny,
+                "x".repeat(CONTROL_VALUE_LIMIT + 1)
+            )),
+            Err(SignalError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let policy = SignalPolicy::default();
+
+        assert!(matches!(
+            policy.check(signal(SignalType::Offer, SDP.to_string())),
+            Err(SignalError::MalformedSdp(_))
+        ));
+        assert!(matches!(
+            policy.check(offer("hello")),
+            Err(SignalError::MalformedSdp(_))
+        ));
+        assert!(matches!(
+            policy.check(signal(
+                SignalType::Answer,
+                json!({"type": "offer", "sdp": SDP}).to_string()
+            )),
+            Err(SignalError::MalformedSdp(_))
+        ));
+        assert!(matches!(
+            policy.check(candidate("candidate:1 1 udp")),
+            Err(SignalError::MalformedCandidate(_))
+        ));
+        assert!(matches!(
+            policy.check(signal(SignalType::NewCandidate, "candidate".to_string())),
+            Err(SignalError::MalformedCandidate(_))
+        ));
+    }
+
+    #[test]
+    fn strip_host_candidates() {
+        let policy = SignalPolicy {
+            strip_host_candidates: true,
+            ..Default::default()
+        };
+
+        let res = policy.check(offer(SDP)).unwrap().unwrap();
+        let desc: SessionDescription = serde_json::from_str(&res.value).unwrap();
+        assert_eq!(desc.sdp_type, "offer");
+        assert!(!desc.sdp.contains("192.168.1.5"));
+        assert!(desc.sdp.contains("203.0.113.7"));
+
+        assert!(policy
+            .check(candidate("candidate:1 1 udp 1 192.168.1.5 9 typ host"))
+            .unwrap()
+            .is_none());
+        let res = policy
+            .check(candidate("candidate:2 1 udp 1 203.0.113.7 9 typ srflx"))
+            .unwrap()
+            .unwrap();
+        let value: Value = serde_json::from_str(&res.value).unwrap();
+        assert_eq!(value["sdpMid"], "0");
+    }
+
+    fn call(media: MediaType, capabilities: &[Capability]) -> SignalInfo {
+        SignalInfo {
+            call: Some(CallRequest {
+                media,
+                capabilities: capabilities.iter().copied().collect(),
+            }),
+            ..signal(SignalType::RequestCall, String::new())
+        }
+    }
+
+    #[test]
+    fn forward_call_requests() {
+        let policy = SignalPolicy::default();
+
+        let res = policy
+            .check(call(
+                MediaType::Screen,
+                &[Capability::Screen, Capability::DataChannel],
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.call.unwrap().media, MediaType::Screen);
+
+        // legacy video requests are propagated as video calls
+        let res = policy
+            .check(signal(
+                SignalType::RequestVideo,
+                "1727000000000".to_string(),
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.call, Some(CallRequest::legacy_video()));
+
+        assert_eq!(
+            policy.check(signal(SignalType::RequestCall, String::new())),
+            Err(SignalError::MissingCall)
+        );
+    }
+
+    #[test]
+    fn enforce_allowed_media() {
+        let policy = SignalPolicy {
+            allowed_media: BTreeSet::from([MediaType::Audio]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.check(call(MediaType::Video, &[Capability::Video])),
+            Err(SignalError::MediaNotAllowed(MediaType::Video))
+        );
+        assert_eq!(
+            policy.check(signal(SignalType::RequestVideo, String::new())),
+            Err(SignalError::MediaNotAllowed(MediaType::Video))
+        );
+
+        let res = policy
+            .check(call(
+                MediaType::Audio,
+                &[
+                    Capability::Audio,
+                    Capability::Video,
+                    Capability::DataChannel,
+                ],
+            ))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            res.call.unwrap().capabilities,
+            BTreeSet::from([Capability::Audio, Capability::DataChannel])
+        );
+    }
+}
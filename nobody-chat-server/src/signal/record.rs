@@ -0,0 +1,422 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::{error, warn};
+
+use super::{MediaType, SignalInfo, SignalType};
+use crate::models::UserId;
+
+/// a call lifecycle event, never any media
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum CallEvent {
+    Request {
+        caller: UserId,
+        callee: UserId,
+        media: Option<MediaType>,
+    },
+    Accept {
+        caller: UserId,
+        callee: UserId,
+    },
+    Deny {
+        caller: UserId,
+        callee: UserId,
+    },
+    Stop {
+        caller: UserId,
+        callee: UserId,
+        /// who stopped, or disconnected
+        by: UserId,
+        /// seconds since accepted, `None` if never accepted
+        duration_secs: Option<f64>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CallRecord {
+    /// unix time in milliseconds
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: CallEvent,
+}
+
+impl CallRecord {
+    pub fn now(event: CallEvent) -> Self {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        Self { at, event }
+    }
+}
+
+/// where the call records go
+pub trait CallRecorder: Send + Sync {
+    fn record(&self, record: CallRecord);
+}
+
+/// the records waiting for the file, dropped beyond
+const JSON_LINES_QUEUE: usize = 1024;
+
+/// appends a JSON object per line, written by a thread of its own
+/// so a slow disk never holds up the chat room
+pub struct JsonLinesRecorder {
+    queue: SyncSender<CallRecord>,
+}
+
+impl JsonLinesRecorder {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (queue, rx) = sync_channel(JSON_LINES_QUEUE);
+        thread::Builder::new()
+            .name("call-records".to_string())
+            .spawn(move || write_lines(BufWriter::new(file), rx))?;
+
+        Ok(Self { queue })
+    }
+}
+
+impl CallRecorder for JsonLinesRecorder {
+    fn record(&self, record: CallRecord) {
+        match self.queue.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("call records queue is full, record dropped"),
+            Err(TrySendError::Disconnected(_)) => error!("writing call records stopped"),
+        }
+    }
+}
+
+/// flushed once no record is waiting, until the recorder is dropped
+fn write_lines(mut file: BufWriter<File>, rx: Receiver<CallRecord>) {
+    while let Ok(record) = rx.recv() {
+        let mut next = Some(record);
+        while let Some(record) = next {
+            let mut line = serde_json::to_string(&record).expect("serialize call record");
+            line.push('\n');
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                error!("writing call record failed: {:?}", e);
+            }
+            next = rx.try_recv().ok();
+        }
+        if let Err(e) = file.flush() {
+            error!("writing call record failed: {:?}", e);
+        }
+    }
+}
+
+/// records to every recorder
+impl CallRecorder for Vec<Arc<dyn CallRecorder>> {
+    fn record(&self, record: CallRecord) {
+        for recorder in self {
+            recorder.record(record.clone());
+        }
+    }
+}
+
+/// keeps the latest `capacity` records in memory
+pub struct MemoryRecorder {
+    capacity: usize,
+    records: Mutex<VecDeque<CallRecord>>,
+}
+
+impl MemoryRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// oldest first
+    pub fn records(&self) -> Vec<CallRecord> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.iter().cloned().collect()
+    }
+}
+
+impl CallRecorder for MemoryRecorder {
+    fn record(&self, record: CallRecord) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        if records.len() >= self.capacity {
+            records.pop_front();
+        }
+        if self.capacity > 0 {
+            records.push_back(record);
+        }
+    }
+}
+
+struct Call {
+    accepted_at: Option<Instant>,
+}
+
+/// follows the forwarded signals and records the lifecycle of calls
+pub struct CallTracker {
+    recorder: Arc<dyn CallRecorder>,
+    /// (caller, callee) -> call
+    calls: HashMap<(UserId, UserId), Call>,
+}
+
+impl CallTracker {
+    pub fn new(recorder: Arc<dyn CallRecorder>) -> Self {
+        Self {
+            recorder,
+            calls: HashMap::new(),
+        }
+    }
+
+    /// observe a signal which has been forwarded
+    pub fn observe(&mut self, signal: &SignalInfo, now: Instant) {
+        let from = &signal.from_id;
+        let to = &signal.to_id;
+
+        if signal.signal_type.is_call_request() {
+            self.calls
+                .insert((from.clone(), to.clone()), Call { accepted_at: None });
+            self.record(CallEvent::Request {
+                caller: from.clone(),
+                callee: to.clone(),
+                media: signal.call.as_ref().map(|call| call.media),
+            });
+            return;
+        }
+
+        // the signal is sent by the callee
+        let by_callee = (to.clone(), from.clone());
+        match signal.signal_type {
+            // the callee offers once accepting
+            SignalType::Offer => {
+                if let Some(call) = self.calls.get_mut(&by_callee) {
+                    if call.accepted_at.is_none() {
+                        call.accepted_at = Some(now);
+                        self.record(CallEvent::Accept {
+                            caller: to.clone(),
+                            callee: from.clone(),
+                        });
+                    }
+                }
+            }
+            SignalType::Deny if self.calls.remove(&by_callee).is_some() => {
+                self.record(CallEvent::Deny {
+                    caller: to.clone(),
+                    callee: from.clone(),
+                });
+            }
+            SignalType::Stop => {
+                let by_caller = (from.clone(), to.clone());
+                for key in [by_caller, by_callee] {
+                    if let Some(call) = self.calls.remove(&key) {
+                        self.record_stop(key, from.clone(), call, now);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// the calls of a disconnected user are stopped by the user
+    pub fn forget(&mut self, id: &UserId, now: Instant) {
+        let keys: Vec<_> = self
+            .calls
+            .keys()
+            .filter(|(caller, callee)| caller == id || callee == id)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            if let Some(call) = self.calls.remove(&key) {
+                self.record_stop(key, id.clone(), call, now);
+            }
+        }
+    }
+
+    fn record_stop(
+        &self,
+        (caller, callee): (UserId, UserId),
+        by: UserId,
+        call: Call,
+        now: Instant,
+    ) {
+        self.record(CallEvent::Stop {
+            caller,
+            callee,
+            by,
+            duration_secs: call
+                .accepted_at
+                .map(|at| now.saturating_duration_since(at).as_secs_f64()),
+        });
+    }
+
+    fn record(&self, event: CallEvent) {
+        self.recorder.record(CallRecord::now(event));
+    }
+}
+
+#[cfg(test)]
+mod test_call_tracker {
+    use super::*;
+    use crate::signal::CallRequest;
+    use std::time::Duration;
+
+    fn signal(from: &str, to: &str, signal_type: SignalType) -> SignalInfo {
+        SignalInfo {
+            from_id: from.to_string(),
+            to_id: to.to_string(),
+            signal_type,
+            value: Default::default(),
+            call: None,
+        }
+    }
+
+    fn events(recorder: &MemoryRecorder) -> Vec<CallEvent> {
+        recorder.records().into_iter().map(|r| r.event).collect()
+    }
+
+    #[test]
+    fn accepted_call() {
+        let recorder = Arc::new(MemoryRecorder::new(16));
+        let mut tracker = CallTracker::new(recorder.clone());
+        let now = Instant::now();
+
+        let mut request = signal("a", "b", SignalType::RequestVideo);
+        request.call = Some(CallRequest::legacy_video());
+        tracker.observe(&request, now);
+        tracker.observe(&signal("b", "a", SignalType::Offer), now);
+        tracker.observe(&signal("a", "b", SignalType::Answer), now);
+        // renegotiation is not another accept
+        tracker.observe(&signal("b", "a", SignalType::Offer), now);
+        tracker.observe(
+            &signal("b", "a", SignalType::Stop),
+            now + Duration::from_secs(90),
+        );
+
+        assert_eq!(
+            events(&recorder),
+            vec![
+                CallEvent::Request {
+                    caller: "a".to_string(),
+                    callee: "b".to_string(),
+                    media: Some(MediaType::Video),
+                },
+                CallEvent::Accept {
+                    caller: "a".to_string(),
+                    callee: "b".to_string(),
+                },
+                CallEvent::Stop {
+                    caller: "a".to_string(),
+                    callee: "b".to_string(),
+                    by: "b".to_string(),
+                    duration_secs: Some(90.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn denied_call() {
+        let recorder = Arc::new(MemoryRecorder::new(16));
+        let mut tracker = CallTracker::new(recorder.clone());
+        let now = Instant::now();
+
+        tracker.observe(&signal("a", "b", SignalType::RequestVideo), now);
+        tracker.observe(&signal("b", "a", SignalType::Deny), now);
+        // nothing to stop any more
+        tracker.observe(&signal("a", "b", SignalType::Stop), now);
+
+        assert_eq!(
+            events(&recorder)[1],
+            CallEvent::Deny {
+                caller: "a".to_string(),
+                callee: "b".to_string(),
+            }
+        );
+        assert_eq!(recorder.records().len(), 2);
+    }
+
+    #[test]
+    fn disconnected_during_call() {
+        let recorder = Arc::new(MemoryRecorder::new(16));
+        let mut tracker = CallTracker::new(recorder.clone());
+        let now = Instant::now();
+
+        tracker.observe(&signal("a", "b", SignalType::RequestVideo), now);
+        tracker.forget(&"b".to_string(), now);
+
+        assert_eq!(
+            events(&recorder)[1],
+            CallEvent::Stop {
+                caller: "a".to_string(),
+                callee: "b".to_string(),
+                by: "b".to_string(),
+                duration_secs: None,
+            }
+        );
+    }
+
+    #[test]
+    fn memory_recorder_keeps_latest() {
+        let recorder = MemoryRecorder::new(2);
+        for caller in ["a", "b", "c"] {
+            recorder.record(CallRecord::now(CallEvent::Deny {
+                caller: caller.to_string(),
+                callee: "x".to_string(),
+            }));
+        }
+
+        let records = recorder.records();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[0].event, CallEvent::Deny { caller, .. } if caller == "b"));
+    }
+
+    #[test]
+    fn json_lines_recorder() {
+        let path = std::env::temp_dir().join(format!("calls-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let recorder = JsonLinesRecorder::open(&path).unwrap();
+        recorder.record(CallRecord {
+            at: 1,
+            event: CallEvent::Accept {
+                caller: "a".to_string(),
+                callee: "b".to_string(),
+            },
+        });
+        recorder.record(CallRecord {
+            at: 2,
+            event: CallEvent::Deny {
+                caller: "a".to_string(),
+                callee: "b".to_string(),
+            },
+        });
+
+        // written by the thread of the recorder
+        let started = Instant::now();
+        let mut content = String::new();
+        while content.lines().count() < 2 && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+            content = std::fs::read_to_string(&path).unwrap();
+        }
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"at":1,"event":"accept","caller":"a","callee":"b"}"#,
+                r#"{"at":2,"event":"deny","caller":"a","callee":"b"}"#,
+            ]
+        );
+    }
+}
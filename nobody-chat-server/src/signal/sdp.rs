@@ -0,0 +1,253 @@
+use std::net::IpAddr;
+
+/// the fields of an `a=candidate` (RFC 8839) we care about
+#[derive(Debug, PartialEq)]
+pub struct Candidate<'a> {
+    pub foundation: &'a str,
+    pub component: u16,
+    pub transport: &'a str,
+    pub priority: u32,
+    pub address: &'a str,
+    pub port: u16,
+    pub typ: &'a str,
+}
+
+impl<'a> Candidate<'a> {
+    /// parse a candidate attribute, with or without the leading `a=` and `candidate:`
+    pub fn parse(line: &'a str) -> Result<Self, String> {
+        let line = line.strip_prefix("a=").unwrap_or(line);
+        let line = line
+            .strip_prefix("candidate:")
+            .ok_or("missing `candidate:` prefix")?;
+
+        let mut fields = line.split(' ');
+        let mut next = |name: &str| {
+            fields
+                .next()
+                .filter(|v| !v.is_empty())
+                .ok_or(format!("missing {name}"))
+        };
+
+        let foundation = next("foundation")?;
+        if foundation.len() > 32 || !foundation.chars().all(is_ice_char) {
+            return Err("invalid foundation".to_string());
+        }
+        let component = next("component id")?
+            .parse()
+            .map_err(|_| "invalid component id")?;
+        let transport = next("transport")?;
+        if !transport.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("invalid transport".to_string());
+        }
+        let priority = next("priority")?.parse().map_err(|_| "invalid priority")?;
+        let address = next("connection address")?;
+        if !address
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".:-".contains(c))
+        {
+            return Err("invalid connection address".to_string());
+        }
+        let port = next("port")?.parse().map_err(|_| "invalid port")?;
+        if next("typ")? != "typ" {
+            return Err("missing `typ`".to_string());
+        }
+        let typ = next("candidate type")?;
+        if !typ.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err("invalid candidate type".to_string());
+        }
+
+        // the rest are `name value` pairs, e.g. `raddr`, `rport`, `generation`
+        for ext in fields {
+            if !ext.chars().all(|c| c.is_ascii_graphic()) {
+                return Err("invalid extension attribute".to_string());
+            }
+        }
+
+        Ok(Self {
+            foundation,
+            component,
+            transport,
+            priority,
+            address,
+            port,
+            typ,
+        })
+    }
+
+    /// a host candidate carrying a private or link-local address of the user's LAN,
+    /// mDNS (`.local`) host candidates already hide the address
+    pub fn leaks_private_address(&self) -> bool {
+        if self.typ != "host" {
+            return false;
+        }
+
+        match self.address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+            Ok(IpAddr::V6(ip)) => {
+                let first = ip.segments()[0];
+                // unique local fc00::/7 and link-local fe80::/10
+                (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80 || ip.is_loopback()
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn is_ice_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '+' || c == '/'
+}
+
+/// a session description (RFC 8866), only checks the structure,
+/// the semantic is left to the peers
+pub struct Sdp<'a> {
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Sdp<'a> {
+    pub fn parse(sdp: &'a str) -> Result<Self, String> {
+        let lines: Vec<&str> = sdp
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .collect();
+
+        if lines.first() != Some(&"v=0") {
+            return Err("SDP must start with `v=0`".to_string());
+        }
+
+        // the line number only, the errors are logged and SDP is never echoed,
+        // so the body stays out of the logs
+        for (n, line) in lines.iter().enumerate() {
+            let mut chars = line.chars();
+            match (chars.next(), chars.next()) {
+                (Some(t), Some('=')) if t.is_ascii_lowercase() => {}
+                _ => return Err(format!("invalid SDP line {}", n + 1)),
+            }
+            if line.chars().any(|c| c.is_control()) {
+                return Err("SDP contains control characters".to_string());
+            }
+            if line.starts_with("a=candidate:") {
+                Candidate::parse(line)?;
+            }
+        }
+
+        for required in ["o=", "s=", "t="] {
+            if !lines.iter().any(|line| line.starts_with(required)) {
+                return Err(format!("SDP missing `{required}` line"));
+            }
+        }
+
+        Ok(Self { lines })
+    }
+
+    /// remove the host candidates leaking private LAN addresses
+    pub fn strip_private_host_candidates(&mut self) {
+        self.lines.retain(|line| {
+            !line.starts_with("a=candidate:")
+                || !Candidate::parse(line)
+                    .map(|c| c.leaks_private_address())
+                    .unwrap_or(false)
+        });
+    }
+
+    pub fn to_string_crlf(&self) -> String {
+        let mut sdp = self.lines.join("\r\n");
+        sdp.push_str("\r\n");
+        sdp
+    }
+}
+
+#[cfg(test)]
+mod test_sdp {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 192.168.1.5 rport 46154 generation 0\r\n\
+        a=candidate:1 1 udp 2122260223 192.168.1.5 46154 typ host generation 0\r\n\
+        a=candidate:2 1 udp 2122260223 4b1f3c2a-6e9d.local 46155 typ host\r\n\
+        a=rtpmap:111 opus/48000/2\r\n";
+
+    #[test]
+    fn parse_candidate() {
+        let c = Candidate::parse(
+            "candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 0.0.0.0 rport 0",
+        )
+        .unwrap();
+
+        assert_eq!(
+            c,
+            Candidate {
+                foundation: "842163049",
+                component: 1,
+                transport: "udp",
+                priority: 1677729535,
+                address: "203.0.113.7",
+                port: 46154,
+                typ: "srflx",
+            }
+        );
+        assert!(!c.leaks_private_address());
+    }
+
+    #[test]
+    fn malformed_candidate() {
+        assert!(Candidate::parse("842163049 1 udp 1 203.0.113.7 1 typ host").is_err());
+        assert!(Candidate::parse("candidate:1 1 udp 1 203.0.113.7 99999 typ host").is_err());
+        assert!(Candidate::parse("candidate:1 1 udp 1 203.0.113.7 1 host").is_err());
+        assert!(Candidate::parse("candidate:1 1 udp 1 <script> 1 typ host").is_err());
+        assert!(Candidate::parse("candidate:1 1 udp").is_err());
+    }
+
+    #[test]
+    fn private_host_candidates() {
+        let leaks = |addr: &str| {
+            Candidate::parse(&format!("candidate:1 1 udp 1 {addr} 9 typ host"))
+                .unwrap()
+                .leaks_private_address()
+        };
+
+        assert!(leaks("10.0.0.2"));
+        assert!(leaks("172.16.3.4"));
+        assert!(leaks("192.168.1.5"));
+        assert!(leaks("169.254.0.1"));
+        assert!(leaks("fd00::1"));
+        assert!(leaks("fe80::1"));
+        assert!(!leaks("203.0.113.7"));
+        assert!(!leaks("2001:db8::1"));
+        assert!(!leaks("4b1f3c2a-6e9d.local"));
+    }
+
+    #[test]
+    fn parse_sdp() {
+        assert!(Sdp::parse(SDP).is_ok());
+        assert!(Sdp::parse(&SDP.replace("\r\n", "\n")).is_ok());
+    }
+
+    #[test]
+    fn malformed_sdp() {
+        assert!(Sdp::parse("").is_err());
+        assert!(Sdp::parse("hello").is_err());
+        assert!(Sdp::parse(&SDP.replace("s=-\r\n", "")).is_err());
+        assert!(Sdp::parse(&SDP.replace("t=0 0", "T=0 0")).is_err());
+        assert!(Sdp::parse(&SDP.replace("typ host generation", "host generation")).is_err());
+        assert!(Sdp::parse(&SDP.replace("s=-", "s=\u{7}")).is_err());
+    }
+
+    #[test]
+    fn strip_private_host_candidates() {
+        let mut sdp = Sdp::parse(SDP).unwrap();
+        sdp.strip_private_host_candidates();
+        let stripped = sdp.to_string_crlf();
+
+        assert!(!stripped.contains("192.168.1.5 46154 typ host"));
+        // relayed and mDNS candidates are kept
+        assert!(stripped.contains("typ srflx"));
+        assert!(stripped.contains(".local 46155 typ host"));
+        assert_eq!(stripped.lines().count(), SDP.lines().count() - 1);
+    }
+}
@@ -1,5 +1,7 @@
+mod outbox;
 mod recv;
 mod send;
 
+pub use outbox::*;
 pub use recv::*;
 pub use send::*;
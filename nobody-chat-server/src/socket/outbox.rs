@@ -0,0 +1,411 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{debug, Instrument};
+
+use super::{SendMsg, SendSocket};
+
+/// what to do once the outbound queue of a user is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumer {
+    /// drop the oldest presence update queued, disconnect if only messages are queued
+    #[default]
+    DropPresence,
+    /// disconnect at once
+    Disconnect,
+}
+
+/// the frames queued for a user, sent by a task of their own,
+/// so a slow connection never blocks the user or the chat room
+#[derive(Debug, Clone)]
+pub struct OutboxOptions {
+    pub capacity: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+impl Default for OutboxOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 512,
+            slow_consumer: SlowConsumer::default(),
+        }
+    }
+}
+
+/// of every outbox
+#[derive(Debug, Default)]
+pub struct OutboxMetrics {
+    queued: AtomicUsize,
+    deepest: AtomicUsize,
+    dropped: AtomicU64,
+    slow_disconnects: AtomicU64,
+}
+
+impl OutboxMetrics {
+    pub fn stats(&self) -> OutboxStats {
+        OutboxStats {
+            queued: self.queued.load(Ordering::Relaxed),
+            deepest: self.deepest.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            slow_disconnects: self.slow_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct OutboxStats {
+    /// frames waiting in every outbox now
+    pub queued: usize,
+    /// the most frames ever waiting in one outbox
+    pub deepest: usize,
+    /// presence updates dropped for slow users
+    pub dropped: u64,
+    /// users disconnected for being too slow
+    pub slow_disconnects: u64,
+}
+
+pub enum Outgoing {
+    /// online, offline and renames, dropped first
+    Presence(String),
+    Data(String),
+    Ping,
+}
+
+enum Closing {
+    GoingAway,
+    Kicked,
+    Close,
+}
+
+#[derive(Default)]
+struct Queue {
+    frames: VecDeque<Outgoing>,
+    closing: Option<Closing>,
+    /// the socket failed, or too slow
+    dead: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Notify,
+    metrics: Arc<OutboxMetrics>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let left = self.lock().frames.len();
+        self.metrics.queued.fetch_sub(left, Ordering::Relaxed);
+    }
+}
+
+pub struct Outbox {
+    shared: Arc<Shared>,
+    options: OutboxOptions,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Outbox {
+    /// start writing to `socket`
+    pub fn spawn<S: SendMsg + 'static>(
+        socket: SendSocket<S>,
+        options: OutboxOptions,
+        metrics: Arc<OutboxMetrics>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::default(),
+            ready: Notify::new(),
+            metrics,
+        });
+        let writer = tokio::spawn(write(socket, shared.clone()).in_current_span());
+
+        Self {
+            shared,
+            options,
+            writer: Some(writer),
+        }
+    }
+
+    /// `false` if the user should be disconnected, too slow or the socket is gone
+    pub fn push(&self, frame: Outgoing) -> bool {
+        let metrics = &self.shared.metrics;
+        let mut queue = self.shared.lock();
+        if queue.dead || queue.closing.is_some() {
+            return false;
+        }
+
+        if queue.frames.len() >= self.options.capacity {
+            let oldest_presence = match self.options.slow_consumer {
+                SlowConsumer::DropPresence => queue
+                    .frames
+                    .iter()
+                    .position(|frame| matches!(frame, Outgoing::Presence(_))),
+                SlowConsumer::Disconnect => None,
+            };
+            match (oldest_presence, &frame) {
+                (Some(i), _) => {
+                    queue.frames.remove(i);
+                    metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                (None, Outgoing::Presence(_))
+                    if self.options.slow_consumer == SlowConsumer::DropPresence =>
+                {
+                    metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                    return true;
+                }
+                (None, _) => {
+                    queue.dead = true;
+                    metrics.slow_disconnects.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+            metrics.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        queue.frames.push_back(frame);
+        metrics.queued.fetch_add(1, Ordering::Relaxed);
+        metrics
+            .deepest
+            .fetch_max(queue.frames.len(), Ordering::Relaxed);
+        drop(queue);
+
+        self.shared.ready.notify_one();
+        true
+    }
+
+    /// send what is queued then the going away frame, waiting for it
+    pub async fn going_away(&mut self) {
+        self.shared.lock().closing.get_or_insert(Closing::GoingAway);
+        self.shared.ready.notify_one();
+
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.await;
+        }
+    }
+
+    /// send what is queued then the kicked frame, without waiting
+    pub fn kick(&mut self) {
+        self.shared.lock().closing.get_or_insert(Closing::Kicked);
+        self.shared.ready.notify_one();
+        self.writer.take();
+    }
+
+    /// send what is queued then close, without waiting
+    pub fn close(&mut self) {
+        self.shared.lock().closing.get_or_insert(Closing::Close);
+        self.shared.ready.notify_one();
+        self.writer.take();
+    }
+
+    /// stop sending at once, for a user too slow
+    pub fn abort(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.abort();
+        }
+    }
+}
+
+enum Next {
+    Frame(Outgoing),
+    Close(Closing),
+    Wait,
+}
+
+async fn write<S: SendMsg>(mut socket: SendSocket<S>, shared: Arc<Shared>) {
+    loop {
+        let next = {
+            let mut queue = shared.lock();
+            match queue.frames.pop_front() {
+                Some(frame) => {
+                    shared.metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    Next::Frame(frame)
+                }
+                None => match queue.closing.take() {
+                    Some(closing) => {
+                        queue.dead = true;
+                        Next::Close(closing)
+                    }
+                    None => Next::Wait,
+                },
+            }
+        };
+
+        match next {
+            Next::Frame(frame) => {
+                let sent = match frame {
+                    Outgoing::Presence(text) | Outgoing::Data(text) => socket.send(text).await,
+                    Outgoing::Ping => socket.ping().await,
+                };
+                if let Err(e) = sent {
+                    debug!("socket is dead: {}", e);
+                    shared.lock().dead = true;
+                    return;
+                }
+            }
+            Next::Close(closing) => {
+                let _ = match closing {
+                    Closing::GoingAway => socket.going_away().await,
+                    Closing::Kicked => socket.kicked().await,
+                    Closing::Close => Ok(()),
+                };
+                socket.close().await;
+                return;
+            }
+            Next::Wait => shared.ready.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_outbox {
+    use std::time::Duration;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::cipher::SplitedEncrypt;
+
+    type Sent = Arc<Mutex<Vec<String>>>;
+
+    /// sends a frame only once let through by the gate
+    struct StalledSink {
+        gate: Arc<Semaphore>,
+        sent: Sent,
+    }
+
+    impl SendMsg for StalledSink {
+        async fn send(&mut self, msg: String) -> Result<(), axum::Error> {
+            self.gate.acquire().await.unwrap().forget();
+            self.sent.lock().unwrap().push(msg);
+            Ok(())
+        }
+
+        async fn close(&mut self) {}
+
+        async fn going_away(&mut self) -> Result<(), axum::Error> {
+            self.sent.lock().unwrap().push("going away".to_string());
+            Ok(())
+        }
+
+        async fn kicked(&mut self) -> Result<(), axum::Error> {
+            self.sent.lock().unwrap().push("kicked".to_string());
+            Ok(())
+        }
+
+        async fn ping(&mut self) -> Result<(), axum::Error> {
+            self.send("ping".to_string()).await
+        }
+    }
+
+    struct Plain;
+
+    impl SplitedEncrypt for Plain {
+        fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+            data.to_vec()
+        }
+    }
+
+    fn stalled(slow_consumer: SlowConsumer) -> (Outbox, Arc<Semaphore>, Sent, Arc<OutboxMetrics>) {
+        let (gate, sent) = (Arc::new(Semaphore::new(0)), Arc::default());
+        let sink = StalledSink {
+            gate: gate.clone(),
+            sent: Arc::clone(&sent),
+        };
+        let metrics = Arc::new(OutboxMetrics::default());
+        let options = OutboxOptions {
+            capacity: 3,
+            slow_consumer,
+        };
+        let outbox = Outbox::spawn(SendSocket::new(sink, Plain), options, metrics.clone());
+
+        (outbox, gate, sent, metrics)
+    }
+
+    /// the sent frames, base64 of the plain cipher
+    fn decoded(sent: &Mutex<Vec<String>>) -> Vec<String> {
+        use base64::prelude::*;
+
+        sent.lock()
+            .unwrap()
+            .iter()
+            .map(|text| match BASE64_STANDARD.decode(text) {
+                Ok(plain) => String::from_utf8(plain).unwrap(),
+                Err(_) => text.clone(),
+            })
+            .collect()
+    }
+
+    /// until the writer took every frame, or is gone
+    async fn wait_for_writer(metrics: &OutboxMetrics) {
+        while metrics.stats().queued > 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_presence_then_disconnect() {
+        let (mut outbox, gate, sent, metrics) = stalled(SlowConsumer::DropPresence);
+
+        assert!(outbox.push(Outgoing::Data("stuck".to_string())));
+        wait_for_writer(&metrics).await;
+        assert!(outbox.push(Outgoing::Presence("online a".to_string())));
+        assert!(outbox.push(Outgoing::Data("msg 1".to_string())));
+        assert!(outbox.push(Outgoing::Presence("online b".to_string())));
+        assert_eq!(metrics.stats().queued, 3);
+
+        // the oldest presence goes, then the new one, the messages stay
+        assert!(outbox.push(Outgoing::Data("msg 2".to_string())));
+        assert!(outbox.push(Outgoing::Presence("online c".to_string())));
+        assert!(outbox.push(Outgoing::Data("msg 3".to_string())));
+        assert!(!outbox.push(Outgoing::Data("msg 4".to_string())));
+        assert_eq!(
+            metrics.stats(),
+            OutboxStats {
+                queued: 3,
+                deepest: 3,
+                dropped: 3,
+                slow_disconnects: 1,
+            }
+        );
+
+        gate.add_permits(10);
+        outbox.going_away().await;
+        drop(outbox);
+        assert_eq!(
+            decoded(&sent),
+            vec!["stuck", "msg 1", "msg 2", "msg 3", "going away"]
+        );
+        assert_eq!(metrics.stats().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn disconnect_once_full() {
+        let (mut outbox, _gate, _sent, metrics) = stalled(SlowConsumer::Disconnect);
+
+        assert!(outbox.push(Outgoing::Data("stuck".to_string())));
+        wait_for_writer(&metrics).await;
+        for n in 0..3 {
+            assert!(outbox.push(Outgoing::Presence(format!("online {n}"))));
+        }
+        assert!(!outbox.push(Outgoing::Presence("online 3".to_string())));
+        assert_eq!(metrics.stats().slow_disconnects, 1);
+
+        // the writer never finishes the stuck frame
+        outbox.abort();
+        drop(outbox);
+        wait_for_writer(&metrics).await;
+    }
+}
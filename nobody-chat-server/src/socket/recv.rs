@@ -31,14 +31,20 @@ impl<S: Stream<Item = Result<Message, axum::Error>> + std::marker::Unpin, C: Spl
 
         let res = ready!(Stream::poll_next(soc, cx));
         if let Some(Ok(Message::Text(cipher_text))) = &res {
-            let cipher_text = BASE64_STANDARD.decode(cipher_text).unwrap();
-            let plain_text = self.cipher.decrypt(&cipher_text);
+            let plain_text = BASE64_STANDARD
+                .decode(cipher_text)
+                .ok()
+                .and_then(|cipher_text| self.cipher.decrypt(&cipher_text))
+                .and_then(|plain_text| String::from_utf8(plain_text).ok());
 
-            return std::task::Poll::Ready(Some(Ok(Message::Text(
-                String::from_utf8(plain_text).expect("cannot decode to plain text"),
-            ))));
+            // a broken frame is an error of the stream, not a panic
+            std::task::Poll::Ready(Some(
+                plain_text
+                    .map(Message::Text)
+                    .ok_or(axum::Error::new("cannot decrypt the message")),
+            ))
         } else {
-            return std::task::Poll::Ready(res);
+            std::task::Poll::Ready(res)
         }
     }
 }
@@ -56,8 +62,8 @@ mod test_recv_socket_stream {
         const EXPECTED_TEXT_2: &str = "plain text";
         struct Cipher;
         impl SplitedDecrypt for Cipher {
-            fn decrypt(&self, data: &[u8]) -> Vec<u8> {
-                data.to_vec()
+            fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+                Some(data.to_vec())
             }
         }
 
@@ -79,28 +85,57 @@ mod test_recv_socket_stream {
         if let Some(Ok(Message::Text(text))) = recv_socket.next().await {
             assert_eq!(text, EXPECTED_TEXT);
         } else {
-            assert!(false);
+            panic!("expected text message");
         }
         if let Some(Ok(Message::Text(text))) = recv_socket.next().await {
             assert_eq!(text, EXPECTED_TEXT);
         } else {
-            assert!(false);
+            panic!("expected text message");
         }
         if let Some(Ok(Message::Text(text))) = recv_socket.next().await {
             assert_eq!(text, EXPECTED_TEXT);
         } else {
-            assert!(false);
+            panic!("expected text message");
         }
         if let Some(Ok(Message::Text(text))) = recv_socket.next().await {
             assert_eq!(text, EXPECTED_TEXT_2);
         } else {
-            assert!(false);
+            panic!("expected text message");
         }
         if let Some(Ok(Message::Text(text))) = recv_socket.next().await {
             assert_eq!(text, EXPECTED_TEXT_2);
         } else {
-            assert!(false);
+            panic!("expected text message");
         }
         assert!(recv_socket.next().await.is_none());
     }
+
+    #[tokio::test]
+    async fn broken_frame_is_error() {
+        struct Cipher;
+        impl SplitedDecrypt for Cipher {
+            fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+                (data != b"forged").then(|| data.to_vec())
+            }
+        }
+
+        let socket = stream! {
+            yield Result::<Message, Error>::Ok(Message::Text("not base64!".to_string()));
+            yield Result::<Message, Error>::Ok(Message::Text(BASE64_STANDARD.encode("forged")));
+            yield Result::<Message, Error>::Ok(Message::Text(BASE64_STANDARD.encode("text")));
+        };
+        pin_mut!(socket);
+
+        let mut recv_socket = RecvSocket {
+            cipher: Cipher,
+            socket,
+        };
+
+        assert!(matches!(recv_socket.next().await, Some(Err(_))));
+        assert!(matches!(recv_socket.next().await, Some(Err(_))));
+        // the stream goes on
+        assert!(
+            matches!(recv_socket.next().await, Some(Ok(Message::Text(text))) if text == "text")
+        );
+    }
 }
@@ -1,11 +1,19 @@
 use crate::cipher::SplitedEncrypt;
-use axum::extract::ws::{Message, WebSocket};
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
 use base64::prelude::*;
 use futures_util::{stream::SplitSink, SinkExt};
-
-pub trait SendMsg {
-    async fn send(&mut self, msg: String) -> Result<(), axum::Error>;
-    async fn close(&mut self);
+use std::future::Future;
+
+/// `Send` futures, so an outbox writes in a task of its own
+pub trait SendMsg: Send {
+    fn send(&mut self, msg: String) -> impl Future<Output = Result<(), axum::Error>> + Send;
+    fn close(&mut self) -> impl Future<Output = ()> + Send;
+    /// a close frame telling the client the server is going away
+    fn going_away(&mut self) -> impl Future<Output = Result<(), axum::Error>> + Send;
+    /// a close frame telling the client an admin removed it
+    fn kicked(&mut self) -> impl Future<Output = Result<(), axum::Error>> + Send;
+    /// answered by a pong of the client
+    fn ping(&mut self) -> impl Future<Output = Result<(), axum::Error>> + Send;
 }
 
 pub struct SinkSendMsg(pub SplitSink<WebSocket, Message>);
@@ -16,12 +24,36 @@ impl SendMsg for SinkSendMsg {
     }
 
     async fn close(&mut self) {
-        self.0.close().await.unwrap();
+        // already closed by a close frame or the client
+        let _ = self.0.close().await;
+    }
+
+    async fn going_away(&mut self) -> Result<(), axum::Error> {
+        self.0
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "server shutting down".into(),
+            })))
+            .await
+    }
+
+    async fn kicked(&mut self) -> Result<(), axum::Error> {
+        self.0
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: "kicked".into(),
+            })))
+            .await
+    }
+
+    async fn ping(&mut self) -> Result<(), axum::Error> {
+        self.0.send(Message::Ping(vec![])).await
     }
 }
 
 pub struct SendSocket<S: SendMsg> {
-    cipher: Box<dyn SplitedEncrypt>,
+    /// `None` sends plain text, for bots
+    cipher: Option<Box<dyn SplitedEncrypt>>,
     socket: S,
 }
 
@@ -29,13 +61,17 @@ impl<S: SendMsg> SendSocket<S> {
     #[allow(dead_code)]
     pub fn new(socket: S, encrypt: impl SplitedEncrypt + 'static) -> Self {
         Self {
-            cipher: Box::new(encrypt),
+            cipher: Some(Box::new(encrypt)),
             socket,
         }
     }
 
     pub async fn send(&mut self, text: String) -> Result<(), axum::Error> {
-        let cipher_text = self.cipher.encrypt(text.as_ref());
+        let Some(cipher) = &self.cipher else {
+            return self.socket.send(text).await;
+        };
+
+        let cipher_text = cipher.encrypt(text.as_ref());
         let cipher_text = BASE64_STANDARD.encode(cipher_text);
         self.socket.send(cipher_text).await
     }
@@ -43,6 +79,19 @@ impl<S: SendMsg> SendSocket<S> {
     pub async fn close(&mut self) {
         self.socket.close().await;
     }
+
+    pub async fn going_away(&mut self) -> Result<(), axum::Error> {
+        self.socket.going_away().await
+    }
+
+    pub async fn kicked(&mut self) -> Result<(), axum::Error> {
+        self.socket.kicked().await
+    }
+
+    /// a control frame, never encrypted
+    pub async fn ping(&mut self) -> Result<(), axum::Error> {
+        self.socket.ping().await
+    }
 }
 
 impl SendSocket<SinkSendMsg> {
@@ -52,7 +101,15 @@ impl SendSocket<SinkSendMsg> {
     ) -> Self {
         Self {
             socket: SinkSendMsg(sink),
-            cipher: Box::new(encrypt),
+            cipher: Some(Box::new(encrypt)),
+        }
+    }
+
+    /// without the encryption, for bots authenticated by an API key
+    pub fn plain(sink: SplitSink<WebSocket, Message>) -> Self {
+        Self {
+            socket: SinkSendMsg(sink),
+            cipher: None,
         }
     }
 }
@@ -70,6 +127,9 @@ mod test_send_socket {
         impl SendMsg for MySink {
             async fn send(&mut self, msg: String) -> Result<(), axum::Error>;
             async fn close(&mut self);
+            async fn going_away(&mut self) -> Result<(), axum::Error>;
+            async fn kicked(&mut self) -> Result<(), axum::Error>;
+            async fn ping(&mut self) -> Result<(), axum::Error>;
         }
     }
 
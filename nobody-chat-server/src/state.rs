@@ -1,7 +1,71 @@
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
+use axum::http::HeaderValue;
+use tower_http::cors::AllowOrigin;
+
+/// the state of the routes checking the origin
+pub type OriginPolicyState = Arc<OriginPolicy>;
+
+#[deprecated(note = "the routes take an `OriginPolicyState`, see `OriginPolicy`")]
 pub type AllowOriginState = Arc<Vec<String>>;
 
+#[deprecated(note = "use `OriginPolicy::List`, or `OriginPolicy::from_list` for `*`")]
+#[allow(deprecated)]
 pub fn new_allow_origin_state(allow_origins: Vec<String>) -> AllowOriginState {
     Arc::new(allow_origins)
 }
+
+/// which origins of the UI may open a WebSocket and call the API
+#[derive(Clone)]
+pub enum OriginPolicy {
+    Any,
+    List(Vec<String>),
+    /// decided by the embedding application
+    Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl OriginPolicy {
+    /// `*` in the list allows any
+    pub fn from_list(allow_origins: Vec<String>) -> Self {
+        if allow_origins.iter().any(|url| url == "*") {
+            OriginPolicy::Any
+        } else {
+            OriginPolicy::List(allow_origins)
+        }
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        match self {
+            OriginPolicy::Any => true,
+            OriginPolicy::List(list) => list.iter().any(|url| url == origin),
+            OriginPolicy::Predicate(pred) => pred(origin),
+        }
+    }
+
+    pub(crate) fn cors(&self) -> AllowOrigin {
+        match self {
+            OriginPolicy::Any => AllowOrigin::any(),
+            OriginPolicy::List(list) => AllowOrigin::list(
+                list.iter()
+                    .filter_map(|url| url.parse::<HeaderValue>().ok())
+                    .collect::<Vec<_>>(),
+            ),
+            OriginPolicy::Predicate(pred) => {
+                let pred = pred.clone();
+                AllowOrigin::predicate(move |origin, _| {
+                    origin.to_str().map(|origin| pred(origin)).unwrap_or(false)
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Debug for OriginPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OriginPolicy::Any => write!(f, "Any"),
+            OriginPolicy::List(list) => f.debug_tuple("List").field(list).finish(),
+            OriginPolicy::Predicate(_) => write!(f, "Predicate"),
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::net::{IpAddr, SocketAddr};
+
+pub const HEADER_LEN: usize = 20;
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const BINDING_ERROR: u16 = 0x0111;
+
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const ATTR_FINGERPRINT: u16 = 0x8028;
+
+const FAMILY_IPV4: u8 = 0x01;
+const FAMILY_IPV6: u8 = 0x02;
+
+const FINGERPRINT_XOR: u32 = 0x5354_554E;
+
+/// a parsed STUN Binding request (RFC 5389)
+#[derive(Debug, PartialEq)]
+pub struct BindingRequest {
+    pub transaction_id: [u8; 12],
+    /// comprehension-required attributes this server does not understand
+    pub unknown_attributes: Vec<u16>,
+    pub has_fingerprint: bool,
+}
+
+impl BindingRequest {
+    /// parse a datagram as a Binding request,
+    /// returns `None` if it is not a well formed one, and should be discarded silently
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[0] & 0b1100_0000 != 0 {
+            return None;
+        }
+
+        let msg_type = u16::from_be_bytes([data[0], data[1]]);
+        let msg_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let cookie = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+
+        if msg_type != BINDING_REQUEST
+            || cookie != MAGIC_COOKIE
+            || msg_len % 4 != 0
+            || data.len() != HEADER_LEN + msg_len
+        {
+            return None;
+        }
+
+        let mut transaction_id = [0u8; 12];
+        transaction_id.copy_from_slice(&data[8..HEADER_LEN]);
+
+        let mut unknown_attributes = vec![];
+        let mut has_fingerprint = false;
+        let mut offset = HEADER_LEN;
+        while offset < data.len() {
+            if data.len() - offset < 4 {
+                return None;
+            }
+            let attr_type = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let attr_len = u16::from_be_bytes([data[offset + 2], data[offset + 3]]) as usize;
+            let value_start = offset + 4;
+            let padded_len = (attr_len + 3) & !3;
+            if data.len() - value_start < padded_len {
+                return None;
+            }
+
+            if attr_type == ATTR_FINGERPRINT {
+                // FINGERPRINT must be the last attribute and must match
+                if attr_len != 4 || value_start + 4 != data.len() {
+                    return None;
+                }
+                let expected = u32::from_be_bytes([
+                    data[value_start],
+                    data[value_start + 1],
+                    data[value_start + 2],
+                    data[value_start + 3],
+                ]);
+                if fingerprint(&data[..offset]) != expected {
+                    return None;
+                }
+                has_fingerprint = true;
+            } else if attr_type < 0x8000 {
+                // Binding requests carry no comprehension-required attributes we understand
+                unknown_attributes.push(attr_type);
+            }
+
+            offset = value_start + padded_len;
+        }
+
+        Some(Self {
+            transaction_id,
+            unknown_attributes,
+            has_fingerprint,
+        })
+    }
+
+    /// build the response of this request, sent back to `from`
+    pub fn response(&self, from: SocketAddr) -> Vec<u8> {
+        if !self.unknown_attributes.is_empty() {
+            let mut msg = MessageWriter::new(BINDING_ERROR, self.transaction_id);
+            msg.error_code(420, "Unknown Attribute");
+            let attrs: Vec<u8> = self
+                .unknown_attributes
+                .iter()
+                .flat_map(|attr| attr.to_be_bytes())
+                .collect();
+            msg.attribute(ATTR_UNKNOWN_ATTRIBUTES, &attrs);
+            return msg.finish(self.has_fingerprint);
+        }
+
+        let mut msg = MessageWriter::new(BINDING_SUCCESS, self.transaction_id);
+        // no SOFTWARE, the response stays small and tells nothing of the server
+        msg.xor_mapped_address(from);
+        msg.finish(self.has_fingerprint)
+    }
+}
+
+struct MessageWriter {
+    buf: Vec<u8>,
+    transaction_id: [u8; 12],
+}
+
+impl MessageWriter {
+    fn new(msg_type: u16, transaction_id: [u8; 12]) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&msg_type.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&transaction_id);
+
+        Self {
+            buf,
+            transaction_id,
+        }
+    }
+
+    fn attribute(&mut self, attr_type: u16, value: &[u8]) {
+        self.buf.extend_from_slice(&attr_type.to_be_bytes());
+        self.buf
+            .extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        while self.buf.len() % 4 != 0 {
+            self.buf.push(0);
+        }
+        self.set_len(self.buf.len() - HEADER_LEN);
+    }
+
+    fn xor_mapped_address(&mut self, addr: SocketAddr) {
+        let x_port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+
+        let mut value = vec![0u8];
+        match addr.ip().to_canonical() {
+            IpAddr::V4(ip) => {
+                value.push(FAMILY_IPV4);
+                value.extend_from_slice(&x_port.to_be_bytes());
+                let x_addr = u32::from(ip) ^ MAGIC_COOKIE;
+                value.extend_from_slice(&x_addr.to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                value.push(FAMILY_IPV6);
+                value.extend_from_slice(&x_port.to_be_bytes());
+                let mut mask = [0u8; 16];
+                mask[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+                mask[4..].copy_from_slice(&self.transaction_id);
+                value.extend(ip.octets().iter().zip(mask).map(|(a, b)| a ^ b));
+            }
+        }
+
+        self.attribute(ATTR_XOR_MAPPED_ADDRESS, &value);
+    }
+
+    fn error_code(&mut self, code: u16, reason: &str) {
+        let mut value = vec![0u8, 0u8, (code / 100) as u8, (code % 100) as u8];
+        value.extend_from_slice(reason.as_bytes());
+        self.attribute(ATTR_ERROR_CODE, &value);
+    }
+
+    fn finish(mut self, with_fingerprint: bool) -> Vec<u8> {
+        if with_fingerprint {
+            // the length field has to cover FINGERPRINT itself before calculating
+            self.set_len(self.buf.len() - HEADER_LEN + 8);
+            let crc = fingerprint(&self.buf);
+            self.attribute(ATTR_FINGERPRINT, &crc.to_be_bytes());
+        }
+
+        self.buf
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    }
+}
+
+/// CRC-32 of the message XOR'ed with 0x5354554e
+fn fingerprint(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc ^ FINGERPRINT_XOR
+}
+
+#[cfg(test)]
+mod test_stun_message {
+    use super::*;
+
+    const TRANSACTION_ID: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+    const ATTR_SOFTWARE: u16 = 0x8022;
+
+    fn binding_request(attrs: &[(u16, &[u8])], with_fingerprint: bool) -> Vec<u8> {
+        let mut msg = MessageWriter::new(BINDING_REQUEST, TRANSACTION_ID);
+        for (attr_type, value) in attrs {
+            msg.attribute(*attr_type, value);
+        }
+        msg.finish(with_fingerprint)
+    }
+
+    fn attribute(msg: &[u8], attr_type: u16) -> Option<&[u8]> {
+        let mut offset = HEADER_LEN;
+        while offset < msg.len() {
+            let t = u16::from_be_bytes([msg[offset], msg[offset + 1]]);
+            let len = u16::from_be_bytes([msg[offset + 2], msg[offset + 3]]) as usize;
+            if t == attr_type {
+                return Some(&msg[offset + 4..offset + 4 + len]);
+            }
+            offset += 4 + ((len + 3) & !3);
+        }
+        None
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(fingerprint(b"123456789") ^ FINGERPRINT_XOR, 0xCBF4_3926);
+    }
+
+    #[test]
+    fn parse_binding_request() {
+        let data = binding_request(&[(ATTR_SOFTWARE, b"client")], true);
+
+        let req = BindingRequest::parse(&data).unwrap();
+        assert_eq!(req.transaction_id, TRANSACTION_ID);
+        assert!(req.unknown_attributes.is_empty());
+        assert!(req.has_fingerprint);
+    }
+
+    #[test]
+    fn discard_invalid_datagrams() {
+        let mut data = binding_request(&[], false);
+
+        assert!(BindingRequest::parse(&data[..HEADER_LEN - 1]).is_none());
+
+        // wrong magic cookie
+        data[4] = 0;
+        assert!(BindingRequest::parse(&data).is_none());
+
+        // bad fingerprint
+        let mut data = binding_request(&[], true);
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        assert!(BindingRequest::parse(&data).is_none());
+
+        // not a request
+        let data = MessageWriter::new(BINDING_SUCCESS, TRANSACTION_ID).finish(false);
+        assert!(BindingRequest::parse(&data).is_none());
+    }
+
+    #[test]
+    fn xor_mapped_address_ipv4() {
+        let req = BindingRequest::parse(&binding_request(&[], false)).unwrap();
+        let from: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+
+        let res = req.response(from);
+        assert_eq!(u16::from_be_bytes([res[0], res[1]]), BINDING_SUCCESS);
+        assert_eq!(&res[8..HEADER_LEN], &TRANSACTION_ID);
+
+        // the example values of RFC 5769 2.2
+        let value = attribute(&res, ATTR_XOR_MAPPED_ADDRESS).unwrap();
+        assert_eq!(value, &[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert!(attribute(&res, ATTR_FINGERPRINT).is_none());
+        // nothing but the address
+        assert!(attribute(&res, ATTR_SOFTWARE).is_none());
+        assert_eq!(res.len(), HEADER_LEN + 12);
+    }
+
+    #[test]
+    fn xor_mapped_address_ipv6() {
+        let req = BindingRequest::parse(&binding_request(&[], false)).unwrap();
+        let from: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+
+        let res = req.response(from);
+        let value = attribute(&res, ATTR_XOR_MAPPED_ADDRESS).unwrap();
+        assert_eq!(value[1], FAMILY_IPV6);
+        assert_eq!(&value[2..4], &[0xa1, 0x47]);
+        assert_eq!(&value[4..8], &[0x01, 0x13, 0xa9, 0xfa]);
+        let expected: Vec<u8> = [
+            0x12, 0x34, 0x56, 0x78, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+        ]
+        .iter()
+        .zip(TRANSACTION_ID)
+        .map(|(a, b)| a ^ b)
+        .collect();
+        assert_eq!(&value[8..], &expected);
+    }
+
+    #[test]
+    fn response_with_fingerprint() {
+        let req = BindingRequest::parse(&binding_request(&[], true)).unwrap();
+        let res = req.response("127.0.0.1:3478".parse().unwrap());
+
+        let len = u16::from_be_bytes([res[2], res[3]]) as usize;
+        assert_eq!(len + HEADER_LEN, res.len());
+
+        let value = attribute(&res, ATTR_FINGERPRINT).unwrap();
+        let crc = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+        assert_eq!(crc, fingerprint(&res[..res.len() - 8]));
+    }
+
+    #[test]
+    fn unknown_comprehension_required_attribute() {
+        let req =
+            BindingRequest::parse(&binding_request(&[(0x0003, &[0, 0, 0, 0])], false)).unwrap();
+        assert_eq!(req.unknown_attributes, vec![0x0003]);
+
+        let res = req.response("127.0.0.1:3478".parse().unwrap());
+        assert_eq!(u16::from_be_bytes([res[0], res[1]]), BINDING_ERROR);
+        assert_eq!(attribute(&res, ATTR_ERROR_CODE).unwrap()[2..4], [4, 20]);
+        assert_eq!(
+            attribute(&res, ATTR_UNKNOWN_ATTRIBUTES).unwrap(),
+            &[0x00, 0x03]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+mod message;
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tracing::{debug, error, info};
+
+use crate::limit::{limited_addr, Rate, TokenBucket};
+
+pub use message::BindingRequest;
+
+/// the largest datagram we accept, a Binding request is far smaller than this
+const MAX_DATAGRAM: usize = 1500;
+
+/// the sources above which the idle ones are forgotten
+const SWEEP_AT: usize = 4096;
+
+/// an embedded STUN responder, only answers Binding requests (RFC 5389),
+/// so that clients can discover their server reflexive address without an external STUN server
+pub struct StunServer {
+    socket: UdpSocket,
+    rate: Rate,
+    /// of every source, an IPv6 one by its /64
+    buckets: HashMap<IpAddr, TokenBucket>,
+    swept: Instant,
+}
+
+impl StunServer {
+    /// a gathering client sends a few requests, retransmitted at most 7 times each
+    pub const DEFAULT_RATE: Rate = Rate {
+        burst: 50,
+        period: Duration::from_secs(10),
+    };
+
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr).await?;
+
+        Ok(Self {
+            socket,
+            rate: Self::DEFAULT_RATE,
+            buckets: HashMap::new(),
+            swept: Instant::now(),
+        })
+    }
+
+    /// how many requests a source may send, the others are not answered
+    /// so the responder cannot be used to flood a spoofed address
+    pub fn with_rate(mut self, rate: Rate) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn admit(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= SWEEP_AT
+            && now.saturating_duration_since(self.swept) >= Duration::from_secs(1)
+        {
+            self.swept = now;
+            self.buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+
+        let rate = self.rate;
+        self.buckets
+            .entry(limited_addr(ip))
+            .or_insert_with(|| TokenBucket::new(rate, now))
+            .try_take(1, now)
+    }
+
+    pub async fn run(mut self) {
+        if let Ok(addr) = self.local_addr() {
+            info!("STUN listening: {}", addr);
+        }
+
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(e) => {
+                    // e.g. ICMP port unreachable of a previous response, keep serving
+                    debug!("STUN receiving failed: {:?}", e);
+                    continue;
+                }
+            };
+
+            let Some(req) = BindingRequest::parse(&buf[..len]) else {
+                debug!("STUN discard datagram from {}", from);
+                continue;
+            };
+            if !self.admit(from.ip(), Instant::now()) {
+                debug!("STUN too many requests from {}", from);
+                continue;
+            }
+
+            if let Err(e) = self.socket.send_to(&req.response(from), from).await {
+                error!("STUN responding to {} failed: {:?}", from, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_stun_server {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn answer_binding_request() {
+        let server = StunServer::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        // garbage is ignored, the server keeps serving
+        client
+            .send_to(b"not a stun message", server_addr)
+            .await
+            .unwrap();
+
+        let mut req = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42];
+        req.extend_from_slice(&[7u8; 12]);
+        client.send_to(&req, server_addr).await.unwrap();
+
+        let mut buf = [0u8; MAX_DATAGRAM];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let res = &buf[..len];
+
+        assert_eq!(from, server_addr);
+        assert_eq!(&res[..2], &[0x01, 0x01]);
+        assert_eq!(&res[8..20], &[7u8; 12]);
+
+        // XOR-MAPPED-ADDRESS is the first attribute
+        assert_eq!(&res[20..22], &[0x00, 0x20]);
+        let port = u16::from_be_bytes([res[26], res[27]]) ^ 0x2112;
+        let ip = u32::from_be_bytes([res[28], res[29], res[30], res[31]]) ^ 0x2112_A442;
+        assert_eq!(port, client_addr.port());
+        assert_eq!(std::net::Ipv4Addr::from(ip), std::net::Ipv4Addr::LOCALHOST);
+    }
+
+    #[tokio::test]
+    async fn limit_requests_per_source() {
+        let server = StunServer::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_rate(Rate::new(2, Duration::from_secs(60)));
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(server.run());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut req = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xA4, 0x42];
+        req.extend_from_slice(&[7u8; 12]);
+        let mut buf = [0u8; MAX_DATAGRAM];
+        for _ in 0..2 {
+            client.send_to(&req, server_addr).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        client.send_to(&req, server_addr).await.unwrap();
+        let answered =
+            tokio::time::timeout(Duration::from_millis(300), client.recv_from(&mut buf)).await;
+        assert!(answered.is_err());
+    }
+}
@@ -0,0 +1,233 @@
+//! a WebSocket client doing the key exchange like the UI, for the tests
+
+use std::net::SocketAddr;
+
+use base64::prelude::*;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, client::IntoClientRequest, protocol::CloseFrame, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    cipher::{chacha::ChaCha, EncryptDecrypt},
+    models::UserId,
+    App,
+};
+
+/// serve an `App` allowing any origin on a random port, until the test ends
+pub async fn serve(app: App) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { app.serve(listener, std::future::pending()).await });
+
+    addr
+}
+
+/// serve the routes of `App::build` on a random port, with connect info as an embedder should
+pub async fn serve_routes(routes: axum::Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            routes.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+    });
+
+    addr
+}
+
+/// `GET` over a fresh connection, the status code and the body
+pub async fn http_get(addr: SocketAddr, path: &str, headers: &[(&str, &str)]) -> (u16, String) {
+    http_request(addr, "GET", path, headers, None).await
+}
+
+/// a request over a fresh connection, with a JSON body if any
+pub async fn http_request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: Option<Value>,
+) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut request = format!("{method} {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        request.push_str(&format!("{name}: {value}\r\n"));
+    }
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    if !body.is_empty() {
+        request.push_str("content-type: application/json\r\n");
+    }
+    request.push_str(&format!("content-length: {}\r\n\r\n{body}", body.len()));
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+
+    (status, body)
+}
+
+/// the status of a WebSocket upgrade refused, `None` if upgraded
+pub async fn refused(addr: SocketAddr, path: &str, headers: &[(&str, &str)]) -> Option<u16> {
+    match connect_async(upgrade_request(addr, path, headers)).await {
+        Ok(_) => None,
+        Err(tungstenite::Error::Http(res)) => Some(res.status().as_u16()),
+        Err(e) => panic!("upgrade failed: {e}"),
+    }
+}
+
+/// upgraded but never exchanging a key
+pub async fn upgrade(addr: SocketAddr, path: &str) -> WebSocketStream<MaybeTlsStream<TcpStream>> {
+    let (ws, _) = connect_async(upgrade_request(addr, path, &[]))
+        .await
+        .unwrap();
+    ws
+}
+
+fn upgrade_request(
+    addr: SocketAddr,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> tungstenite::handshake::client::Request {
+    let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+    let map = request.headers_mut();
+    map.insert("origin", "http://localhost:3001".parse().unwrap());
+    map.insert("user-agent", "test client".parse().unwrap());
+    for (name, value) in headers {
+        map.insert(
+            tungstenite::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+    }
+
+    request
+}
+
+/// ids of `/api/allonlineusers`
+pub async fn online_users(addr: SocketAddr) -> Vec<UserId> {
+    let (_, body) = http_get(addr, "/api/allonlineusers", &[]).await;
+    let users: Vec<Value> = serde_json::from_str(&body).unwrap();
+    users
+        .iter()
+        .map(|user| user["id"].as_str().unwrap().to_string())
+        .collect()
+}
+
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    cipher: ChaCha,
+    pub id: UserId,
+}
+
+/// what the server sent
+#[derive(Debug)]
+pub enum Received {
+    Data(Value),
+    Close(Option<CloseFrame<'static>>),
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        Self::connect_path(addr, "/ws").await
+    }
+
+    pub async fn connect_path(addr: SocketAddr, path: &str) -> Self {
+        Self::connect_with(addr, path, &[]).await
+    }
+
+    /// with more `headers`, e.g. of a proxy
+    pub async fn connect_with(addr: SocketAddr, path: &str, headers: &[(&str, &str)]) -> Self {
+        let (mut ws, _) = connect_async(upgrade_request(addr, path, headers))
+            .await
+            .unwrap();
+
+        let secret = EphemeralSecret::random();
+        let pub_key = PublicKey::from(&secret).to_bytes().map(|n| n.to_string());
+        ws.send(Message::Text(BASE64_STANDARD.encode(pub_key.join(","))))
+            .await
+            .unwrap();
+
+        let Some(Ok(Message::Text(remote_pub_key))) = ws.next().await else {
+            panic!("expected the public key of the server");
+        };
+        let remote_pub_key = String::from_utf8(BASE64_STANDARD.decode(remote_pub_key).unwrap())
+            .unwrap()
+            .split(',')
+            .map(|n| n.parse::<u8>().unwrap())
+            .collect::<Vec<_>>();
+        let remote_pub_key: [u8; 32] = remote_pub_key.try_into().unwrap();
+        let shared = secret.diffie_hellman(&PublicKey::from(remote_pub_key));
+
+        let mut client = Self {
+            ws,
+            cipher: ChaCha::new(shared.to_bytes()),
+            id: UserId::new(),
+        };
+        let Received::Data(set_user) = client.recv().await else {
+            panic!("expected setUser");
+        };
+        client.id = set_user["msg_type"]["setUser"]["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        client
+    }
+
+    pub async fn send(&mut self, data: Value) {
+        let cipher_text = self.cipher.encrypt(data.to_string().as_bytes());
+        self.ws
+            .send(Message::Text(BASE64_STANDARD.encode(cipher_text)))
+            .await
+            .unwrap();
+    }
+
+    pub async fn talk_to(&mut self, to: &UserId, msg: &str) {
+        self.send(serde_json::json!({ "msg_type": { "talkTo": { "to": to, "msg": msg } } }))
+            .await;
+    }
+
+    /// the next data or close frame, a dropped connection is a close without frame
+    pub async fn recv(&mut self) -> Received {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Text(cipher_text))) => {
+                    let plain_text = self
+                        .cipher
+                        .decrypt(&BASE64_STANDARD.decode(cipher_text).unwrap())
+                        .unwrap();
+                    return Received::Data(serde_json::from_slice(&plain_text).unwrap());
+                }
+                Some(Ok(Message::Close(frame))) => return Received::Close(frame),
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return Received::Close(None),
+            }
+        }
+    }
+
+    /// skip everything until the data matching `pred`, `None` once closed
+    pub async fn recv_until(&mut self, pred: impl Fn(&Value) -> bool) -> Option<Value> {
+        loop {
+            match self.recv().await {
+                Received::Data(data) if pred(&data) => return Some(data),
+                Received::Data(_) => continue,
+                Received::Close(_) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::{
+    http::{header::HOST, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    routing::any,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{sync::mpsc, time::sleep};
+use tracing::{error, info, warn};
+
+/// serve HTTPS and WSS instead of plain HTTP
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+    /// also listen plain HTTP here, only redirecting to HTTPS
+    pub redirect_addr: Option<String>,
+    /// how often the files are checked for changes, SIGHUP reloads at once
+    pub reload_interval: Duration,
+}
+
+impl TlsOptions {
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            redirect_addr: None,
+            reload_interval: Duration::from_secs(10),
+        }
+    }
+
+    pub async fn load(&self) -> io::Result<RustlsConfig> {
+        // kameo pulls in another crypto provider, so rustls cannot pick one by itself
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        RustlsConfig::from_pem_file(&self.cert, &self.key).await
+    }
+
+    /// reload the certificate on SIGHUP or once the files change,
+    /// a broken certificate is logged and the old one kept
+    pub async fn watch(self, config: RustlsConfig) {
+        // the sender is kept, so `recv` is pending instead of `None` without SIGHUP
+        let (_hangup_tx, mut hangup_rx) = mpsc::channel(1);
+        #[cfg(unix)]
+        tokio::spawn(forward_hangups(_hangup_tx.clone()));
+
+        let mut last_contents = contents(&self.cert, &self.key);
+        loop {
+            tokio::select! {
+                Some(()) = hangup_rx.recv() => info!("SIGHUP, reloading the certificate"),
+                _ = sleep(self.reload_interval) => {
+                    let now_contents = contents(&self.cert, &self.key);
+                    if now_contents == last_contents {
+                        continue;
+                    }
+                    info!("certificate changed, reloading");
+                }
+            }
+            last_contents = contents(&self.cert, &self.key);
+
+            match config.reload_from_pem_file(&self.cert, &self.key).await {
+                Ok(()) => info!("certificate reloaded"),
+                Err(e) => error!("reloading certificate failed, keep the old one: {:?}", e),
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn forward_hangups(tx: mpsc::Sender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("cannot listen SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let _ = tx.try_send(());
+    }
+}
+
+/// compared rather than the modification times, two writes within one tick of them
+/// would not be noticed
+fn contents(cert: &Path, key: &Path) -> Option<(Vec<u8>, Vec<u8>)> {
+    Some((fs::read(cert).ok()?, fs::read(key).ok()?))
+}
+
+/// redirects every plain HTTP request to the HTTPS listening on `https_port`
+pub fn redirect_routes(https_port: u16) -> Router {
+    Router::new().fallback(any(move |headers: HeaderMap, uri: Uri| async move {
+        redirect_to_https(&headers, &uri, https_port)
+    }))
+}
+
+fn redirect_to_https(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Response {
+    let Some(authority) = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<axum::http::uri::Authority>().ok())
+    else {
+        return (StatusCode::BAD_REQUEST, "Missing Host").into_response();
+    };
+
+    let host = authority.host();
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = if https_port == 443 {
+        format!("https://{host}{path}")
+    } else {
+        format!("https://{host}:{https_port}{path}")
+    };
+
+    Redirect::permanent(&location).into_response()
+}
+
+#[cfg(test)]
+mod test_tls {
+    use super::*;
+    use crate::{test_client::serve, App};
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::{
+        rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    struct Cert {
+        options: TlsOptions,
+        der: rustls::pki_types::CertificateDer<'static>,
+    }
+
+    fn self_signed(name: &str) -> Cert {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("{name}-{}-cert.pem", std::process::id()));
+        let key = dir.join(format!("{name}-{}-key.pem", std::process::id()));
+        fs::write(&cert, certified.cert.pem()).unwrap();
+        fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        Cert {
+            options: TlsOptions::new(cert, key),
+            der: certified.cert.der().clone(),
+        }
+    }
+
+    async fn get(addr: std::net::SocketAddr, cert: &Cert) -> String {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.der.clone()).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response
+    }
+
+    #[tokio::test]
+    async fn serve_https() {
+        let cert = self_signed("serve");
+        let app = App::new("127.0.0.1:0", vec!["*".to_string()]).with_tls(cert.options.clone());
+        let addr = serve(app).await;
+
+        let response = get(addr, &cert).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("Running"), "{response}");
+
+        let _ = fs::remove_file(&cert.options.cert);
+        let _ = fs::remove_file(&cert.options.key);
+    }
+
+    #[tokio::test]
+    async fn reload_changed_files() {
+        let cert = self_signed("reload");
+        let config = cert.options.load().await.unwrap();
+        let before = config.get_inner();
+
+        let options = TlsOptions {
+            reload_interval: Duration::from_millis(20),
+            ..cert.options.clone()
+        };
+        tokio::spawn(options.watch(config.clone()));
+
+        // a broken certificate keeps the old one, for several reloads
+        fs::write(&cert.options.cert, "not a certificate").unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert!(Arc::ptr_eq(&before, &config.get_inner()));
+
+        let renewed = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(&cert.options.key, renewed.key_pair.serialize_pem()).unwrap();
+        fs::write(&cert.options.cert, renewed.cert.pem()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while Arc::ptr_eq(&before, &config.get_inner()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the renewed certificate is loaded");
+
+        let _ = fs::remove_file(&cert.options.cert);
+        let _ = fs::remove_file(&cert.options.key);
+    }
+
+    #[test]
+    fn redirect() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HOST, "chat.example.com:8080".parse().unwrap());
+        let uri: Uri = "/ws?x=1".parse().unwrap();
+
+        let response = redirect_to_https(&headers, &uri, 443);
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()["location"],
+            "https://chat.example.com/ws?x=1"
+        );
+
+        let response = redirect_to_https(&headers, &uri, 3443);
+        assert_eq!(
+            response.headers()["location"],
+            "https://chat.example.com:3443/ws?x=1"
+        );
+
+        let response = redirect_to_https(&HeaderMap::new(), &uri, 443);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashSet,
+    io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use kameo::{actor::ActorRef, mailbox::unbounded::UnboundedMailbox, message::Message, Actor};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    chat::{SetName, UserDisconnection, UserOnline},
+    handle::ChatEvent,
+    logging::Redacted,
+    models::UserId,
+};
+
+/// `sha256=<hex of HMAC-SHA256 of the body>`
+pub const SIGNATURE_HEADER: &str = "x-nobody-chat-signature";
+/// the same on every retry of an event
+pub const DELIVERY_HEADER: &str = "x-nobody-chat-delivery";
+/// the backoff doubles up to it
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// of every event, so a dead endpoint cannot hold the later events for long
+pub const MAX_ATTEMPTS: u32 = 20;
+
+/// an HTTP endpoint receiving the chat and presence events as signed JSON
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    pub url: String,
+    /// key of the signature
+    pub secret: Redacted<String>,
+    /// the messages sent to these ids are delivered too, e.g. `bot-helpdesk`,
+    /// even if nobody is online with the id
+    pub messages_to: Vec<UserId>,
+    /// events waiting for delivery, the new ones are dropped once full
+    pub queue_size: usize,
+    /// attempts of every event, at most `MAX_ATTEMPTS`,
+    /// the backoff doubles after every failure up to `MAX_BACKOFF`
+    pub max_attempts: u32,
+    pub backoff: Duration,
+    /// of every attempt
+    pub timeout: Duration,
+}
+
+impl WebhookOptions {
+    pub fn new(url: impl Into<String>, secret: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            secret: Redacted(secret.into()),
+            messages_to: vec![],
+            queue_size: 1024,
+            max_attempts: 5,
+            backoff: Duration::from_millis(500),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// the body POSTed to a webhook
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a ChatEvent,
+    /// unix seconds the event happened
+    timestamp: u64,
+}
+
+struct Hook {
+    url: String,
+    messages_to: HashSet<UserId>,
+    queue: mpsc::Sender<(ChatEvent, u64)>,
+}
+
+/// the webhooks, and the ids their messages are opted in
+#[derive(Clone)]
+pub(crate) struct WebhooksRef {
+    pub actor: ActorRef<Webhooks>,
+    messages_to: HashSet<UserId>,
+}
+
+impl WebhooksRef {
+    /// whether any webhook takes the messages to `to`
+    pub fn takes(&self, to: &str) -> bool {
+        self.messages_to.contains(to)
+    }
+}
+
+/// subscribed to the presence of the chat room, queues the events of every webhook
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+}
+
+impl Actor for Webhooks {
+    type Mailbox = UnboundedMailbox<Self>;
+}
+
+impl Webhooks {
+    /// spawn a delivery task of every webhook
+    pub(crate) fn spawn(options: Vec<WebhookOptions>) -> io::Result<WebhooksRef> {
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(io::Error::other)?;
+
+        let messages_to = options
+            .iter()
+            .flat_map(|options| options.messages_to.iter().cloned())
+            .collect();
+        let hooks = options
+            .into_iter()
+            .map(|options| {
+                let (queue, rx) = mpsc::channel(options.queue_size.max(1));
+                let hook = Hook {
+                    url: options.url.clone(),
+                    messages_to: options.messages_to.iter().cloned().collect(),
+                    queue,
+                };
+                tokio::spawn(deliver(options, client.clone(), rx));
+                hook
+            })
+            .collect();
+
+        Ok(WebhooksRef {
+            actor: kameo::spawn(Webhooks { hooks }),
+            messages_to,
+        })
+    }
+
+    fn push(&self, hook: &Hook, event: ChatEvent) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        match hook.queue.try_send((event, now)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("webhook {} queue is full, event dropped", hook.url)
+            }
+            Err(TrySendError::Closed(_)) => error!("webhook {} delivery stopped", hook.url),
+        }
+    }
+
+    fn push_all(&self, event: ChatEvent) {
+        for hook in &self.hooks {
+            self.push(hook, event.clone());
+        }
+    }
+}
+
+/// one event after another, so a webhook sees them in order
+async fn deliver(
+    options: WebhookOptions,
+    client: reqwest::Client,
+    mut rx: mpsc::Receiver<(ChatEvent, u64)>,
+) {
+    while let Some((event, timestamp)) = rx.recv().await {
+        let body = serde_json::to_vec(&Payload {
+            event: &event,
+            timestamp,
+        })
+        .expect("serialize webhook payload");
+        let signature = sign(&options.secret, &body);
+        let delivery = Uuid::new_v4().to_string();
+
+        let max_attempts = options.max_attempts.min(MAX_ATTEMPTS);
+        let mut backoff = options.backoff.min(MAX_BACKOFF);
+        for attempt in 1..=max_attempts {
+            let res = client
+                .post(&options.url)
+                .timeout(options.timeout)
+                .header("content-type", "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(DELIVERY_HEADER, &delivery)
+                .body(body.clone())
+                .send()
+                .await;
+            match res {
+                Ok(res) if res.status().is_success() => break,
+                Ok(res) => warn!(
+                    "webhook {} attempt {} answered {}",
+                    options.url,
+                    attempt,
+                    res.status()
+                ),
+                Err(e) => warn!("webhook {} attempt {} failed: {}", options.url, attempt, e),
+            }
+
+            if attempt == max_attempts {
+                error!(
+                    "webhook {} gave up delivery {} after {} attempts",
+                    options.url, delivery, attempt
+                );
+            } else {
+                tokio::time::sleep(backoff).await;
+                backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// `sha256=<hex>` of `body`, what the receiver compares the signature header with
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+impl Message<UserOnline> for Webhooks {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: UserOnline,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.push_all(ChatEvent::Online {
+            id: msg.0,
+            name: msg.1,
+            bot: msg.2,
+        });
+    }
+}
+
+impl Message<UserDisconnection> for Webhooks {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: UserDisconnection,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.push_all(ChatEvent::Offline { id: msg.0 });
+    }
+}
+
+impl Message<SetName> for Webhooks {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: SetName,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.push_all(ChatEvent::Renamed {
+            id: msg.0,
+            name: msg.1,
+        });
+    }
+}
+
+/// a message to a user, delivered to the webhooks opted in to the receiver
+pub struct WebhookMessage {
+    pub from: UserId,
+    pub to: UserId,
+    pub msg: String,
+}
+
+/// replies whether any webhook takes the messages to the receiver
+impl Message<WebhookMessage> for Webhooks {
+    type Reply = bool;
+
+    async fn handle(
+        &mut self,
+        msg: WebhookMessage,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let mut taken = false;
+        for hook in self
+            .hooks
+            .iter()
+            .filter(|h| h.messages_to.contains(&msg.to))
+        {
+            self.push(
+                hook,
+                ChatEvent::Message {
+                    from: msg.from.clone(),
+                    to: msg.to.clone(),
+                    msg: msg.msg.clone().into(),
+                },
+            );
+            taken = true;
+        }
+
+        taken
+    }
+}
+
+/// a message a filter flagged, delivered to every webhook
+pub struct WebhookFlagged {
+    pub from: UserId,
+    pub to: UserId,
+    pub msg: String,
+    pub reasons: Vec<String>,
+}
+
+impl Message<WebhookFlagged> for Webhooks {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: WebhookFlagged,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        self.push_all(ChatEvent::Flagged {
+            from: msg.from,
+            to: msg.to,
+            msg: msg.msg.into(),
+            reasons: msg.reasons,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test_webhook {
+    use super::*;
+    use crate::{
+        test_client::{serve_routes, TestClient},
+        App,
+    };
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post, Router};
+    use futures_util::StreamExt;
+    use kameo::request::MessageSend;
+    use serde_json::Value;
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    struct Delivered {
+        signature: String,
+        delivery: String,
+        body: Vec<u8>,
+    }
+
+    /// answers 500 to the first request, then 200
+    async fn stand_in() -> (SocketAddr, mpsc::UnboundedReceiver<Delivered>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let routes = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State((tx, requests)): State<(
+                        mpsc::UnboundedSender<Delivered>,
+                        Arc<AtomicUsize>,
+                    )>,
+                     headers: HeaderMap,
+                     body: axum::body::Bytes| async move {
+                        let _ = tx.send(Delivered {
+                            signature: headers[SIGNATURE_HEADER].to_str().unwrap().to_string(),
+                            delivery: headers[DELIVERY_HEADER].to_str().unwrap().to_string(),
+                            body: body.to_vec(),
+                        });
+                        if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::INTERNAL_SERVER_ERROR
+                        } else {
+                            StatusCode::OK
+                        }
+                    },
+                ),
+            )
+            .with_state((tx, requests));
+
+        (serve_routes(routes).await, rx)
+    }
+
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<Delivered>) -> (Delivered, Value) {
+        let delivered = rx.recv().await.unwrap();
+        assert_eq!(
+            delivered.signature,
+            sign("a long enough webhook secret", &delivered.body)
+        );
+        let event = serde_json::from_slice(&delivered.body).unwrap();
+        (delivered, event)
+    }
+
+    #[tokio::test]
+    async fn deliver_signed_events() {
+        let (hook_addr, mut rx) = stand_in().await;
+        let (routes, chat) = App::new("", vec!["*".to_string()])
+            .with_webhook(WebhookOptions {
+                messages_to: vec!["bot-helpdesk".to_string()],
+                backoff: Duration::from_millis(10),
+                ..WebhookOptions::new(
+                    format!("http://{hook_addr}/hook"),
+                    "a long enough webhook secret",
+                )
+            })
+            .build()
+            .unwrap();
+        let addr = serve_routes(routes).await;
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            let client = TestClient::connect(addr).await;
+
+            // retried after the 500, the same delivery
+            let (failed, event) = next_event(&mut rx).await;
+            assert_eq!(event["event"], "online");
+            assert_eq!(event["id"], client.id.as_str());
+            assert!(event["timestamp"].as_u64().unwrap() > 0);
+            let (retried, _) = next_event(&mut rx).await;
+            assert_eq!(failed.delivery, retried.delivery);
+            assert_eq!(failed.body, retried.body);
+
+            // nobody online as the bot, the webhook takes it
+            chat.send_to("bot-helpdesk", &client.id, "help")
+                .await
+                .unwrap();
+            let (_, event) = next_event(&mut rx).await;
+            assert_eq!(event["event"], "message");
+            assert_eq!(event["from"], client.id.as_str());
+            assert_eq!(event["to"], "bot-helpdesk");
+            assert_eq!(event["msg"], "help");
+            // not opted in
+            assert!(chat.send_to("bot-other", &client.id, "help").await.is_err());
+
+            let id = client.id.clone();
+            let mut events = chat.subscribe();
+            drop(client);
+            events.next().await;
+            let (_, event) = next_event(&mut rx).await;
+            assert_eq!(event["event"], "offline");
+            assert_eq!(event["id"], id.as_str());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn never_block_once_queue_full() {
+        // accepts, never answers, so the first delivery hangs
+        let stalled = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stalled_addr = stalled.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = stalled.accept().await {
+                conns.push(conn);
+            }
+        });
+
+        let webhooks = Webhooks::spawn(vec![WebhookOptions {
+            messages_to: vec!["bot-helpdesk".to_string()],
+            queue_size: 1,
+            timeout: Duration::from_secs(60),
+            ..WebhookOptions::new(
+                format!("http://{stalled_addr}/hook"),
+                "a long enough webhook secret",
+            )
+        }])
+        .unwrap()
+        .actor;
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            for n in 0..100 {
+                webhooks
+                    .ask(UserDisconnection(n.to_string()))
+                    .send()
+                    .await
+                    .unwrap();
+            }
+            let taken = webhooks
+                .ask(WebhookMessage {
+                    from: "a".to_string(),
+                    to: "bot-helpdesk".to_string(),
+                    msg: "hi".to_string(),
+                })
+                .send()
+                .await
+                .unwrap();
+            // dropped, but opted in
+            assert!(taken);
+        })
+        .await
+        .unwrap();
+    }
+}